@@ -1,16 +1,65 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Principal};
+#[cfg(not(test))]
+use ic_cdk::api::{canister_balance, caller, is_controller, print, time};
+#[cfg(test)]
+use tests::env::{canister_balance, caller, is_controller, print, time};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{
+    BoundedStorable, Cell, DefaultMemoryImpl, Memory as _, StableBTreeMap, Storable,
+};
+use std::collections::{BTreeMap, BTreeSet};
 use std::{borrow::Cow, cell::RefCell};
 use std::borrow::{Borrow, BorrowMut};
+use ic_ledger_types::{
+    query_archived_blocks, query_blocks, AccountIdentifier, Block, GetBlocksArgs, Memo,
+    Operation, DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID,
+};
+use validator::{validate_email, validate_url, Validate, ValidationError};
 
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
+// Oldest events are evicted once the log holds this many
+const MAX_EVENTS: u64 = 10_000;
+// Upper bound on the number of items a paginated query returns
+const MAX_PAGE_SIZE: u64 = 100;
+
+// Prices are in the currency's smallest unit, e.g. e8s for ICP
+const DEFAULT_CURRENCY: &str = "ICP";
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+// 1T cycles, roughly the point where an operator should top the canister up
+const DEFAULT_LOW_BALANCE_THRESHOLD: u64 = 1_000_000_000_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+enum FuelType {
+    #[default]
+    Petrol,
+    Diesel,
+    Electric,
+    Hybrid,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+enum Transmission {
+    #[default]
+    Manual,
+    Automatic,
+}
+
+// Booked is managed by reservations; Maintenance and Retired take a car out of rotation
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+enum CarStatus {
+    #[default]
+    Available,
+    Booked,
+    Maintenance,
+    Retired,
+}
+
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Car {
     id: u64,
@@ -21,24 +70,220 @@ struct Car {
     created_at: u64,
     updated_at: Option<u64>,
     owner: String,
-    is_booked: bool, // New field for booking status
+    status: CarStatus,
+    fuel_type: FuelType,
+    transmission: Transmission,
+    image_url: Option<String>,
+    daily_price: u64,
+    currency: String,
+    // Applied to the daily price on Saturdays and Sundays (UTC)
+    weekend_multiplier: f64,
+    // Prices for a full week and a full 30-day month, where the owner offers them
+    weekly_price: Option<u64>,
+    monthly_price: Option<u64>,
+    // Refundable amount held with each reservation, in the car's currency
+    deposit: u64,
+    // Cheap popularity signals, bumped by make_reservation
+    total_bookings: u64,
+    last_booked_at: Option<u64>,
+    // Set when a reservation is cancelled; no reservation may start before it
+    grace_until: Option<u64>,
+    // Distance driven in rentals, summed from check-out and check-in odometer readings
+    mileage: u64,
+    // Free-form labels such as "airport", lowercase and unique
+    tags: Vec<String>,
+    // Where the car can be picked up, in degrees; both set or neither
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    // Bumped by every write, see do_insert_car
+    version: u64,
+}
+
+// Stored form of a car. Fields added after cars were first persisted are optional
+// here so that records written by older versions still decode, falling back to defaults.
+#[derive(candid::CandidType, Deserialize)]
+struct StoredCar {
+    id: u64,
+    make: String,
+    model: String,
+    year: u32,
+    color: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    owner: String,
+    // Replaced by status; only present on cars stored before status existed
+    is_booked: Option<bool>,
+    status: Option<CarStatus>,
+    fuel_type: Option<FuelType>,
+    transmission: Option<Transmission>,
+    image_url: Option<String>,
+    daily_price: Option<u64>,
+    currency: Option<String>,
+    weekend_multiplier: Option<f64>,
+    weekly_price: Option<u64>,
+    monthly_price: Option<u64>,
+    deposit: Option<u64>,
+    total_bookings: Option<u64>,
+    last_booked_at: Option<u64>,
+    grace_until: Option<u64>,
+    mileage: Option<u64>,
+    tags: Option<Vec<String>>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    version: Option<u64>,
+}
+
+impl From<StoredCar> for Car {
+    fn from(stored: StoredCar) -> Self {
+        Car {
+            id: stored.id,
+            make: stored.make,
+            model: stored.model,
+            year: stored.year,
+            color: stored.color,
+            created_at: stored.created_at,
+            updated_at: stored.updated_at,
+            owner: stored.owner,
+            status: stored.status.unwrap_or(match stored.is_booked {
+                Some(true) => CarStatus::Booked,
+                _ => CarStatus::Available,
+            }),
+            fuel_type: stored.fuel_type.unwrap_or_default(),
+            transmission: stored.transmission.unwrap_or_default(),
+            image_url: stored.image_url,
+            daily_price: stored.daily_price.unwrap_or_default(),
+            currency: stored.currency.unwrap_or_else(|| DEFAULT_CURRENCY.to_string()),
+            weekend_multiplier: stored.weekend_multiplier.unwrap_or(1.0),
+            weekly_price: stored.weekly_price,
+            monthly_price: stored.monthly_price,
+            deposit: stored.deposit.unwrap_or_default(),
+            total_bookings: stored.total_bookings.unwrap_or_default(),
+            last_booked_at: stored.last_booked_at,
+            grace_until: stored.grace_until,
+            mileage: stored.mileage.unwrap_or_default(),
+            tags: stored.tags.unwrap_or_default(),
+            latitude: stored.latitude,
+            longitude: stored.longitude,
+            version: stored.version.unwrap_or_default(),
+        }
+    }
 }
 
 impl Storable for Car {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Self::try_from_bytes(bytes.as_ref()).unwrap()
+    }
+}
+
+impl Car {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, candid::Error> {
+        Decode!(bytes, StoredCar).map(Into::into)
+    }
+}
+
+// A car as kept in the car maps: its encoded bytes, decoded only when read, so one
+// corrupt record can be skipped instead of trapping every call that walks the fleet
+struct CarRecord(Vec<u8>);
+
+impl CarRecord {
+    fn new(car: &Car) -> Self {
+        CarRecord(car.to_bytes().into_owned())
+    }
+
+    fn decode(&self, id: u64) -> Result<Car, Error> {
+        Car::try_from_bytes(&self.0).map_err(|error| {
+            print(format!("car with id={} is corrupt: {}", id, error));
+            Error::CorruptRecord { id }
+        })
+    }
+}
+
+impl Storable for CarRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        CarRecord(bytes.into_owned())
     }
 }
 
+impl BoundedStorable for CarRecord {
+    const MAX_SIZE: u32 = Car::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Leaves room for every bounded string field at its maximum length plus the candid
+// field overhead, with headroom for fields added later. The bound of a stable BTreeMap
+// can't be raised in place, so growing it means moving cars to fresh memories (see post_upgrade).
 impl BoundedStorable for Car {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Cars as they were bounded before MAX_SIZE was raised, used only to read the old maps
+struct LegacyCar(Car);
+
+impl Storable for LegacyCar {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        LegacyCar(Car::from_bytes(bytes))
+    }
+}
+
+impl BoundedStorable for LegacyCar {
     const MAX_SIZE: u32 = 1024;
     const IS_FIXED_SIZE: bool = false;
 }
 
+// MemoryId assignments. Ids are never reused, even once a store is retired,
+// since an upgraded canister would read the old data under the new layout.
+//   0  ID_COUNTER (cars and customers)
+//   1  cars, retired (1024-byte bound)
+//   2  customers
+//   3  active reservations
+//   4  LOW_BALANCE_THRESHOLD
+//   5  WAITLIST_STORAGE
+//   6  RESERVATION_ARCHIVE
+//   7  IDEMPOTENCY_KEYS
+//   8  COLOR_POLICY
+//   9  STAFF
+//  10  EVENT_LOG
+//  11  EVENT_ID_COUNTER
+//  12  deleted cars, retired (1024-byte bound)
+//  13  CAR_STORAGE
+//  14  DELETED_CAR_STORAGE
+//  15  CURRENCY_SETTINGS
+//  16  COUPONS
+//  17  ID_RESERVATION_COUNTER
+//  18  LEDGER_CANISTER
+//  19  SETTLED_BLOCKS
+//  20  NOTIFICATIONS
+//  21  NOTIFICATION_ID_COUNTER
+//  22  CAR_CREATIONS
+//  23  CAR_RATE_LIMIT
+//  24  RENTAL_POLICY
+//  25  LOYALTY_THRESHOLDS
+//  26  GRACE_PERIOD
+//  27  BLACKOUTS
+//  28  BLACKOUT_ID_COUNTER
+//  29  DEFAULT_RENTAL
+//  30  OWNER_CONTACTS
+//  31  LATE_FEE_PER_DAY
+//  32  MIN_LEAD_TIME
+//  33  LISTING_MODE
+//  34  ALLOWED_LISTERS
+//  35  BLOCKED_PRINCIPALS
+//  36  READ_ONLY
+//  37  WATCHES
+//  38  CANCELLATION_POLICY
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
@@ -49,295 +294,8780 @@ thread_local! {
             .expect("Cannot create a counter")
     );
 
-    static CAR_STORAGE: RefCell<StableBTreeMap<u64, Car, Memory>> =
+    // MemoryId 1 held cars under the old 1024-byte bound
+    static CAR_STORAGE: RefCell<StableBTreeMap<u64, CarRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        ));
+
+    static LOW_BALANCE_THRESHOLD: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), DEFAULT_LOW_BALANCE_THRESHOLD)
+            .expect("Cannot create the low balance threshold")
+    );
+
+    // Cancelled, completed and no-show reservations, keyed by reservation id
+    static RESERVATION_ARCHIVE: RefCell<StableBTreeMap<u64, Reservation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+        ));
+
+    // Maps a caller-supplied idempotency key to the reservation it created
+    static IDEMPOTENCY_KEYS: RefCell<StableBTreeMap<IdempotencyKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        ));
+
+    static COLOR_POLICY: RefCell<Cell<ColorPolicy, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))), ColorPolicy::default())
+            .expect("Cannot create the color policy")
+    );
+
+    // Keyed by (car_id, customer_id) so a car's waitlist is a contiguous range
+    static WAITLIST_STORAGE: RefCell<StableBTreeMap<(u64, u64), WaitlistEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+        ));
+
+    // Principals registered by an admin as front-desk staff
+    static STAFF: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+        ));
+
+    static EVENT_LOG: RefCell<StableBTreeMap<u64, Event, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+        ));
+
+    static EVENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))), 0)
+            .expect("Cannot create the event counter")
+    );
+
+    // Soft-deleted cars are moved here so every other query ignores them until restored.
+    // MemoryId 12 held them under the old 1024-byte bound.
+    static DELETED_CAR_STORAGE: RefCell<StableBTreeMap<u64, CarRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+        ));
+
+    static CURRENCY_SETTINGS: RefCell<Cell<CurrencySettings, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))), CurrencySettings::default())
+            .expect("Cannot create the currency settings")
+    );
+
+    static COUPONS: RefCell<StableBTreeMap<CouponCode, Coupon, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+        ));
+
+    // Reservations used to draw their ids from ID_COUNTER; post_upgrade seeds this
+    // counter past those ids
+    static ID_RESERVATION_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))), 0)
+            .expect("Cannot create a reservation counter")
+    );
+
+    // The ICP ledger that reservation payments are verified against
+    static LEDGER_CANISTER: RefCell<Cell<PrincipalKey, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))), PrincipalKey(MAINNET_LEDGER_CANISTER_ID))
+            .expect("Cannot create the ledger canister setting")
+    );
+
+    // Ledger block index -> the reservation it paid for, so a transfer settles only once
+    static SETTLED_BLOCKS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+        ));
+
+    // Keyed by recipient first so each principal's inbox is a contiguous range
+    static NOTIFICATIONS: RefCell<StableBTreeMap<(PrincipalKey, u64), Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+        ));
+
+    static NOTIFICATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))), 0)
+            .expect("Cannot create a notification counter")
+    );
+
+    // (creator, car id) -> created_at for cars added within the rate-limit window;
+    // older entries are pruned as the creator adds more
+    static CAR_CREATIONS: RefCell<StableBTreeMap<(PrincipalKey, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+        ));
+
+    static CAR_RATE_LIMIT: RefCell<Cell<RateLimit, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))), RateLimit::default())
+            .expect("Cannot create the car rate limit")
+    );
+
+    static RENTAL_POLICY: RefCell<Cell<RentalPolicy, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))), RentalPolicy::default())
+            .expect("Cannot create the rental policy")
+    );
+
+    static LOYALTY_THRESHOLDS: RefCell<Cell<LoyaltyThresholds, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))), LoyaltyThresholds::default())
+            .expect("Cannot create the loyalty thresholds")
+    );
+
+    // Time after a cancellation before the car can be booked again, e.g. for cleaning
+    static GRACE_PERIOD: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))), 0)
+            .expect("Cannot create the grace period")
+    );
+
+    // Keyed by (car_id, blackout_id)
+    static BLACKOUTS: RefCell<StableBTreeMap<(u64, u64), Blackout, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+        ));
+
+    static BLACKOUT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))), 0)
+            .expect("Cannot create a blackout counter")
+    );
+
+    // Length of a reservation made without an end_time
+    static DEFAULT_RENTAL: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))), NANOS_PER_DAY)
+            .expect("Cannot create the default rental length")
+    );
+
+    static OWNER_CONTACTS: RefCell<StableBTreeMap<u64, OwnerContact, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+        ));
+
+    // In the smallest unit of the reservation's currency
+    static LATE_FEE_PER_DAY: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))), 0)
+            .expect("Cannot create the late fee")
+    );
+
+    // How far ahead of its start a reservation must be made
+    static MIN_LEAD_TIME: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))), 0)
+            .expect("Cannot create the minimum lead time")
+    );
+
+    static LISTING_MODE: RefCell<Cell<ListingMode, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))), ListingMode::default())
+            .expect("Cannot create the listing mode")
+    );
+
+    static ALLOWED_LISTERS: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+        ));
+
+    static BLOCKED_PRINCIPALS: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+        ));
+
+    // While set, every update except set_read_only is refused
+    static READ_ONLY: RefCell<Cell<Flag, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))), Flag::default())
+            .expect("Cannot create the read-only flag")
+    );
+
+    // Keyed by (customer_id, car_id)
+    static WATCHES: RefCell<StableBTreeMap<(u64, u64), Watch, Memory>> =
         RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
         ));
+
+    static CANCELLATION_POLICY: RefCell<Cell<CancellationPolicy, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))), CancellationPolicy::default())
+            .expect("Cannot create the cancellation policy")
+    );
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+// A bool kept in a stable cell
+#[derive(Clone, Copy, Default)]
+struct Flag(bool);
+
+impl Storable for Flag {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(vec![self.0 as u8])
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Flag(bytes.first() == Some(&1))
+    }
+}
+
+// Lets a principal be used as a stable map key
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct PrincipalKey(Principal);
+
+// Needed for use inside tuple keys
+impl Default for PrincipalKey {
+    fn default() -> Self {
+        PrincipalKey(Principal::anonymous())
+    }
+}
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_slice().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Maximum lengths count characters, which are up to four bytes each; they keep
+// even a car with every field at its maximum well within Car::MAX_SIZE
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Validate)]
 struct CarPayload {
+    #[validate(length(min = 2, max = 64), custom = "validate_plain_text")]
     make: String,
+    #[validate(length(min = 2, max = 64), custom = "validate_plain_text")]
     model: String,
+    #[validate(range(min = 1880))]
     year: u32,
+    #[validate(length(min = 3, max = 32))]
     color: String,
+    #[validate(length(max = 128))]
     owner: String,
-    is_booked: bool, // Add is_booked field to payload
+    status: CarStatus,
+    fuel_type: FuelType,
+    transmission: Transmission,
+    #[validate(length(max = 256), custom = "validate_https_url")]
+    image_url: Option<String>,
+    daily_price: u64,
+    // Falls back to the canister's default currency
+    #[validate(custom = "validate_currency_code")]
+    currency: Option<String>,
+    // Defaults to 1.0, pricing weekends like weekdays
+    #[validate(custom = "validate_multiplier")]
+    weekend_multiplier: Option<f64>,
+    // Default to charging every day at the daily price
+    weekly_price: Option<u64>,
+    monthly_price: Option<u64>,
+    // Defaults to no deposit
+    deposit: Option<u64>,
+    // Kept apart from the car and shown only to its renters, see get_owner_contact
+    #[validate(length(max = 128), custom = "validate_contact")]
+    owner_contact: Option<String>,
+    // Defaults to no tags
+    #[validate(length(max = 10), custom = "validate_tags")]
+    tags: Option<Vec<String>>,
+    // Give both or neither
+    #[validate(custom = "validate_latitude")]
+    latitude: Option<f64>,
+    #[validate(custom = "validate_longitude")]
+    longitude: Option<f64>,
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default, Clone)]
-struct Customer {
-    id: u64,
+// Every field is optional; only the ones provided are validated and applied
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Validate)]
+struct CarPatch {
+    #[validate(length(min = 2, max = 64), custom = "validate_plain_text")]
+    make: Option<String>,
+    #[validate(length(min = 2, max = 64), custom = "validate_plain_text")]
+    model: Option<String>,
+    #[validate(range(min = 1880))]
+    year: Option<u32>,
+    #[validate(length(min = 3, max = 32))]
+    color: Option<String>,
+    #[validate(length(max = 128))]
+    owner: Option<String>,
+    status: Option<CarStatus>,
+    fuel_type: Option<FuelType>,
+    transmission: Option<Transmission>,
+    #[validate(length(max = 256), custom = "validate_https_url")]
+    image_url: Option<String>,
+    daily_price: Option<u64>,
+    #[validate(custom = "validate_currency_code")]
+    currency: Option<String>,
+    #[validate(custom = "validate_multiplier")]
+    weekend_multiplier: Option<f64>,
+    weekly_price: Option<u64>,
+    monthly_price: Option<u64>,
+    deposit: Option<u64>,
+    #[validate(length(max = 128), custom = "validate_contact")]
+    owner_contact: Option<String>,
+    // Replaces all of the car's tags
+    #[validate(length(max = 10), custom = "validate_tags")]
+    tags: Option<Vec<String>>,
+    // Moving a car takes both coordinates
+    #[validate(custom = "validate_latitude")]
+    latitude: Option<f64>,
+    #[validate(custom = "validate_longitude")]
+    longitude: Option<f64>,
+}
+
+// The fields of a customer that callers supply; like CarPayload, the bounds keep a
+// maximal customer within Customer::MAX_SIZE
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Validate)]
+struct CustomerPayload {
+    #[validate(length(max = 64))]
     name: String,
-    contact: String,
+    #[validate(custom = "validate_customer_contact")]
+    contact: Contact,
 }
 
-impl Storable for Customer {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, PartialEq)]
+enum Contact {
+    Email(String),
+    // E.164: a + and up to 15 digits, the first not 0
+    Phone(String),
+}
+
+impl Default for Contact {
+    fn default() -> Self {
+        Contact::Email(String::new())
     }
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+impl Contact {
+    // Best effort for contacts stored as free text before Contact existed: anything
+    // with an @ is taken for an email address, the rest for a phone number
+    fn classify(contact: String) -> Self {
+        if contact.contains('@') {
+            return Contact::Email(contact);
+        }
+        let digits: String = contact.chars().filter(char::is_ascii_digit).collect();
+        match contact.trim_start().starts_with('+') {
+            true => Contact::Phone(format!("+{}", digits)),
+            false if digits.is_empty() => Contact::Phone(contact),
+            false => Contact::Phone(digits),
+        }
+    }
+
+    // The form two contacts are compared in: email addresses are case-insensitive
+    // and phone numbers are already canonical E.164
+    fn normalized(&self) -> Self {
+        match self {
+            Contact::Email(email) => Contact::Email(email.trim().to_lowercase()),
+            Contact::Phone(phone) => Contact::Phone(phone.trim().to_string()),
+        }
     }
 }
 
-impl BoundedStorable for Customer {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+fn validate_customer_contact(contact: &Contact) -> Result<(), ValidationError> {
+    let (valid, message) = match contact {
+        Contact::Email(email) => (
+            email.chars().count() <= 128 && validate_email(email),
+            "must be a valid email address of at most 128 characters",
+        ),
+        Contact::Phone(phone) => (
+            phone
+                .strip_prefix('+')
+                .is_some_and(|digits| {
+                    (2..=15).contains(&digits.len())
+                        && digits.chars().all(|c| c.is_ascii_digit())
+                        && !digits.starts_with('0')
+                }),
+            "must be an E.164 phone number such as +14155552671",
+        ),
+    };
+    if !valid {
+        let mut error = ValidationError::new("contact");
+        error.message = Some(message.into());
+        return Err(error);
+    }
+    Ok(())
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default, Clone)]
-struct Reservation {
-    car_id: u64,
-    customer_id: u64,
-    reservation_time: u64,
+impl CarPayload {
+    fn trim(&mut self) {
+        self.make = self.make.trim().to_string();
+        self.model = self.model.trim().to_string();
+        self.tags = self.tags.as_deref().map(_normalize_tags);
+    }
 }
 
-impl Storable for Reservation {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+impl CarPatch {
+    fn trim(&mut self) {
+        self.make = self.make.as_deref().map(|make| make.trim().to_string());
+        self.model = self.model.as_deref().map(|model| model.trim().to_string());
+        self.tags = self.tags.as_deref().map(_normalize_tags);
     }
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+// Trimmed, lowercase and without repeats, keeping the first occurrence's position
+fn _normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
     }
+    normalized
 }
 
-impl BoundedStorable for Reservation {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Ten tags of at most 32 characters still fit a maximal car within Car::MAX_SIZE
+fn validate_tags(tags: &[String]) -> Result<(), ValidationError> {
+    if tags
+        .iter()
+        .any(|tag| tag.chars().count() > 32 || validate_plain_text(tag).is_err())
+    {
+        let mut error = ValidationError::new("tags");
+        error.message =
+            Some("tags must not be blank, contain control characters or exceed 32 characters".into());
+        return Err(error);
+    }
+    Ok(())
 }
 
-#[ic_cdk::query]
-fn get_car(id: u64) -> Result<Car, Error> {
-    match _get_car(&id) {
-        Some(car) => Ok(car),
-        None => Err(Error::NotFound {
-            msg: format!("a car with id={} not found", id),
-        }),
+// Control characters such as newlines or null bytes would break reports and displays
+fn validate_plain_text(text: &str) -> Result<(), ValidationError> {
+    if text.chars().any(char::is_control) || text.trim().is_empty() {
+        let mut error = ValidationError::new("plain_text");
+        error.message = Some("must not be blank or contain control characters".into());
+        return Err(error);
     }
+    Ok(())
 }
 
-#[ic_cdk::update]
-fn add_car(car: CarPayload) -> Option<Car> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("cannot increment id counter");
-    let car = Car {
-        id,
-        make: car.make,
-        model: car.model,
-        year: car.year,
-        color: car.color,
-        created_at: time(),
-        updated_at: None,
-        owner: car.owner,
-        is_booked: car.is_booked, // Set is_booked from payload
-    };
-    do_insert_car(&car);
-    Some(car)
+fn validate_https_url(url: &str) -> Result<(), ValidationError> {
+    if !url.starts_with("https://") || !validate_url(url) {
+        let mut error = ValidationError::new("https_url");
+        error.message = Some("must be an https:// URL".into());
+        return Err(error);
+    }
+    Ok(())
 }
 
-#[ic_cdk::update]
-fn update_car(id: u64, payload: CarPayload) -> Result<Car, Error> {
-    match CAR_STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut car) => {
-            car.make = payload.make;
-            car.model = payload.model;
-            car.year = payload.year;
-            car.color = payload.color;
-            car.updated_at = Some(time());
-            car.owner = payload.owner;
-            car.is_booked = payload.is_booked; // Update is_booked field
-            do_insert_car(&car);
-            Ok(car)
-        }
-        None => Err(Error::NotFound {
-            msg: format!(
-                "couldn't update a car with id={}. car not found",
-                id
-            ),
-        }),
+fn validate_multiplier(multiplier: f64) -> Result<(), ValidationError> {
+    if !(0.0..=10.0).contains(&multiplier) {
+        let mut error = ValidationError::new("multiplier");
+        error.message = Some("must be between 0 and 10".into());
+        return Err(error);
     }
+    Ok(())
 }
 
-#[ic_cdk::query]
-fn is_booked(id: u64) -> Result<bool, Error> {
-    match _get_car(&id) {
-        Some(car) => Ok(car.is_booked),
-        None => Err(Error::NotFound {
-            msg: format!("a car with id={} not found", id),
-        }),
+// An email address or a phone number: digits with an optional leading +, and
+// spaces, dashes or parentheses as separators
+fn validate_contact(contact: &str) -> Result<(), ValidationError> {
+    let digits = contact.chars().filter(char::is_ascii_digit).count();
+    let phone = contact
+        .strip_prefix('+')
+        .unwrap_or(contact)
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, ' ' | '-' | '(' | ')'))
+        && (7..=15).contains(&digits);
+    if !phone && !validate_email(contact) {
+        let mut error = ValidationError::new("contact");
+        error.message = Some("must be an email address or a phone number".into());
+        return Err(error);
     }
+    Ok(())
 }
 
-fn do_insert_car(car: &Car) {
-    CAR_STORAGE.with(|service| service.borrow_mut().insert(car.id, car.clone()));
+fn validate_latitude(latitude: f64) -> Result<(), ValidationError> {
+    if !(-90.0..=90.0).contains(&latitude) {
+        let mut error = ValidationError::new("latitude");
+        error.message = Some("must be between -90 and 90 degrees".into());
+        return Err(error);
+    }
+    Ok(())
 }
 
-#[ic_cdk::update]
-fn delete_car(id: u64) -> Result<Car, Error> {
-    match CAR_STORAGE.with(|service| service.borrow_mut().remove(&id)) {
-        Some(car) => Ok(car),
-        None => Err(Error::NotFound {
-            msg: format!(
-                "couldn't delete a car with id={}. car not found.",
-                id
-            ),
-        }),
+fn validate_longitude(longitude: f64) -> Result<(), ValidationError> {
+    if !(-180.0..=180.0).contains(&longitude) {
+        let mut error = ValidationError::new("longitude");
+        error.message = Some("must be between -180 and 180 degrees".into());
+        return Err(error);
     }
+    Ok(())
 }
 
-#[ic_cdk::update]
-fn add_customer(name: String, contact: String) -> Option<Customer> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("cannot increment id counter");
-    let customer = Customer {
-        id,
-        name,
-        contact,
-    };
-    do_insert_customer(&customer);
-    Some(customer)
+// A location is a pair; one coordinate alone can't be searched by distance
+fn _check_coordinates(latitude: Option<f64>, longitude: Option<f64>) -> Result<(), Error> {
+    if latitude.is_some() != longitude.is_some() {
+        return Err(_field_error(
+            "longitude",
+            "latitude and longitude must be given together".to_string(),
+        ));
+    }
+    Ok(())
 }
 
-fn do_insert_customer(customer: &Customer) {
-    // Assuming MemoryId::new(2) is reserved for customer storage
-    let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
-    StableBTreeMap::<u64, Customer, Memory>::init(customer_storage)
-        .borrow_mut()
-        .insert(customer.id, customer.clone());
+// ISO 4217 style: three ASCII letters, compared case-insensitively
+fn validate_currency_code(code: &str) -> Result<(), ValidationError> {
+    if code.len() != 3 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        let mut error = ValidationError::new("currency_code");
+        error.message = Some("must be a three-letter currency code".into());
+        return Err(error);
+    }
+    Ok(())
 }
 
-#[ic_cdk::query]
-fn get_customer(id: u64) -> Result<Customer, Error> {
-    match _get_customer(&id) {
-        Some(customer) => Ok(customer),
-        None => Err(Error::NotFound {
-            msg: format!("a customer with id={} not found", id),
-        }),
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CurrencySettings {
+    default_currency: String,
+    // When false, cars must be priced in the default currency
+    allow_other_currencies: bool,
+}
+
+impl Default for CurrencySettings {
+    fn default() -> Self {
+        CurrencySettings {
+            default_currency: DEFAULT_CURRENCY.to_string(),
+            allow_other_currencies: false,
+        }
     }
 }
 
-fn _get_customer(id: &u64) -> Option<Customer> {
-    // Assuming MemoryId::new(2) is reserved for customer storage
-    let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
-    StableBTreeMap::<u64, Customer, Memory>::init(customer_storage)
-        .borrow()
-        .get(id)
+impl Storable for CurrencySettings {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-#[ic_cdk::update]
-fn delete_customer(id: u64) -> Result<Customer, Error> {
-    match _get_customer(&id) {
-        Some(customer) => {
-            // Assuming MemoryId::new(2) is reserved for customer storage
-            let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
-            StableBTreeMap::<u64, Customer, Memory>::init(customer_storage)
-                .borrow_mut()
-                .remove(&id);
-            Ok(customer)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, PartialEq)]
+struct Price {
+    amount: u64,
+    currency: String,
+}
+
+// Criteria left as None don't restrict the search
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct CarFilter {
+    make: Option<String>,
+    model: Option<String>,
+    min_year: Option<u32>,
+    max_year: Option<u32>,
+    color: Option<String>,
+    fuel_type: Option<FuelType>,
+    transmission: Option<Transmission>,
+    // The car must carry every one of these tags
+    tags_all: Option<Vec<String>>,
+    // The car must carry at least one of these tags
+    tags_any: Option<Vec<String>>,
+}
+
+impl CarFilter {
+    fn matches(&self, car: &Car) -> bool {
+        let contains = |value: &str, query: &str| value.to_lowercase().contains(&query.to_lowercase());
+        let has_tag = |car: &Car, tag: &String| car.tags.contains(&tag.trim().to_lowercase());
+        self.make.as_ref().is_none_or(|make| contains(&car.make, make))
+            && self.model.as_ref().is_none_or(|model| contains(&car.model, model))
+            && self.min_year.is_none_or(|min_year| car.year >= min_year)
+            && self.max_year.is_none_or(|max_year| car.year <= max_year)
+            && self.color.as_ref().is_none_or(|color| car.color == color.trim().to_lowercase())
+            && self.fuel_type.is_none_or(|fuel_type| car.fuel_type == fuel_type)
+            && self.transmission.is_none_or(|transmission| car.transmission == transmission)
+            && self.tags_all.as_ref().is_none_or(|tags| tags.iter().all(|tag| has_tag(car, tag)))
+            && self.tags_any.as_ref().is_none_or(|tags| tags.iter().any(|tag| has_tag(car, tag)))
+    }
+}
+
+// Colors are always stored lowercase. In strict mode they must also be one of allowed_colors.
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Clone)]
+struct ColorPolicy {
+    strict: bool,
+    allowed_colors: Vec<String>,
+}
+
+impl Storable for ColorPolicy {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// At most max_calls add_car calls per caller within any window of window_ns
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy)]
+struct RateLimit {
+    max_calls: u32,
+    window_ns: u64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            max_calls: 10,
+            window_ns: 3_600 * 1_000_000_000,
         }
-        None => Err(Error::NotFound {
-            msg: format!("a customer with id={} not found", id),
-        }),
     }
 }
 
-#[ic_cdk::update]
-fn make_reservation(car_id: u64, customer_id: u64) -> Result<Reservation, Error> {
-    match (_get_car(&car_id), _get_customer(&customer_id)) {
-        (Some(_), Some(_)) => {
-            let reservation = Reservation {
-                car_id,
-                customer_id,
-                reservation_time: time(),
-            };
-            do_insert_reservation(&reservation);
-            Ok(reservation)
+impl Storable for RateLimit {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Who may list cars: anyone, or only principals on the allowlist. Blocked
+// principals are refused either way.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+enum ListingMode {
+    #[default]
+    Open,
+    Allowlist,
+}
+
+impl Storable for ListingMode {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ListingAccess {
+    mode: ListingMode,
+    allowed_listers: Vec<Principal>,
+    blocked_principals: Vec<Principal>,
+}
+
+// Fees for cancelling late, in the smallest unit of the reservation's currency.
+// Cancelling at least free_cancellation_ns before the start is free, later costs
+// late_cancellation_fee, and once the reservation has started no_show_fee. The
+// defaults keep every cancellation free.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Default)]
+struct CancellationPolicy {
+    free_cancellation_ns: u64,
+    late_cancellation_fee: u64,
+    no_show_fee: u64,
+}
+
+impl Storable for CancellationPolicy {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Bounds on a reservation's length; the defaults allow any duration
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy)]
+struct RentalPolicy {
+    min_rental_ns: u64,
+    max_rental_ns: u64,
+}
+
+impl Default for RentalPolicy {
+    fn default() -> Self {
+        RentalPolicy {
+            min_rental_ns: 0,
+            max_rental_ns: u64::MAX,
         }
-        _ => Err(Error::NotFound {
-            msg: "Car or customer not found for reservation".to_string(),
-        }),
     }
 }
 
-fn do_insert_reservation(reservation: &Reservation) {
-    // Assuming MemoryId::new(3) is reserved for reservation storage
-    let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
-    
-    StableBTreeMap::<u64, Reservation, Memory>::init(reservation_storage)
-        .borrow_mut()
-        .insert(reservation.car_id, reservation.clone());
+impl Storable for RentalPolicy {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum CarSortKey {
+    Id,
+    Year,
+    Make,
+    Model,
+    CreatedAt,
+    // By amount alone, whatever the car's currency
+    DailyPrice,
+}
 
-#[ic_cdk::query]
-fn get_reservation(car_id: u64) -> Result<Reservation, Error> {
-    match _get_reservation(&car_id) {
-        Some(reservation) => Ok(reservation),
-        None => Err(Error::NotFound {
-            msg: format!("a reservation for car_id={} not found", car_id),
-        }),
+// The sort is stable and cars come out of storage in id order, so ties stay in id order
+fn _sort_cars(cars: &mut [Car], sort_by: CarSortKey, descending: bool) {
+    cars.sort_by(|a, b| {
+        let ordering = match sort_by {
+            CarSortKey::Id => a.id.cmp(&b.id),
+            CarSortKey::Year => a.year.cmp(&b.year),
+            CarSortKey::Make => a.make.to_lowercase().cmp(&b.make.to_lowercase()),
+            CarSortKey::Model => a.model.to_lowercase().cmp(&b.model.to_lowercase()),
+            CarSortKey::CreatedAt => a.created_at.cmp(&b.created_at),
+            CarSortKey::DailyPrice => a.daily_price.cmp(&b.daily_price),
+        };
+        if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+}
+
+// OwnerOnly contacts are masked for everyone but the customer's creator and admins
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+enum ContactVisibility {
+    #[default]
+    Public,
+    OwnerOnly,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum LoyaltyTier {
+    Bronze,
+    Silver,
+    Gold,
+}
+
+// Reservation counts at which a customer reaches each tier
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy)]
+struct LoyaltyThresholds {
+    silver: u64,
+    gold: u64,
+}
+
+impl Default for LoyaltyThresholds {
+    fn default() -> Self {
+        LoyaltyThresholds { silver: 5, gold: 10 }
     }
 }
 
-fn _get_reservation(car_id: &u64) -> Option<Reservation> {
-    // Assuming MemoryId::new(3) is reserved for reservation storage
-    let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
-    StableBTreeMap::<u64, Reservation, Memory>::init(reservation_storage)
-        .borrow()
-        .get(car_id)
+impl Storable for LoyaltyThresholds {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-#[ic_cdk::update]
-fn cancel_reservation(car_id: u64) -> Result<(), Error> {
-    match _get_reservation(&car_id) {
-        Some(_) => {
-            // Assuming MemoryId::new(3) is reserved for reservation storage
-            let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
-            StableBTreeMap::<u64, Reservation, Memory>::init(reservation_storage)
-                .borrow_mut()
-                .remove(&car_id);
-            Ok(())
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Customer {
+    id: u64,
+    name: String,
+    contact: Contact,
+    created_at: u64,
+    updated_at: Option<u64>,
+    // None for customers stored before creators were recorded; only staff and
+    // controllers act on their behalf
+    created_by: Option<Principal>,
+    contact_visibility: ContactVisibility,
+}
+
+// Stored form of a customer. The first customers had no created_at, updated_at or
+// created_by; post_upgrade fills in created_at, see _migrate_legacy_customers.
+// Customers stored before contact_visibility existed are public. Records written
+// before Contact existed hold the contact as free text under `contact`, newer ones
+// under `typed_contact`.
+#[derive(candid::CandidType, Deserialize)]
+struct StoredCustomer {
+    id: u64,
+    name: String,
+    contact: Option<String>,
+    typed_contact: Option<Contact>,
+    created_at: Option<u64>,
+    updated_at: Option<u64>,
+    created_by: Option<Principal>,
+    contact_visibility: Option<ContactVisibility>,
+}
+
+impl From<&Customer> for StoredCustomer {
+    fn from(customer: &Customer) -> Self {
+        StoredCustomer {
+            id: customer.id,
+            name: customer.name.clone(),
+            contact: None,
+            typed_contact: Some(customer.contact.clone()),
+            created_at: Some(customer.created_at),
+            updated_at: customer.updated_at,
+            created_by: customer.created_by,
+            contact_visibility: Some(customer.contact_visibility),
         }
-        None => Err(Error::NotFound {
-            msg: format!("a reservation for car_id={} not found", car_id),
-        }),
     }
 }
 
-#[ic_cdk::query]
-fn generate_report() -> Vec<Car> {
-    // Assuming MemoryId::new(1) is reserved for car storage
-    let car_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)));
-    StableBTreeMap::<u64, Car, Memory>::init(car_storage)
-        .borrow()
-        .iter()
-        .map(|(_, car)| car.clone())
-        .collect()
+impl From<StoredCustomer> for Customer {
+    fn from(stored: StoredCustomer) -> Self {
+        Customer {
+            id: stored.id,
+            name: stored.name,
+            contact: stored
+                .typed_contact
+                .unwrap_or_else(|| Contact::classify(stored.contact.unwrap_or_default())),
+            created_at: stored.created_at.unwrap_or_default(),
+            updated_at: stored.updated_at,
+            created_by: stored.created_by,
+            contact_visibility: stored.contact_visibility.unwrap_or_default(),
+        }
+    }
 }
 
+impl Storable for Customer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&StoredCustomer::from(self)).unwrap())
+    }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), StoredCustomer).unwrap().into()
+    }
 }
 
-fn _get_car(id: &u64) -> Option<Car> {
-    // Assuming MemoryId::new(1) is reserved for car storage
-    let car_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)));
-    StableBTreeMap::<u64, Car, Memory>::init(car_storage)
-        .borrow()
-        .get(id)
+impl BoundedStorable for Customer {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Customers read without defaults filled in, so the migration can tell which ones
+// predate created_at
+struct LegacyCustomer(StoredCustomer);
+
+impl Storable for LegacyCustomer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        LegacyCustomer(Decode!(bytes.as_ref(), StoredCustomer).unwrap())
+    }
+}
+
+// Same bound as Customer: both read the map in MemoryId 2
+impl BoundedStorable for LegacyCustomer {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum ReservationStatus {
+    Active,
+    Completed,
+    Cancelled,
+    // The customer never picked the car up; see mark_no_show
+    NoShow,
+    // Never stored; reservation_status reports held reservations that haven't started yet as Upcoming
+    Upcoming,
+}
+
+// Nanoseconds since the Unix epoch, as returned by ic_cdk::api::time.
+// On the wire and in storage it is a plain nat64.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(transparent)]
+struct Timestamp(u64);
+
+// Anything earlier (March 1973) is almost certainly seconds, milliseconds or
+// microseconds passed by mistake
+const MIN_PLAUSIBLE_TIMESTAMP: Timestamp = Timestamp::from_secs(100_000_000);
+
+impl Timestamp {
+    fn now() -> Self {
+        Timestamp(time())
+    }
+
+    const fn from_secs(secs: u64) -> Self {
+        Timestamp(secs.saturating_mul(1_000_000_000))
+    }
+
+    // For callers holding JavaScript-style millisecond times
+    #[allow(dead_code)]
+    const fn from_millis(millis: u64) -> Self {
+        Timestamp(millis.saturating_mul(1_000_000))
+    }
+
+    fn as_nanos(self) -> u64 {
+        self.0
+    }
+}
+
+impl candid::CandidType for Timestamp {
+    fn _ty() -> candid::types::Type {
+        u64::_ty()
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: candid::types::Serializer,
+    {
+        self.0.idl_serialize(serializer)
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Reservation {
+    id: u64,
+    car_id: u64,
+    customer_id: u64,
+    reserved_by: Principal,
+    start_time: Timestamp,
+    end_time: Timestamp,
+    reservation_time: u64,
+    status: ReservationStatus,
+    ended_at: Option<u64>,
+    // Price agreed at booking time; reservations made before pricing existed have none
+    cost: Option<Price>,
+    coupon_code: Option<String>,
+    // Ledger block of the transfer that paid for the reservation
+    paid_block: Option<u64>,
+    // Short reference for the customer; see _confirmation_code
+    confirmation_code: Option<String>,
+    // Recorded by check_out and check_in when the car is handed over and returned
+    checked_out_at: Option<u64>,
+    checkout_odometer: Option<u64>,
+    checked_in_at: Option<u64>,
+    checkin_odometer: Option<u64>,
+    // Charged at check-in for returning the car after end_time
+    late_fee: Option<u64>,
+    // The car's deposit when the reservation was made; None if it asked for none
+    deposit_held: Option<u64>,
+    deposit_state: Option<DepositState>,
+    // Charged by cancel_reservation under the cancellation policy; zero if free
+    cancellation_fee: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum DepositState {
+    Held,
+    Released,
+    Forfeited { reason: String },
+}
+
+// Stored form of a reservation. The first reservations held only car_id, customer_id
+// and reservation_time, keyed by car id; the fields added since are optional here so
+// that those records still decode. post_upgrade gives them ids; see
+// _migrate_legacy_reservations.
+#[derive(candid::CandidType, Deserialize)]
+struct StoredReservation {
+    id: Option<u64>,
+    car_id: u64,
+    customer_id: u64,
+    reserved_by: Option<Principal>,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+    reservation_time: u64,
+    status: Option<ReservationStatus>,
+    ended_at: Option<u64>,
+    cost: Option<Price>,
+    coupon_code: Option<String>,
+    paid_block: Option<u64>,
+    confirmation_code: Option<String>,
+    checked_out_at: Option<u64>,
+    checkout_odometer: Option<u64>,
+    checked_in_at: Option<u64>,
+    checkin_odometer: Option<u64>,
+    late_fee: Option<u64>,
+    deposit_held: Option<u64>,
+    deposit_state: Option<DepositState>,
+    cancellation_fee: Option<u64>,
+}
+
+impl From<StoredReservation> for Reservation {
+    fn from(stored: StoredReservation) -> Self {
+        // Reservations without a window held the car from the moment they were made,
+        // as if made today without an end_time
+        let start_time = stored.start_time.unwrap_or(stored.reservation_time);
+        let end_time = stored.end_time.unwrap_or_else(|| {
+            let default_rental = DEFAULT_RENTAL.with(|cell| *cell.borrow().get());
+            start_time.saturating_add(default_rental)
+        });
+        Reservation {
+            id: stored.id.unwrap_or_default(),
+            car_id: stored.car_id,
+            customer_id: stored.customer_id,
+            // Nobody calls as the management canister, so nobody may modify a reservation
+            // whose booker was never recorded
+            reserved_by: stored
+                .reserved_by
+                .unwrap_or_else(Principal::management_canister),
+            start_time: Timestamp(start_time),
+            end_time: Timestamp(end_time),
+            reservation_time: stored.reservation_time,
+            status: stored.status.unwrap_or(ReservationStatus::Active),
+            ended_at: stored.ended_at,
+            cost: stored.cost,
+            coupon_code: stored.coupon_code,
+            paid_block: stored.paid_block,
+            confirmation_code: stored.confirmation_code,
+            checked_out_at: stored.checked_out_at,
+            checkout_odometer: stored.checkout_odometer,
+            checked_in_at: stored.checked_in_at,
+            checkin_odometer: stored.checkin_odometer,
+            late_fee: stored.late_fee,
+            deposit_held: stored.deposit_held,
+            deposit_state: stored.deposit_state,
+            cancellation_fee: stored.cancellation_fee,
+        }
+    }
+}
+
+impl Storable for Reservation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), StoredReservation).unwrap().into()
+    }
+}
+
+// Reservations read without defaults filled in, so the migration can tell which ones
+// predate reservation ids
+struct LegacyReservation(StoredReservation);
+
+impl Storable for LegacyReservation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        LegacyReservation(Decode!(bytes.as_ref(), StoredReservation).unwrap())
+    }
+}
+
+// Same bound as Reservation: both read the map in MemoryId 3
+impl BoundedStorable for LegacyReservation {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Reservation {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+const MAX_COUPON_CODE_LEN: usize = 32;
+
+// Crockford's base32, which leaves out letters easily mistaken for digits
+const CONFIRMATION_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const CONFIRMATION_CODE_LEN: usize = 8;
+const CONFIRMATION_MULTIPLIER: u64 = 0x9E3779B97F;
+const CONFIRMATION_SALT: u64 = 0x5DEECE66D5;
+
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Coupon {
+    code: String,
+    percent_off: u8,
+    valid_until: u64,
+    max_uses: u32,
+    uses: u32,
 }
 
-ic_cdk::export_candid!();
\ No newline at end of file
+impl Storable for Coupon {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Coupon {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Coupon codes are stored uppercase, so lookups are case-insensitive
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct CouponCode(String);
+
+impl Storable for CouponCode {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        CouponCode(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for CouponCode {
+    const MAX_SIZE: u32 = MAX_COUPON_CODE_LEN as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Keys are scoped to the calling principal and the customer, so identical keys
+// chosen by different clients or for different customers never collide
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct IdempotencyKey {
+    caller: Principal,
+    customer_id: u64,
+    key: String,
+}
+
+impl Storable for IdempotencyKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdempotencyKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum EventKind {
+    CarAdded,
+    CarUpdated,
+    CarPatched,
+    CarRepriced,
+    CarDeleted,
+    CarRestored,
+    CarPurged,
+    CarReleased,
+    CarStatusChanged,
+    CarImported,
+    SnapshotImported,
+    CustomerAdded,
+    CustomerUpdated,
+    CustomerDeleted,
+    CustomersMerged,
+    ReservationMade,
+    ReservationModified,
+    ReservationCancelled,
+    ReservationNoShow,
+    ReservationReassigned,
+    ReservationSettled,
+    ReservationCheckedOut,
+    ReservationCheckedIn,
+    DepositReleased,
+    DepositForfeited,
+    WaitlistJoined,
+    CarWatched,
+    CarUnwatched,
+    BlackoutAdded,
+    BlackoutRemoved,
+    CouponCreated,
+    CouponExpired,
+    SettingsChanged,
+    StaffAdded,
+    StaffRemoved,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Event {
+    id: u64,
+    kind: EventKind,
+    actor: Principal,
+    target_id: Option<u64>,
+    timestamp: u64,
+}
+
+impl Storable for Event {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Every encoded event carries the candid type table of EventKind, which grows with
+// each new kind, so the bound leaves plenty of room beyond today's size
+impl BoundedStorable for Event {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Notification {
+    id: u64,
+    recipient: Principal,
+    message: String,
+    created_at: u64,
+    read: bool,
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Clone)]
+struct WaitlistEntry {
+    car_id: u64,
+    customer_id: u64,
+    joined_at: u64,
+}
+
+impl Storable for WaitlistEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for WaitlistEntry {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Watch {
+    customer_id: u64,
+    car_id: u64,
+    created_at: u64,
+}
+
+impl Storable for Watch {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Watch {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CarAvailabilityNotice {
+    car_id: u64,
+    customer_id: u64,
+    make: String,
+    model: String,
+    watched_since: u64,
+}
+
+const MAX_REASON_LEN: usize = 200;
+
+// How renters reach a car's owner, keyed by car id
+struct OwnerContact(String);
+
+impl Storable for OwnerContact {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        OwnerContact(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for OwnerContact {
+    // 128 characters of up to four bytes each
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A window in which the owner keeps a car off the market, e.g. for personal use
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Blackout {
+    id: u64,
+    car_id: u64,
+    start: Timestamp,
+    end: Timestamp,
+    reason: String,
+}
+
+impl Storable for Blackout {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Blackout {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[ic_cdk::query]
+fn get_car(id: u64) -> Result<Car, Error> {
+    match _try_get_car(&id)? {
+        Some(car) => Ok(car),
+        None => Err(Error::NotFound {
+            msg: format!("a car with id={} not found", id),
+        }),
+    }
+}
+
+// Cars in id order with the fleet size as total_matches; limit is capped at MAX_PAGE_SIZE
+#[ic_cdk::query]
+fn list_cars(offset: u64, limit: u64) -> CarPage {
+    let cars = _get_cars();
+    let total_matches = cars.len() as u64;
+    CarPage {
+        items: cars
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .collect(),
+        total_matches,
+    }
+}
+
+#[ic_cdk::update]
+fn add_car(mut car: CarPayload) -> Result<Car, Error> {
+    _check_writable()?;
+    _check_can_list()?;
+    car.trim();
+    _validate(&car)?;
+    _validate_year(car.year)?;
+    _check_coordinates(car.latitude, car.longitude)?;
+    let color = _normalize_color(&car.color)?;
+    let currency = _resolve_currency(car.currency.as_deref())?;
+    _check_car_rate_limit()?;
+    let id = _next_id(&ID_COUNTER)?;
+    let owner_contact = car.owner_contact;
+    let mut car = Car {
+        id,
+        make: car.make,
+        model: car.model,
+        year: car.year,
+        color,
+        created_at: time(),
+        updated_at: None,
+        owner: car.owner,
+        status: car.status,
+        fuel_type: car.fuel_type,
+        transmission: car.transmission,
+        image_url: car.image_url,
+        daily_price: car.daily_price,
+        weekend_multiplier: car.weekend_multiplier.unwrap_or(1.0),
+        weekly_price: car.weekly_price,
+        monthly_price: car.monthly_price,
+        deposit: car.deposit.unwrap_or_default(),
+        currency,
+        total_bookings: 0,
+        last_booked_at: None,
+        grace_until: None,
+        mileage: 0,
+        tags: car.tags.unwrap_or_default(),
+        latitude: car.latitude,
+        longitude: car.longitude,
+        version: 0,
+    };
+    do_insert_car(&mut car)?;
+    _set_owner_contact(car.id, owner_contact);
+    CAR_CREATIONS.with(|service| {
+        service
+            .borrow_mut()
+            .insert((PrincipalKey(caller()), car.id), car.created_at)
+    });
+    _record_event(EventKind::CarAdded, Some(car.id));
+    Ok(car)
+}
+
+fn _check_can_list() -> Result<(), Error> {
+    let lister = PrincipalKey(caller());
+    if BLOCKED_PRINCIPALS.with(|service| service.borrow().contains_key(&lister)) {
+        return Err(Error::NotAuthorized {
+            msg: "caller is blocked from listing cars".to_string(),
+        });
+    }
+    let mode = LISTING_MODE.with(|cell| *cell.borrow().get());
+    if mode == ListingMode::Allowlist
+        && !ALLOWED_LISTERS.with(|service| service.borrow().contains_key(&lister))
+    {
+        return Err(Error::NotAuthorized {
+            msg: "only approved listers may add cars".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Drops the caller's creations that left the window, then rejects the call if the
+// ones left already use up the limit
+fn _check_car_rate_limit() -> Result<(), Error> {
+    let limit = CAR_RATE_LIMIT.with(|cell| *cell.borrow().get());
+    let creator = PrincipalKey(caller());
+    let now = time();
+    let window_start = now.saturating_sub(limit.window_ns);
+    let creations: Vec<((PrincipalKey, u64), u64)> = CAR_CREATIONS.with(|service| {
+        service
+            .borrow()
+            .range((creator, 0)..)
+            .take_while(|((key, _), _)| *key == creator)
+            .collect()
+    });
+    let mut in_window = Vec::new();
+    for (key, created_at) in creations {
+        if created_at <= window_start {
+            CAR_CREATIONS.with(|service| service.borrow_mut().remove(&key));
+        } else {
+            in_window.push(created_at);
+        }
+    }
+    if in_window.len() >= limit.max_calls as usize {
+        let oldest = in_window.iter().min().copied().unwrap_or(now);
+        return Err(Error::RateLimited {
+            retry_after: oldest.saturating_add(limit.window_ns),
+        });
+    }
+    Ok(())
+}
+
+// With expected_version, the update is refused if the car was written since the
+// caller read that version; clients then re-read and retry
+#[ic_cdk::update]
+fn update_car(
+    id: u64,
+    mut payload: CarPayload,
+    expected_version: Option<u64>,
+) -> Result<Car, Error> {
+    _check_writable()?;
+    payload.trim();
+    _validate(&payload)?;
+    _validate_year(payload.year)?;
+    _check_coordinates(payload.latitude, payload.longitude)?;
+    let color = _normalize_color(&payload.color)?;
+    let currency = _resolve_currency(payload.currency.as_deref())?;
+    match _get_car(&id) {
+        Some(mut car) => {
+            _check_if_owner_or_admin(&car)?;
+            _check_version(&car, expected_version)?;
+            if payload.status != car.status {
+                _check_status_change(&car, payload.status)?;
+            }
+            car.make = payload.make;
+            car.model = payload.model;
+            car.year = payload.year;
+            car.color = color;
+            car.updated_at = Some(time());
+            car.owner = payload.owner;
+            car.status = payload.status;
+            car.fuel_type = payload.fuel_type;
+            car.transmission = payload.transmission;
+            car.image_url = payload.image_url;
+            car.daily_price = payload.daily_price;
+            car.weekend_multiplier = payload.weekend_multiplier.unwrap_or(1.0);
+            car.weekly_price = payload.weekly_price;
+            car.monthly_price = payload.monthly_price;
+            car.deposit = payload.deposit.unwrap_or_default();
+            car.tags = payload.tags.unwrap_or_default();
+            car.latitude = payload.latitude;
+            car.longitude = payload.longitude;
+            car.currency = currency;
+            do_insert_car(&mut car)?;
+            _set_owner_contact(car.id, payload.owner_contact);
+            _record_event(EventKind::CarUpdated, Some(car.id));
+            Ok(car)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't update a car with id={}. car not found",
+                id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn patch_car(id: u64, mut patch: CarPatch, expected_version: Option<u64>) -> Result<Car, Error> {
+    _check_writable()?;
+    patch.trim();
+    _validate(&patch)?;
+    if let Some(year) = patch.year {
+        _validate_year(year)?;
+    }
+    _check_coordinates(patch.latitude, patch.longitude)?;
+    let color = patch.color.as_deref().map(_normalize_color).transpose()?;
+    let currency = patch
+        .currency
+        .as_deref()
+        .map(|currency| _resolve_currency(Some(currency)))
+        .transpose()?;
+    match _get_car(&id) {
+        Some(mut car) => {
+            _check_if_owner_or_admin(&car)?;
+            _check_version(&car, expected_version)?;
+            if let Some(status) = patch.status.filter(|status| *status != car.status) {
+                _check_status_change(&car, status)?;
+            }
+            if let Some(make) = patch.make {
+                car.make = make;
+            }
+            if let Some(model) = patch.model {
+                car.model = model;
+            }
+            if let Some(year) = patch.year {
+                car.year = year;
+            }
+            if let Some(color) = color {
+                car.color = color;
+            }
+            if let Some(owner) = patch.owner {
+                car.owner = owner;
+            }
+            if let Some(status) = patch.status {
+                car.status = status;
+            }
+            if let Some(fuel_type) = patch.fuel_type {
+                car.fuel_type = fuel_type;
+            }
+            if let Some(transmission) = patch.transmission {
+                car.transmission = transmission;
+            }
+            if let Some(image_url) = patch.image_url {
+                car.image_url = Some(image_url);
+            }
+            if let Some(daily_price) = patch.daily_price {
+                car.daily_price = daily_price;
+            }
+            if let Some(weekend_multiplier) = patch.weekend_multiplier {
+                car.weekend_multiplier = weekend_multiplier;
+            }
+            if let Some(weekly_price) = patch.weekly_price {
+                car.weekly_price = Some(weekly_price);
+            }
+            if let Some(monthly_price) = patch.monthly_price {
+                car.monthly_price = Some(monthly_price);
+            }
+            if let Some(deposit) = patch.deposit {
+                car.deposit = deposit;
+            }
+            if let Some(tags) = patch.tags {
+                car.tags = tags;
+            }
+            if let (Some(latitude), Some(longitude)) = (patch.latitude, patch.longitude) {
+                car.latitude = Some(latitude);
+                car.longitude = Some(longitude);
+            }
+            if let Some(currency) = currency {
+                car.currency = currency;
+            }
+            car.updated_at = Some(time());
+            do_insert_car(&mut car)?;
+            if let Some(owner_contact) = patch.owner_contact {
+                _set_owner_contact(car.id, Some(owner_contact));
+            }
+            _record_event(EventKind::CarPatched, Some(car.id));
+            Ok(car)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't patch a car with id={}. car not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Everything that decides what renting the car costs, in the car's currency
+#[derive(candid::CandidType, Serialize, Deserialize, Validate)]
+struct CarPricing {
+    daily_price: u64,
+    // None means 1.0
+    #[validate(custom = "validate_multiplier")]
+    weekend_multiplier: Option<f64>,
+    // None stops offering the weekly or monthly price
+    weekly_price: Option<u64>,
+    monthly_price: Option<u64>,
+}
+
+// Reprices a car without resending the rest of its record. Existing reservations
+// keep the cost they were booked at. Only the car's owner or an admin may do this.
+#[ic_cdk::update]
+fn set_car_pricing(
+    car_id: u64,
+    pricing: CarPricing,
+    expected_version: Option<u64>,
+) -> Result<Car, Error> {
+    _check_writable()?;
+    _validate(&pricing)?;
+    let mut car = _get_car(&car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", car_id),
+    })?;
+    _check_if_owner_or_admin(&car)?;
+    _check_version(&car, expected_version)?;
+    car.daily_price = pricing.daily_price;
+    car.weekend_multiplier = pricing.weekend_multiplier.unwrap_or(1.0);
+    car.weekly_price = pricing.weekly_price;
+    car.monthly_price = pricing.monthly_price;
+    car.updated_at = Some(time());
+    do_insert_car(&mut car)?;
+    _record_event(EventKind::CarRepriced, Some(car_id));
+    Ok(car)
+}
+
+fn _check_version(car: &Car, expected_version: Option<u64>) -> Result<(), Error> {
+    match expected_version {
+        Some(expected) if expected != car.version => Err(Error::VersionConflict {
+            current_version: car.version,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct FieldError {
+    field: String,
+    message: String,
+}
+
+// Flattens validator's per-field error map into one entry per failed rule, ordered by field
+fn _validate<T: Validate>(payload: &T) -> Result<(), Error> {
+    payload.validate().map_err(|errors| {
+        let mut field_errors: Vec<FieldError> = errors
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |error| FieldError {
+                    field: field.to_string(),
+                    message: error.to_string(),
+                })
+            })
+            .collect();
+        field_errors.sort_by(|a, b| a.field.cmp(&b.field));
+        Error::ValidationErrors {
+            errors: field_errors,
+        }
+    })
+}
+
+// A validation error for a single field
+fn _field_error(field: &str, message: String) -> Error {
+    Error::ValidationErrors {
+        errors: vec![FieldError {
+            field: field.to_string(),
+            message,
+        }],
+    }
+}
+
+// The upper bound moves with the canister clock; next year's models may already be listed
+fn _validate_year(year: u32) -> Result<(), Error> {
+    let max_year = _year_of(time()) + 1;
+    if year > max_year {
+        return Err(_field_error(
+            "year",
+            format!("year must not be later than {}", max_year),
+        ));
+    }
+    Ok(())
+}
+
+// Gregorian calendar year of a timestamp in nanoseconds since the Unix epoch
+fn _year_of(timestamp: u64) -> u32 {
+    // Days-to-civil conversion from Howard Hinnant's date algorithms, shifted so years start in March
+    let days = timestamp / 1_000_000_000 / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let year = year_of_era + era * 400;
+    // Month indexes 10 and 11 are January and February of the following civil year
+    if month_index >= 10 {
+        (year + 1) as u32
+    } else {
+        year as u32
+    }
+}
+
+// Cars without a currency are priced in the default one; other currencies need to be allowed
+fn _resolve_currency(currency: Option<&str>) -> Result<String, Error> {
+    let settings = CURRENCY_SETTINGS.with(|settings| settings.borrow().get().clone());
+    let currency = match currency {
+        Some(currency) => currency.to_uppercase(),
+        None => return Ok(settings.default_currency),
+    };
+    if currency != settings.default_currency && !settings.allow_other_currencies {
+        return Err(_field_error(
+            "currency",
+            format!(
+                "cars must be priced in {}, the default currency",
+                settings.default_currency
+            ),
+        ));
+    }
+    Ok(currency)
+}
+
+fn _normalize_color(color: &str) -> Result<String, Error> {
+    let color = color.trim().to_lowercase();
+    let policy = COLOR_POLICY.with(|policy| policy.borrow().get().clone());
+    if policy.strict && !policy.allowed_colors.contains(&color) {
+        return Err(_field_error(
+            "color",
+            format!("{} isn't one of the allowed colors", color),
+        ));
+    }
+    Ok(color)
+}
+
+fn _set_owner_contact(car_id: u64, owner_contact: Option<String>) {
+    OWNER_CONTACTS.with(|service| match owner_contact {
+        Some(contact) => service.borrow_mut().insert(car_id, OwnerContact(contact)),
+        None => service.borrow_mut().remove(&car_id),
+    });
+}
+
+// Readable by the owner, admins, and whoever made a reservation still holding the car
+#[ic_cdk::query]
+fn get_owner_contact(car_id: u64) -> Result<String, Error> {
+    let car = _get_car(&car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", car_id),
+    })?;
+    let caller = caller();
+    let renting = _get_car_reservations(&car_id)
+        .iter()
+        .any(|reservation| reservation.reserved_by == caller);
+    if !renting && _check_if_owner_or_admin(&car).is_err() {
+        return Err(Error::NotAuthorized {
+            msg: format!(
+                "only renters of the car with id={} may see its owner's contact",
+                car_id
+            ),
+        });
+    }
+    OWNER_CONTACTS
+        .with(|service| service.borrow().get(&car_id))
+        .map(|contact| contact.0)
+        .ok_or(Error::NotFound {
+            msg: format!("the owner of the car with id={} left no contact", car_id),
+        })
+}
+
+// Cars record their owner as the text form of the owning principal
+fn _check_if_owner(car: &Car) -> Result<(), Error> {
+    if car.owner != caller().to_string() {
+        return Err(Error::NotAuthorized {
+            msg: format!("caller isn't the owner of the car with id={}", car.id),
+        });
+    }
+    Ok(())
+}
+
+fn _check_if_owner_or_admin(car: &Car) -> Result<(), Error> {
+    if _check_if_admin().is_ok() {
+        return Ok(());
+    }
+    _check_if_owner(car)
+}
+
+#[ic_cdk::query]
+fn is_booked(id: u64) -> Result<bool, Error> {
+    match _get_car(&id) {
+        Some(car) => Ok(car.status == CarStatus::Booked),
+        None => Err(Error::NotFound {
+            msg: format!("a car with id={} not found", id),
+        }),
+    }
+}
+
+// Inserting a value above MAX_SIZE would trap, so oversized cars are rejected up front
+// Every write bumps the car's version, so update_car and patch_car can tell when
+// the record changed since the caller read it
+fn do_insert_car(car: &mut Car) -> Result<(), Error> {
+    car.version += 1;
+    _store_car(car)
+}
+
+// Writes a car as given, version included, for restoring records from a snapshot
+fn _store_car(car: &Car) -> Result<(), Error> {
+    let size = Encode!(car)
+        .map_err(|error| _internal_error("cannot encode a car", error))?
+        .len() as u32;
+    if size > Car::MAX_SIZE {
+        return Err(Error::RecordTooLarge {
+            size,
+            max: Car::MAX_SIZE,
+        });
+    }
+    CAR_STORAGE.with(|service| service.borrow_mut().insert(car.id, CarRecord::new(car)));
+    Ok(())
+}
+
+// Restores full car records from a snapshot, keeping their ids and owners. Every car
+// is checked before any is written, so one invalid record aborts the whole import.
+#[ic_cdk::update]
+fn import_cars(cars: Vec<Car>) -> Result<u64, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    let next_id = _check_imported_cars(&cars)?;
+    _insert_imported_cars(&cars)?;
+    _advance_counter(&ID_COUNTER, next_id);
+    Ok(cars.len() as u64)
+}
+
+// Checks that imported ids are unique and returns the id a counter has to move to so
+// that new records don't reuse one. An id of u64::MAX leaves no room for that.
+fn _check_imported_ids(list: &str, ids: &[u64]) -> Result<u64, Error> {
+    let mut seen = std::collections::BTreeSet::new();
+    for (index, id) in ids.iter().enumerate() {
+        if !seen.insert(*id) {
+            return Err(_field_error(
+                &format!("{}[{}].id", list, index),
+                format!("id {} appears more than once", id),
+            ));
+        }
+        if *id == u64::MAX {
+            return Err(_field_error(
+                &format!("{}[{}].id", list, index),
+                "id leaves no room for the id counter".to_string(),
+            ));
+        }
+    }
+    Ok(seen.last().map_or(0, |max_id| max_id + 1))
+}
+
+// Moves a counter forward to next_id; counters never move back
+fn _advance_counter(counter: &'static std::thread::LocalKey<RefCell<IdCell>>, next_id: u64) {
+    counter.with(|counter| {
+        let current_value = *counter.borrow().get();
+        if current_value < next_id {
+            // Trapping rolls back the records already written
+            counter
+                .borrow_mut()
+                .set(next_id)
+                .expect("cannot advance id counter");
+        }
+    });
+}
+
+fn _check_imported_cars(cars: &[Car]) -> Result<u64, Error> {
+    let ids: Vec<u64> = cars.iter().map(|car| car.id).collect();
+    let next_id = _check_imported_ids("cars", &ids)?;
+    for (index, car) in cars.iter().enumerate() {
+        _validate_imported_car(car).map_err(|error| match error {
+            Error::ValidationErrors { errors } => Error::ValidationErrors {
+                errors: errors
+                    .into_iter()
+                    .map(|error| FieldError {
+                        field: format!("cars[{}].{}", index, error.field),
+                        message: error.message,
+                    })
+                    .collect(),
+            },
+            error => error,
+        })?;
+    }
+    Ok(next_id)
+}
+
+fn _insert_imported_cars(cars: &[Car]) -> Result<(), Error> {
+    for car in cars {
+        // An imported car replaces any car, live or deleted, holding the same id
+        DELETED_CAR_STORAGE.with(|service| service.borrow_mut().remove(&car.id));
+        _store_car(car)?;
+        _record_event(EventKind::CarImported, Some(car.id));
+    }
+    Ok(())
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Counters {
+    next_id: u64,
+    next_reservation_id: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Snapshot {
+    cars: Vec<Car>,
+    customers: Vec<Customer>,
+    // Active and archived reservations alike
+    reservations: Vec<Reservation>,
+    counters: Counters,
+}
+
+// Everything in one response, so it only suits small deployments: replies are capped
+// at a few MB, and larger datasets should be backed up through the paginated endpoints
+#[ic_cdk::query]
+fn export_all() -> Result<Snapshot, Error> {
+    _check_if_admin()?;
+    let mut reservations = _get_reservations();
+    RESERVATION_ARCHIVE.with(|service| {
+        reservations.extend(service.borrow().iter().map(|(_, reservation)| reservation))
+    });
+    reservations.sort_by_key(|reservation| reservation.id);
+    Ok(Snapshot {
+        cars: generate_report(),
+        customers: _get_customers(),
+        reservations,
+        counters: Counters {
+            next_id: ID_COUNTER.with(|counter| *counter.borrow().get()),
+            next_reservation_id: ID_RESERVATION_COUNTER.with(|counter| *counter.borrow().get()),
+        },
+    })
+}
+
+// Restores a snapshot taken by export_all. Records replace any holding the same id, and
+// counters only move forward, past every imported id. Everything is checked before
+// anything is written, so an invalid snapshot changes nothing.
+#[ic_cdk::update]
+fn import_all(snapshot: Snapshot) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    let next_car_id = _check_imported_cars(&snapshot.cars)?;
+    let customer_ids: Vec<u64> = snapshot.customers.iter().map(|customer| customer.id).collect();
+    let next_customer_id = _check_imported_ids("customers", &customer_ids)?;
+    let reservation_ids: Vec<u64> = snapshot.reservations.iter().map(|reservation| reservation.id).collect();
+    let next_reservation_id = _check_imported_ids("reservations", &reservation_ids)?;
+    for (index, reservation) in snapshot.reservations.iter().enumerate() {
+        let field = |name: &str| format!("reservations[{}].{}", index, name);
+        if reservation.start_time >= reservation.end_time {
+            return Err(_field_error(&field("end_time"), "must be after start_time".to_string()));
+        }
+        let has_car = snapshot.cars.iter().any(|car| car.id == reservation.car_id)
+            || _get_car(&reservation.car_id).is_some();
+        if !has_car {
+            return Err(_field_error(
+                &field("car_id"),
+                format!("no car with id={}", reservation.car_id),
+            ));
+        }
+        if !customer_ids.contains(&reservation.customer_id) && _get_customer(&reservation.customer_id).is_none() {
+            return Err(_field_error(
+                &field("customer_id"),
+                format!("no customer with id={}", reservation.customer_id),
+            ));
+        }
+    }
+    _insert_imported_cars(&snapshot.cars)?;
+    for customer in &snapshot.customers {
+        do_insert_customer(customer);
+    }
+    for reservation in &snapshot.reservations {
+        // Only active reservations live in the active storage; the rest are archived
+        if reservation.status == ReservationStatus::Active {
+            RESERVATION_ARCHIVE.with(|service| service.borrow_mut().remove(&reservation.id));
+            do_insert_reservation(reservation);
+        } else {
+            let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
+            StableBTreeMap::<u64, Reservation, Memory>::init(reservation_storage)
+                .borrow_mut()
+                .remove(&reservation.id);
+            RESERVATION_ARCHIVE.with(|service| service.borrow_mut().insert(reservation.id, reservation.clone()));
+        }
+    }
+    _advance_counter(&ID_COUNTER, snapshot.counters.next_id.max(next_car_id).max(next_customer_id));
+    _advance_counter(
+        &ID_RESERVATION_COUNTER,
+        snapshot.counters.next_reservation_id.max(next_reservation_id),
+    );
+    _record_event(EventKind::SnapshotImported, None);
+    Ok(())
+}
+
+// The same rules add_car enforces, applied to an already normalized record
+fn _validate_imported_car(car: &Car) -> Result<(), Error> {
+    _validate(&CarPayload {
+        make: car.make.clone(),
+        model: car.model.clone(),
+        year: car.year,
+        color: car.color.clone(),
+        owner: car.owner.clone(),
+        status: car.status,
+        fuel_type: car.fuel_type,
+        transmission: car.transmission,
+        image_url: car.image_url.clone(),
+        daily_price: car.daily_price,
+        currency: Some(car.currency.clone()),
+        weekend_multiplier: Some(car.weekend_multiplier),
+        weekly_price: car.weekly_price,
+        monthly_price: car.monthly_price,
+        deposit: Some(car.deposit),
+        owner_contact: None,
+        tags: Some(car.tags.clone()),
+        latitude: car.latitude,
+        longitude: car.longitude,
+    })?;
+    _validate_year(car.year)?;
+    _check_coordinates(car.latitude, car.longitude)?;
+    if car.make != car.make.trim() || car.model != car.model.trim() {
+        return Err(_field_error(
+            "make",
+            "make and model must not have surrounding whitespace".to_string(),
+        ));
+    }
+    if car.color != _normalize_color(&car.color)? {
+        return Err(_field_error("color", "color must be lowercase".to_string()));
+    }
+    if car.tags != _normalize_tags(&car.tags) {
+        return Err(_field_error(
+            "tags",
+            "tags must be trimmed, lowercase and unique".to_string(),
+        ));
+    }
+    if car.currency != car.currency.to_uppercase() {
+        return Err(_field_error("currency", "currency must be uppercase".to_string()));
+    }
+    let size = Encode!(car).unwrap().len() as u32;
+    if size > Car::MAX_SIZE {
+        return Err(Error::RecordTooLarge {
+            size,
+            max: Car::MAX_SIZE,
+        });
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn delete_car(id: u64) -> Result<Car, Error> {
+    _check_writable()?;
+    match _try_get_car(&id)? {
+        Some(car) => {
+            // Only the owner can restore a car, so only the owner may delete it
+            _check_if_owner(&car)?;
+            _check_no_reservations(id, "delete")?;
+            if let Some(record) = CAR_STORAGE.with(|service| service.borrow_mut().remove(&id)) {
+                DELETED_CAR_STORAGE.with(|service| service.borrow_mut().insert(id, record));
+            }
+            _record_event(EventKind::CarDeleted, Some(id));
+            Ok(car)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't delete a car with id={}. car not found.",
+                id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn restore_car(id: u64) -> Result<Car, Error> {
+    _check_writable()?;
+    let deleted = DELETED_CAR_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .map(|record| record.decode(id))
+        .transpose()?;
+    match deleted {
+        Some(mut car) => {
+            _check_if_owner(&car)?;
+            car.updated_at = Some(time());
+            do_insert_car(&mut car)?;
+            DELETED_CAR_STORAGE.with(|service| service.borrow_mut().remove(&id));
+            _record_event(EventKind::CarRestored, Some(id));
+            Ok(car)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't restore a car with id={}. deleted car not found.",
+                id
+            ),
+        }),
+    }
+}
+
+// Permanently removes a car, whether or not it was soft-deleted first, along with
+// its waitlist. A corrupt record is removed all the same and reported as CorruptRecord.
+#[ic_cdk::update]
+fn purge_car(id: u64) -> Result<Car, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    _check_no_reservations(id, "purge")?;
+    let removed = CAR_STORAGE
+        .with(|service| service.borrow_mut().remove(&id))
+        .or_else(|| DELETED_CAR_STORAGE.with(|service| service.borrow_mut().remove(&id)));
+    match removed {
+        Some(record) => {
+            WAITLIST_STORAGE.with(|service| {
+                let mut waitlist = service.borrow_mut();
+                let customers: Vec<u64> = waitlist
+                    .range((id, 0)..=(id, u64::MAX))
+                    .map(|((_, customer_id), _)| customer_id)
+                    .collect();
+                for customer_id in customers {
+                    waitlist.remove(&(id, customer_id));
+                }
+            });
+            for blackout in _get_blackouts(id) {
+                BLACKOUTS.with(|service| service.borrow_mut().remove(&(id, blackout.id)));
+            }
+            WATCHES.with(|service| {
+                let mut watches = service.borrow_mut();
+                let customers: Vec<u64> = watches
+                    .iter()
+                    .filter(|((_, car_id), _)| *car_id == id)
+                    .map(|((customer_id, _), _)| customer_id)
+                    .collect();
+                for customer_id in customers {
+                    watches.remove(&(customer_id, id));
+                }
+            });
+            _set_owner_contact(id, None);
+            _record_event(EventKind::CarPurged, Some(id));
+            record.decode(id)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't purge a car with id={}. car not found.",
+                id
+            ),
+        }),
+    }
+}
+
+// Reservations name their car, so a car can't go away while any reservation holds it
+fn _check_no_reservations(id: u64, action: &str) -> Result<(), Error> {
+    let blocking: Vec<u64> = _get_car_reservations(&id)
+        .iter()
+        .map(|reservation| reservation.id)
+        .collect();
+    if !blocking.is_empty() {
+        return Err(Error::HasActiveReservation {
+            msg: format!(
+                "couldn't {} a car with id={}. cancel the reservations with ids={:?} first",
+                action, id, blocking
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Escape hatch for a car stuck as booked: cancels every reservation still holding
+// it and puts it back into rotation
+#[ic_cdk::update]
+fn admin_release_car(id: u64) -> Result<Car, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    if _get_car(&id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("couldn't release a car with id={}. car not found.", id),
+        });
+    }
+    for reservation in _get_car_reservations(&id) {
+        _archive_reservation(reservation, ReservationStatus::Cancelled);
+    }
+    let mut car = _get_car(&id).expect("the car was found above");
+    car.status = CarStatus::Available;
+    car.updated_at = Some(time());
+    do_insert_car(&mut car)?;
+    _record_event(EventKind::CarReleased, Some(id));
+    Ok(car)
+}
+
+// Deletes every listed car the caller owns that has no active reservation.
+// Other ids are skipped rather than failing the batch; the result lists the ids actually deleted.
+#[ic_cdk::update]
+fn delete_cars(ids: Vec<u64>) -> Result<Vec<u64>, Error> {
+    _check_writable()?;
+    let mut deleted = Vec::new();
+    for id in ids {
+        let deletable = _get_car(&id).is_some_and(|car| {
+            _check_if_owner(&car).is_ok() && _get_car_reservations(&id).is_empty()
+        });
+        if deletable {
+            if let Some(record) = CAR_STORAGE.with(|service| service.borrow_mut().remove(&id)) {
+                DELETED_CAR_STORAGE.with(|service| service.borrow_mut().insert(id, record));
+            }
+            _record_event(EventKind::CarDeleted, Some(id));
+            deleted.push(id);
+        }
+    }
+    Ok(deleted)
+}
+
+// Shared by every way of changing a car's status: a car can't be made Available while
+// one of its reservations is under way
+fn _check_status_change(car: &Car, status: CarStatus) -> Result<(), Error> {
+    if status != CarStatus::Available {
+        return Ok(());
+    }
+    let now = Timestamp::now();
+    let holding = _get_car_reservations(&car.id)
+        .into_iter()
+        .find(|reservation| _holds_car_now(reservation, now));
+    match holding {
+        Some(reservation) => Err(Error::HasActiveReservation {
+            msg: format!(
+                "the car with id={} is held by the reservation with id={} until {}",
+                car.id,
+                reservation.id,
+                reservation.end_time.as_nanos()
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Sets the status of every listed car the caller owns, or of any car for admins.
+// A car isn't made Available while a reservation holds it right now. Skipped ids
+// don't fail the batch; the result lists the ids actually updated.
+#[ic_cdk::update]
+fn set_cars_status(ids: Vec<u64>, status: CarStatus) -> Result<Vec<u64>, Error> {
+    _check_writable()?;
+    let mut updated = Vec::new();
+    for id in ids {
+        let Some(mut car) = _get_car(&id) else {
+            continue;
+        };
+        if _check_if_owner_or_admin(&car).is_err() || _check_status_change(&car, status).is_err() {
+            continue;
+        }
+        car.status = status;
+        car.updated_at = Some(time());
+        do_insert_car(&mut car)?;
+        _record_event(EventKind::CarStatusChanged, Some(id));
+        updated.push(id);
+    }
+    Ok(updated)
+}
+
+#[ic_cdk::update]
+fn add_customer(name: String, contact: Contact) -> Result<Customer, Error> {
+    _check_writable()?;
+    _validate(&CustomerPayload {
+        name: name.clone(),
+        contact: contact.clone(),
+    })?;
+    let id = _next_id(&ID_COUNTER)?;
+    let customer = Customer {
+        id,
+        name,
+        contact,
+        created_at: time(),
+        updated_at: None,
+        created_by: Some(caller()),
+        contact_visibility: ContactVisibility::Public,
+    };
+    do_insert_customer(&customer);
+    _record_event(EventKind::CustomerAdded, Some(customer.id));
+    Ok(customer)
+}
+
+// Returns the customer already holding the contact, so front desks don't enter the
+// same person twice, or adds a new one
+#[ic_cdk::update]
+fn get_or_create_customer(payload: CustomerPayload) -> Result<Customer, Error> {
+    _check_writable()?;
+    _validate(&payload)?;
+    let contact = payload.contact.normalized();
+    match _get_customers()
+        .into_iter()
+        .find(|customer| customer.contact.normalized() == contact)
+    {
+        Some(customer) => Ok(_visible_to_caller(customer)),
+        None => add_customer(payload.name, payload.contact),
+    }
+}
+
+#[ic_cdk::update]
+fn update_customer(id: u64, name: String, contact: Contact) -> Result<Customer, Error> {
+    _check_writable()?;
+    _validate(&CustomerPayload {
+        name: name.clone(),
+        contact: contact.clone(),
+    })?;
+    match _get_customer(&id) {
+        Some(mut customer) => {
+            customer.name = name;
+            customer.contact = contact;
+            customer.updated_at = Some(time());
+            do_insert_customer(&customer);
+            _record_event(EventKind::CustomerUpdated, Some(customer.id));
+            Ok(customer)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't update a customer with id={}. customer not found",
+                id
+            ),
+        }),
+    }
+}
+
+fn do_insert_customer(customer: &Customer) {
+    // Assuming MemoryId::new(2) is reserved for customer storage
+    let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+    StableBTreeMap::<u64, Customer, Memory>::init(customer_storage)
+        .borrow_mut()
+        .insert(customer.id, customer.clone());
+}
+
+#[ic_cdk::update]
+fn set_contact_visibility(id: u64, visibility: ContactVisibility) -> Result<Customer, Error> {
+    _check_writable()?;
+    let mut customer = _get_customer(&id).ok_or(Error::NotFound {
+        msg: format!("a customer with id={} not found", id),
+    })?;
+    if !_can_see_contact(&customer) {
+        return Err(Error::NotAuthorized {
+            msg: format!("caller didn't create the customer with id={}", id),
+        });
+    }
+    customer.contact_visibility = visibility;
+    customer.updated_at = Some(time());
+    do_insert_customer(&customer);
+    _record_event(EventKind::CustomerUpdated, Some(id));
+    Ok(customer)
+}
+
+fn _can_see_contact(customer: &Customer) -> bool {
+    let caller = caller();
+    customer.created_by == Some(caller) || is_controller(&caller)
+}
+
+// Hides most of an OwnerOnly contact from callers who may not see it
+fn _visible_to_caller(mut customer: Customer) -> Customer {
+    if customer.contact_visibility == ContactVisibility::OwnerOnly && !_can_see_contact(&customer) {
+        customer.contact = match customer.contact {
+            Contact::Email(email) => Contact::Email(_mask_contact(&email)),
+            Contact::Phone(phone) => Contact::Phone(_mask_contact(&phone)),
+        };
+    }
+    customer
+}
+
+// Keeps the first character and, for email addresses, the domain: j***@example.com
+fn _mask_contact(contact: &str) -> String {
+    let first: String = contact.chars().take(1).collect();
+    match contact.split_once('@') {
+        Some((_, domain)) => format!("{}***@{}", first, domain),
+        None => format!("{}***", first),
+    }
+}
+
+#[ic_cdk::query]
+fn get_customer(id: u64) -> Result<Customer, Error> {
+    match _get_customer(&id) {
+        Some(customer) => Ok(_visible_to_caller(customer)),
+        None => Err(Error::NotFound {
+            msg: format!("a customer with id={} not found", id),
+        }),
+    }
+}
+
+fn _get_customer(id: &u64) -> Option<Customer> {
+    // Assuming MemoryId::new(2) is reserved for customer storage
+    let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+    StableBTreeMap::<u64, Customer, Memory>::init(customer_storage)
+        .borrow()
+        .get(id)
+}
+
+fn _get_customers() -> Vec<Customer> {
+    // Assuming MemoryId::new(2) is reserved for customer storage
+    let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+    StableBTreeMap::<u64, Customer, Memory>::init(customer_storage)
+        .borrow()
+        .iter()
+        .map(|(_, customer)| customer)
+        .collect()
+}
+
+// The customers the caller created, in id order
+#[ic_cdk::query]
+fn my_customers() -> Vec<Customer> {
+    let caller = caller();
+    _get_customers()
+        .into_iter()
+        .filter(|customer| customer.created_by == Some(caller))
+        .collect()
+}
+
+// Customers in id order; limit is capped at MAX_PAGE_SIZE
+#[ic_cdk::query]
+fn get_customers_paginated(offset: u64, limit: u64) -> Vec<Customer> {
+    _get_customers()
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit.min(MAX_PAGE_SIZE) as usize)
+        .map(_visible_to_caller)
+        .collect()
+}
+
+// Case-insensitive substring match on the name; an empty query matches everyone.
+// Offset and limit apply to the matches, in id order.
+#[ic_cdk::query]
+fn search_customers(name_query: String, offset: u64, limit: u64) -> Vec<Customer> {
+    let name_query = name_query.to_lowercase();
+    _get_customers()
+        .into_iter()
+        .filter(|customer| customer.name.to_lowercase().contains(&name_query))
+        .skip(offset as usize)
+        .take(limit.min(MAX_PAGE_SIZE) as usize)
+        .map(_visible_to_caller)
+        .collect()
+}
+
+// Counts the customer's reservations, active or archived; cancelled ones don't count
+#[ic_cdk::query]
+fn customer_tier(customer_id: u64) -> Result<LoyaltyTier, Error> {
+    if _get_customer(&customer_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a customer with id={} not found", customer_id),
+        });
+    }
+    let reservations = _get_honored_reservations()
+        .iter()
+        .filter(|reservation| reservation.customer_id == customer_id)
+        .count() as u64;
+    let thresholds = LOYALTY_THRESHOLDS.with(|cell| *cell.borrow().get());
+    Ok(if reservations >= thresholds.gold {
+        LoyaltyTier::Gold
+    } else if reservations >= thresholds.silver {
+        LoyaltyTier::Silver
+    } else {
+        LoyaltyTier::Bronze
+    })
+}
+
+// Churn candidates: customers who never made a reservation, active or archived
+#[ic_cdk::query]
+fn customers_without_reservations() -> Vec<Customer> {
+    let mut with_reservations: std::collections::BTreeSet<u64> = _get_reservations()
+        .iter()
+        .map(|reservation| reservation.customer_id)
+        .collect();
+    RESERVATION_ARCHIVE.with(|service| {
+        with_reservations.extend(service.borrow().iter().map(|(_, reservation)| reservation.customer_id))
+    });
+    _get_customers()
+        .into_iter()
+        .filter(|customer| !with_reservations.contains(&customer.id))
+        .map(_visible_to_caller)
+        .collect()
+}
+
+#[ic_cdk::update]
+fn delete_customer(id: u64) -> Result<Customer, Error> {
+    _check_writable()?;
+    match _get_customer(&id) {
+        Some(customer) => {
+            let blocking: Vec<u64> = _get_customer_reservations(&id)
+                .iter()
+                .map(|reservation| reservation.id)
+                .collect();
+            if !blocking.is_empty() {
+                return Err(Error::HasActiveReservation {
+                    msg: format!(
+                        "couldn't delete a customer with id={}. cancel the reservations with ids={:?} first",
+                        id, blocking
+                    ),
+                });
+            }
+            // Assuming MemoryId::new(2) is reserved for customer storage
+            let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+            StableBTreeMap::<u64, Customer, Memory>::init(customer_storage)
+                .borrow_mut()
+                .remove(&id);
+            _take_watches(id);
+            WAITLIST_STORAGE.with(|service| {
+                let mut waitlist = service.borrow_mut();
+                let cars: Vec<u64> = waitlist
+                    .iter()
+                    .filter(|((_, customer_id), _)| *customer_id == id)
+                    .map(|((car_id, _), _)| car_id)
+                    .collect();
+                for car_id in cars {
+                    waitlist.remove(&(car_id, id));
+                }
+            });
+            _record_event(EventKind::CustomerDeleted, Some(id));
+            Ok(customer)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("a customer with id={} not found", id),
+        }),
+    }
+}
+
+// Folds a duplicate customer into the one being kept: reservations, active and
+// archived, waitlist places and watches move over, then the duplicate is deleted
+#[ic_cdk::update]
+fn merge_customers(keep_id: u64, remove_id: u64) -> Result<Customer, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    if keep_id == remove_id {
+        return Err(_field_error(
+            "remove_id",
+            "a customer can't be merged into itself".to_string(),
+        ));
+    }
+    let keep = _get_customer(&keep_id).ok_or(Error::NotFound {
+        msg: format!("a customer with id={} not found", keep_id),
+    })?;
+    if _get_customer(&remove_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a customer with id={} not found", remove_id),
+        });
+    }
+    for mut reservation in _get_customer_reservations(&remove_id) {
+        reservation.customer_id = keep_id;
+        do_insert_reservation(&reservation);
+    }
+    RESERVATION_ARCHIVE.with(|service| {
+        let mut archive = service.borrow_mut();
+        let moved: Vec<Reservation> = archive
+            .iter()
+            .filter(|(_, reservation)| reservation.customer_id == remove_id)
+            .map(|(_, reservation)| reservation)
+            .collect();
+        for mut reservation in moved {
+            reservation.customer_id = keep_id;
+            archive.insert(reservation.id, reservation);
+        }
+    });
+    WAITLIST_STORAGE.with(|service| {
+        let mut waitlist = service.borrow_mut();
+        let moved: Vec<WaitlistEntry> = waitlist
+            .iter()
+            .filter(|((_, customer_id), _)| *customer_id == remove_id)
+            .map(|(_, entry)| entry)
+            .collect();
+        for mut entry in moved {
+            waitlist.remove(&(entry.car_id, remove_id));
+            // Where both were waiting for the same car, the earlier place is kept
+            if let Some(existing) = waitlist.get(&(entry.car_id, keep_id)) {
+                entry.joined_at = entry.joined_at.min(existing.joined_at);
+            }
+            entry.customer_id = keep_id;
+            waitlist.insert((entry.car_id, keep_id), entry);
+        }
+    });
+    for mut watch in _take_watches(remove_id) {
+        // Where both watched the same car, the earlier watch is kept
+        if let Some(existing) = WATCHES.with(|service| service.borrow().get(&(keep_id, watch.car_id))) {
+            watch.created_at = watch.created_at.min(existing.created_at);
+        }
+        watch.customer_id = keep_id;
+        WATCHES.with(|service| service.borrow_mut().insert((keep_id, watch.car_id), watch));
+    }
+    // Assuming MemoryId::new(2) is reserved for customer storage
+    let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+    StableBTreeMap::<u64, Customer, Memory>::init(customer_storage)
+        .borrow_mut()
+        .remove(&remove_id);
+    _record_event(EventKind::CustomersMerged, Some(keep_id));
+    _record_event(EventKind::CustomerDeleted, Some(remove_id));
+    Ok(keep)
+}
+
+#[ic_cdk::update]
+fn make_reservation(
+    car_id: u64,
+    customer_id: u64,
+    start_time: Timestamp,
+    // Defaults to start_time plus the default rental length
+    end_time: Option<Timestamp>,
+    idempotency_key: Option<String>,
+    coupon_code: Option<String>,
+) -> Result<Reservation, Error> {
+    _check_writable()?;
+    let idempotency_key = match idempotency_key {
+        Some(key) => {
+            if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+                return Err(_field_error(
+                    "idempotency_key",
+                    format!(
+                        "idempotency_key must be between 1 and {} bytes long",
+                        MAX_IDEMPOTENCY_KEY_LEN
+                    ),
+                ));
+            }
+            let key = IdempotencyKey {
+                caller: caller(),
+                customer_id,
+                key,
+            };
+            // A retried call gets back the reservation the first call created
+            let existing = IDEMPOTENCY_KEYS
+                .with(|service| service.borrow().get(&key))
+                .and_then(|id| {
+                    _get_reservation(&id)
+                        .or_else(|| RESERVATION_ARCHIVE.with(|service| service.borrow().get(&id)))
+                });
+            if let Some(reservation) = existing {
+                return Ok(reservation);
+            }
+            Some(key)
+        }
+        None => None,
+    };
+    let end_time = end_time.unwrap_or_else(|| {
+        let default_rental = DEFAULT_RENTAL.with(|cell| *cell.borrow().get());
+        Timestamp(start_time.as_nanos().saturating_add(default_rental))
+    });
+    _archive_expired_reservations();
+    let mut car = _check_reservable(car_id, customer_id, start_time, end_time)?;
+    let coupon = coupon_code.as_deref().map(_redeemable_coupon).transpose()?;
+    let id = _next_id(&ID_RESERVATION_COUNTER)?;
+    let reservation = Reservation {
+        id,
+        car_id,
+        customer_id,
+        reserved_by: caller(),
+        start_time,
+        end_time,
+        reservation_time: time(),
+        status: ReservationStatus::Active,
+        ended_at: None,
+        cost: Some(_quote(&car, start_time.as_nanos(), end_time.as_nanos(), coupon.as_ref())),
+        coupon_code: coupon.as_ref().map(|coupon| coupon.code.clone()),
+        paid_block: None,
+        confirmation_code: Some(_confirmation_code(id)),
+        checked_out_at: None,
+        checkout_odometer: None,
+        checked_in_at: None,
+        checkin_odometer: None,
+        late_fee: None,
+        deposit_held: (car.deposit > 0).then_some(car.deposit),
+        deposit_state: (car.deposit > 0).then_some(DepositState::Held),
+        cancellation_fee: None,
+    };
+    car.total_bookings += 1;
+    car.last_booked_at = Some(reservation.reservation_time);
+    do_insert_car(&mut car)?;
+    do_insert_reservation(&reservation);
+    // Books the car now if the reservation is already under way
+    _refresh_booking_status(&car_id);
+    if let Some(mut coupon) = coupon {
+        coupon.uses += 1;
+        COUPONS.with(|service| {
+            service
+                .borrow_mut()
+                .insert(CouponCode(coupon.code.clone()), coupon)
+        });
+    }
+    if let Some(key) = idempotency_key {
+        IDEMPOTENCY_KEYS.with(|service| service.borrow_mut().insert(key, reservation.id));
+    }
+    // A customer who got the car no longer needs to wait for it
+    WAITLIST_STORAGE.with(|service| service.borrow_mut().remove(&(car_id, customer_id)));
+    _record_event(EventKind::ReservationMade, Some(reservation.id));
+    _notify_owner(&car, _reserved_message(&car, reservation.id));
+    Ok(reservation)
+}
+
+// Every check make_reservation runs before writing anything; returns the car to book
+fn _check_reservable(
+    car_id: u64,
+    customer_id: u64,
+    start_time: Timestamp,
+    end_time: Timestamp,
+) -> Result<Car, Error> {
+    _validate_reservation_window(start_time, end_time)?;
+    _check_rental_duration(start_time, end_time)?;
+    _check_lead_time(start_time)?;
+    match (_get_car(&car_id), _get_customer(&customer_id)) {
+        (Some(car), Some(customer)) => {
+            _check_can_reserve_for(&customer)?;
+            _check_in_rotation(&car)?;
+            _check_grace_period(&car, start_time)?;
+            _check_blackouts(car_id, start_time, end_time)?;
+            _check_reservation_overlap(car_id, start_time, end_time, None)
+                .map_err(|error| _same_customer_overlap(error, customer_id))?;
+            Ok(car)
+        }
+        _ => Err(Error::NotFound {
+            msg: "Car or customer not found for reservation".to_string(),
+        }),
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReservationRequest {
+    car_id: u64,
+    customer_id: u64,
+    start_time: Timestamp,
+    end_time: Timestamp,
+}
+
+// All or nothing: every request is checked, against the stored reservations and
+// against the earlier requests of the batch, before the first one is made
+#[ic_cdk::update]
+fn make_reservations(requests: Vec<ReservationRequest>) -> Result<Vec<Reservation>, Error> {
+    _check_writable()?;
+    if requests.len() as u64 > MAX_PAGE_SIZE {
+        return Err(_field_error(
+            "requests",
+            format!("at most {} reservations can be made at once", MAX_PAGE_SIZE),
+        ));
+    }
+    _archive_expired_reservations();
+    for (index, request) in requests.iter().enumerate() {
+        let rejected = |error| Error::BatchRejected {
+            index: index as u64,
+            error: Box::new(error),
+        };
+        _check_reservable(
+            request.car_id,
+            request.customer_id,
+            request.start_time,
+            request.end_time,
+        )
+        .map_err(rejected)?;
+        if let Some(earlier) = requests[..index].iter().position(|earlier| {
+            earlier.car_id == request.car_id
+                && earlier.start_time < request.end_time
+                && request.start_time < earlier.end_time
+        }) {
+            return Err(rejected(Error::CarUnavailable {
+                msg: format!(
+                    "the car with id={} is already requested for an overlapping window by request {}",
+                    request.car_id, earlier
+                ),
+            }));
+        }
+    }
+    requests
+        .into_iter()
+        .map(|request| {
+            make_reservation(
+                request.car_id,
+                request.customer_id,
+                request.start_time,
+                Some(request.end_time),
+                None,
+                None,
+            )
+            // Trapping rolls back the reservations already made in this batch
+            .map_err(|error| ic_cdk::trap(&format!("cannot make a checked reservation: {:?}", error)))
+        })
+        .collect()
+}
+
+// For renters who don't mind which car they get: reserves the lowest-id car that
+// matches the filter and is free for the whole window
+#[ic_cdk::update]
+fn reserve_first_available(
+    filter: CarFilter,
+    customer_id: u64,
+    start_time: Timestamp,
+    end_time: Timestamp,
+) -> Result<Reservation, Error> {
+    _check_writable()?;
+    _validate_reservation_window(start_time, end_time)?;
+    _archive_expired_reservations();
+    if _get_customer(&customer_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a customer with id={} not found", customer_id),
+        });
+    }
+    let car = search_cars(filter)
+        .into_iter()
+        .find(|car| {
+            _check_in_rotation(car).is_ok()
+                && _check_grace_period(car, start_time).is_ok()
+                && _check_blackouts(car.id, start_time, end_time).is_ok()
+                && _check_reservation_overlap(car.id, start_time, end_time, None).is_ok()
+        })
+        .ok_or(Error::NotFound {
+            msg: "no car matching the filter is free for the requested window".to_string(),
+        })?;
+    make_reservation(car.id, customer_id, start_time, Some(end_time), None, None)
+}
+
+#[ic_cdk::update]
+fn modify_reservation(
+    reservation_id: u64,
+    new_start: Timestamp,
+    new_end: Timestamp,
+) -> Result<Reservation, Error> {
+    _check_writable()?;
+    _validate_reservation_window(new_start, new_end)?;
+    _check_rental_duration(new_start, new_end)?;
+    _check_lead_time(new_start)?;
+    _archive_expired_reservations();
+    match _get_reservation(&reservation_id) {
+        Some(mut reservation) => {
+            if reservation.reserved_by != caller() {
+                return Err(Error::NotAuthorized {
+                    msg: format!(
+                        "caller didn't make the reservation with id={}",
+                        reservation_id
+                    ),
+                });
+            }
+            let car = _get_car(&reservation.car_id).ok_or(Error::NotFound {
+                msg: format!("a car with id={} not found", reservation.car_id),
+            })?;
+            _check_in_rotation(&car)?;
+            _check_grace_period(&car, new_start)?;
+            _check_blackouts(reservation.car_id, new_start, new_end)?;
+            _check_reservation_overlap(reservation.car_id, new_start, new_end, Some(reservation_id))
+                .map_err(|error| _same_customer_overlap(error, reservation.customer_id))?;
+            reservation.start_time = new_start;
+            reservation.end_time = new_end;
+            reservation.cost = Some(_requote(&car, &reservation));
+            do_insert_reservation(&reservation);
+            _refresh_booking_status(&reservation.car_id);
+            _record_event(EventKind::ReservationModified, Some(reservation_id));
+            Ok(reservation)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("a reservation with id={} not found", reservation_id),
+        }),
+    }
+}
+
+// Staff and admins may reserve on behalf of any customer; everyone else only for
+// customers they created themselves
+fn _check_can_reserve_for(customer: &Customer) -> Result<(), Error> {
+    let caller = caller();
+    if customer.created_by != Some(caller) && !_is_staff(&caller) {
+        return Err(Error::NotAuthorized {
+            msg: format!(
+                "caller can't reserve on behalf of the customer with id={}",
+                customer.id
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Moves a reservation to another car for the same window, e.g. when the reserved car breaks down
+#[ic_cdk::update]
+fn reassign_reservation(reservation_id: u64, new_car_id: u64) -> Result<Reservation, Error> {
+    _check_writable()?;
+    _archive_expired_reservations();
+    let mut reservation = _get_reservation(&reservation_id).ok_or(Error::NotFound {
+        msg: format!("a reservation with id={} not found", reservation_id),
+    })?;
+    let caller = caller();
+    if reservation.reserved_by != caller && !_is_staff(&caller) {
+        return Err(Error::NotAuthorized {
+            msg: format!(
+                "caller can't reassign the reservation with id={}",
+                reservation_id
+            ),
+        });
+    }
+    if reservation.car_id == new_car_id {
+        return Err(_field_error(
+            "new_car_id",
+            format!("the reservation is already for the car with id={}", new_car_id),
+        ));
+    }
+    let new_car = _get_car(&new_car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", new_car_id),
+    })?;
+    _check_in_rotation(&new_car)?;
+    _check_grace_period(&new_car, reservation.start_time)?;
+    _check_blackouts(new_car_id, reservation.start_time, reservation.end_time)?;
+    _check_reservation_overlap(new_car_id, reservation.start_time, reservation.end_time, None)
+        .map_err(|error| _same_customer_overlap(error, reservation.customer_id))?;
+    let old_car_id = reservation.car_id;
+    reservation.car_id = new_car_id;
+    reservation.cost = Some(_requote(&new_car, &reservation));
+    do_insert_reservation(&reservation);
+    _refresh_booking_status(&old_car_id);
+    _refresh_booking_status(&new_car_id);
+    _record_event(EventKind::ReservationReassigned, Some(reservation_id));
+    Ok(reservation)
+}
+
+// Rejects windows given in seconds or milliseconds, which would land in 1970
+fn _validate_reservation_window(start_time: Timestamp, end_time: Timestamp) -> Result<(), Error> {
+    if start_time < MIN_PLAUSIBLE_TIMESTAMP {
+        return Err(_field_error(
+            "start_time",
+            "start_time must be in nanoseconds since the Unix epoch".to_string(),
+        ));
+    }
+    if end_time <= start_time {
+        return Err(_field_error(
+            "end_time",
+            "end_time must be after start_time".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn _check_rental_duration(start_time: Timestamp, end_time: Timestamp) -> Result<(), Error> {
+    let policy = RENTAL_POLICY.with(|cell| *cell.borrow().get());
+    let duration = end_time.as_nanos() - start_time.as_nanos();
+    if duration < policy.min_rental_ns {
+        return Err(Error::InvalidDuration {
+            msg: format!(
+                "a reservation must last at least {} ns, this one lasts {} ns",
+                policy.min_rental_ns, duration
+            ),
+        });
+    }
+    if duration > policy.max_rental_ns {
+        return Err(Error::InvalidDuration {
+            msg: format!(
+                "a reservation may last at most {} ns, this one lasts {} ns",
+                policy.max_rental_ns, duration
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Without a lead time, reservations may still start in the past
+fn _check_lead_time(start_time: Timestamp) -> Result<(), Error> {
+    let min_lead_time = MIN_LEAD_TIME.with(|cell| *cell.borrow().get());
+    if min_lead_time == 0 {
+        return Ok(());
+    }
+    let earliest = time().saturating_add(min_lead_time);
+    if start_time.as_nanos() < earliest {
+        return Err(Error::InsufficientLeadTime {
+            msg: format!(
+                "reservations must be made at least {} ns ahead, so may start at {} at the earliest",
+                min_lead_time, earliest
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn _check_grace_period(car: &Car, start_time: Timestamp) -> Result<(), Error> {
+    match car.grace_until {
+        Some(grace_until) if start_time.as_nanos() < grace_until => Err(Error::CarUnavailable {
+            msg: format!(
+                "a car with id={} can't be reserved before {} after a cancellation",
+                car.id, grace_until
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Blackouts may overlap one another; a reservation touching any of them is refused
+fn _check_blackouts(car_id: u64, start_time: Timestamp, end_time: Timestamp) -> Result<(), Error> {
+    let blackout = _get_blackouts(car_id)
+        .into_iter()
+        .find(|blackout| blackout.start < end_time && start_time < blackout.end);
+    match blackout {
+        Some(blackout) => Err(Error::CarUnavailable {
+            msg: format!(
+                "a car with id={} is blacked out from {} to {} (blackout id={})",
+                car_id,
+                blackout.start.as_nanos(),
+                blackout.end.as_nanos(),
+                blackout.id
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Windows are half-open, so a reservation may start exactly when another one ends
+fn _check_reservation_overlap(
+    car_id: u64,
+    start_time: Timestamp,
+    end_time: Timestamp,
+    exclude_id: Option<u64>,
+) -> Result<(), Error> {
+    let reservations: Vec<Reservation> = _get_car_reservations(&car_id)
+        .into_iter()
+        .filter(|reservation| Some(reservation.id) != exclude_id)
+        .collect();
+    let conflict = reservations
+        .iter()
+        .filter(|reservation| reservation.start_time < end_time && start_time < reservation.end_time)
+        .min_by_key(|reservation| reservation.start_time);
+    match conflict {
+        Some(reservation) => Err(Error::ReservationConflict {
+            car_id,
+            conflicting_reservation_id: reservation.id,
+            available_after: _free_from(&reservations, reservation.end_time).as_nanos(),
+        }),
+        None => Ok(()),
+    }
+}
+
+// A customer running into their own reservation most likely means to extend it,
+// which modify_reservation does, so they get told apart from other conflicts
+fn _same_customer_overlap(error: Error, customer_id: u64) -> Error {
+    match error {
+        Error::ReservationConflict {
+            car_id,
+            conflicting_reservation_id,
+            ..
+        } if _get_reservation(&conflicting_reservation_id)
+            .is_some_and(|reservation| reservation.customer_id == customer_id) =>
+        {
+            Error::SameCustomerOverlap {
+                car_id,
+                conflicting_reservation_id,
+            }
+        }
+        error => error,
+    }
+}
+
+// Earliest time at or after `from` not covered by any of the reservations,
+// walking through back-to-back reservations
+fn _free_from(reservations: &[Reservation], from: Timestamp) -> Timestamp {
+    let mut free_at = from;
+    while let Some(reservation) = reservations
+        .iter()
+        .find(|reservation| reservation.start_time <= free_at && free_at < reservation.end_time)
+    {
+        free_at = reservation.end_time;
+    }
+    free_at
+}
+
+fn do_insert_reservation(reservation: &Reservation) {
+    // Assuming MemoryId::new(3) is reserved for reservation storage
+    let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
+    
+    StableBTreeMap::<u64, Reservation, Memory>::init(reservation_storage)
+        .borrow_mut()
+        .insert(reservation.id, reservation.clone());
+}
+
+
+#[ic_cdk::query]
+fn get_reservation(id: u64) -> Result<Reservation, Error> {
+    match _get_reservation(&id) {
+        Some(reservation) => Ok(reservation),
+        None => Err(Error::NotFound {
+            msg: format!("a reservation with id={} not found", id),
+        }),
+    }
+}
+
+// Where a reservation stands right now. Reservations past their end count as
+// Completed even before the sweep archives them.
+#[ic_cdk::query]
+fn reservation_status(reservation_id: u64) -> Result<ReservationStatus, Error> {
+    if let Some(reservation) = _get_reservation(&reservation_id) {
+        let now = Timestamp::now();
+        return Ok(if now < reservation.start_time {
+            ReservationStatus::Upcoming
+        } else if now < reservation.end_time {
+            ReservationStatus::Active
+        } else {
+            ReservationStatus::Completed
+        });
+    }
+    match RESERVATION_ARCHIVE.with(|service| service.borrow().get(&reservation_id)) {
+        Some(reservation)
+            if matches!(
+                reservation.status,
+                ReservationStatus::Cancelled | ReservationStatus::NoShow
+            ) =>
+        {
+            Ok(reservation.status)
+        }
+        Some(_) => Ok(ReservationStatus::Completed),
+        None => Err(Error::NotFound {
+            msg: format!("a reservation with id={} not found", reservation_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_reservation_by_code(code: String) -> Result<Reservation, Error> {
+    let code = code.trim().to_uppercase();
+    let matches = |reservation: &Reservation| _confirmation_code(reservation.id) == code;
+    _get_reservations()
+        .into_iter()
+        .find(matches)
+        .or_else(|| {
+            RESERVATION_ARCHIVE.with(|service| {
+                service
+                    .borrow()
+                    .iter()
+                    .map(|(_, reservation)| reservation)
+                    .find(matches)
+            })
+        })
+        .ok_or(Error::NotFound {
+            msg: format!("a reservation with confirmation code {} not found", code),
+        })
+}
+
+// Scrambles the id with an odd multiplier and a salt, both bijections on 40 bits, so
+// distinct ids below 2^40 always get distinct codes while consecutive ids look unrelated
+fn _confirmation_code(id: u64) -> String {
+    const MASK: u64 = (1 << (5 * CONFIRMATION_CODE_LEN)) - 1;
+    let mut value = (id.wrapping_mul(CONFIRMATION_MULTIPLIER) & MASK) ^ CONFIRMATION_SALT;
+    let mut code = [0u8; CONFIRMATION_CODE_LEN];
+    for digit in code.iter_mut().rev() {
+        *digit = CONFIRMATION_ALPHABET[(value & 31) as usize];
+        value >>= 5;
+    }
+    String::from_utf8(code.to_vec()).unwrap()
+}
+
+#[ic_cdk::query]
+fn get_all_reservations() -> Result<Vec<Reservation>, Error> {
+    _check_if_admin()?;
+    Ok(_get_reservations())
+}
+
+// The reservation whose window contains the current time, if the car is taken right now
+#[ic_cdk::query]
+fn current_reservation(car_id: u64) -> Result<Option<Reservation>, Error> {
+    if _get_car(&car_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a car with id={} not found", car_id),
+        });
+    }
+    let now = Timestamp::now();
+    Ok(_get_car_reservations(&car_id)
+        .into_iter()
+        .find(|reservation| reservation.start_time <= now && now < reservation.end_time))
+}
+
+// Reservations starting within the next `within_ns`, soonest first. Admins see every
+// car's; other callers only those of customers they created.
+#[ic_cdk::query]
+fn upcoming_reservations(within_ns: u64) -> Vec<Reservation> {
+    let now = Timestamp::now();
+    let until = Timestamp(now.as_nanos().saturating_add(within_ns));
+    let is_admin = _check_if_admin().is_ok();
+    let caller = caller();
+    let mut reservations: Vec<Reservation> = _get_reservations()
+        .into_iter()
+        .filter(|reservation| now <= reservation.start_time && reservation.start_time <= until)
+        .filter(|reservation| {
+            is_admin
+                || _get_customer(&reservation.customer_id)
+                    .is_some_and(|customer| customer.created_by == Some(caller))
+        })
+        .collect();
+    reservations.sort_by_key(|reservation| reservation.start_time);
+    reservations
+}
+
+// The returns due in a window, e.g. one day, for the front desk: reservations,
+// including completed ones already archived, whose end_time falls within the
+// inclusive bounds, in end_time order. Staff see every car's; other callers only
+// those of customers they created.
+#[ic_cdk::query]
+fn reservations_ending_between(start: Timestamp, end: Timestamp) -> Result<Vec<Reservation>, Error> {
+    if end < start {
+        return Err(_field_error("end", "end must not be before start".to_string()));
+    }
+    let caller = caller();
+    let is_staff = _is_staff(&caller);
+    let archived: Vec<Reservation> = RESERVATION_ARCHIVE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, reservation)| reservation)
+            .filter(|reservation| reservation.status == ReservationStatus::Completed)
+            .collect()
+    });
+    let mut reservations: Vec<Reservation> = _get_reservations()
+        .into_iter()
+        .chain(archived)
+        .filter(|reservation| {
+            start <= reservation.end_time && reservation.end_time <= end
+        })
+        .filter(|reservation| {
+            is_staff
+                || _get_customer(&reservation.customer_id)
+                    .is_some_and(|customer| customer.created_by == Some(caller))
+        })
+        .collect();
+    reservations.sort_by_key(|reservation| reservation.end_time);
+    Ok(reservations)
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CarDetail {
+    car: Car,
+    current_reservation: Option<Reservation>,
+    // Reservations starting later, soonest first
+    upcoming_reservations: Vec<Reservation>,
+}
+
+// Everything a car's detail page needs in one call
+#[ic_cdk::query]
+fn car_detail(id: u64) -> Result<CarDetail, Error> {
+    let car = _get_car(&id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", id),
+    })?;
+    let now = Timestamp::now();
+    let reservations = _get_car_reservations(&id);
+    let current_reservation = reservations
+        .iter()
+        .find(|reservation| reservation.start_time <= now && now < reservation.end_time)
+        .cloned();
+    let mut upcoming_reservations: Vec<Reservation> = reservations
+        .into_iter()
+        .filter(|reservation| reservation.start_time > now)
+        .collect();
+    upcoming_reservations.sort_by_key(|reservation| reservation.start_time);
+    Ok(CarDetail {
+        car,
+        current_reservation,
+        upcoming_reservations,
+    })
+}
+
+// When the car frees up: the end of the run of back-to-back reservations covering
+// the current time or the end of a grace period, or now if the car is free
+#[ic_cdk::query]
+fn next_available_time(car_id: u64) -> Result<u64, Error> {
+    let car = _get_car(&car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", car_id),
+    })?;
+    let from = Timestamp::now().max(Timestamp(car.grace_until.unwrap_or_default()));
+    Ok(_free_from(&_get_car_reservations(&car_id), from).as_nanos())
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReservationPreview {
+    available: bool,
+    // Ids of the reservations overlapping the window, in start order
+    conflicts: Vec<u64>,
+    // Before coupons; None for cars without a daily price
+    estimated_cost: Option<u64>,
+}
+
+// A side-effect-free look at a proposed window for UIs; make_reservation stays the
+// authority and still runs every check
+#[ic_cdk::query]
+fn preview_reservation(
+    car_id: u64,
+    start_time: Timestamp,
+    end_time: Timestamp,
+) -> Result<ReservationPreview, Error> {
+    _validate_reservation_window(start_time, end_time)?;
+    let car = _get_car(&car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", car_id),
+    })?;
+    let mut conflicts: Vec<Reservation> = _get_car_reservations(&car_id)
+        .into_iter()
+        .filter(|reservation| reservation.start_time < end_time && start_time < reservation.end_time)
+        .collect();
+    conflicts.sort_by_key(|reservation| reservation.start_time);
+    let available = conflicts.is_empty()
+        && _check_rental_duration(start_time, end_time).is_ok()
+        && _check_lead_time(start_time).is_ok()
+        && _check_in_rotation(&car).is_ok()
+        && _check_grace_period(&car, start_time).is_ok()
+        && _check_blackouts(car_id, start_time, end_time).is_ok();
+    Ok(ReservationPreview {
+        available,
+        conflicts: conflicts.iter().map(|reservation| reservation.id).collect(),
+        estimated_cost: (car.daily_price > 0)
+            .then(|| _quote(&car, start_time.as_nanos(), end_time.as_nanos(), None).amount),
+    })
+}
+
+// Idle windows of at least min_gap_ns between now and horizon_end, in time order,
+// as (gap_start, gap_end) with gap_end exclusive. Reservations and blackouts both
+// keep the car busy.
+#[ic_cdk::query]
+fn find_gaps(car_id: u64, horizon_end: Timestamp, min_gap_ns: u64) -> Result<Vec<(u64, u64)>, Error> {
+    if _get_car(&car_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a car with id={} not found", car_id),
+        });
+    }
+    let horizon_end = horizon_end.as_nanos();
+    let mut busy: Vec<(u64, u64)> = _get_car_reservations(&car_id)
+        .iter()
+        .map(|reservation| (reservation.start_time.as_nanos(), reservation.end_time.as_nanos()))
+        .chain(
+            _get_blackouts(car_id)
+                .iter()
+                .map(|blackout| (blackout.start.as_nanos(), blackout.end.as_nanos())),
+        )
+        .collect();
+    busy.sort();
+    let mut gaps = Vec::new();
+    let mut free_from = time();
+    for (start, end) in busy {
+        if start >= horizon_end {
+            break;
+        }
+        if start > free_from && start - free_from >= min_gap_ns {
+            gaps.push((free_from, start));
+        }
+        free_from = free_from.max(end);
+    }
+    if horizon_end > free_from && horizon_end - free_from >= min_gap_ns {
+        gaps.push((free_from, horizon_end));
+    }
+    Ok(gaps)
+}
+
+fn _get_reservation(id: &u64) -> Option<Reservation> {
+    // Assuming MemoryId::new(3) is reserved for reservation storage
+    let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
+    StableBTreeMap::<u64, Reservation, Memory>::init(reservation_storage)
+        .borrow()
+        .get(id)
+}
+
+fn _get_reservations() -> Vec<Reservation> {
+    // Assuming MemoryId::new(3) is reserved for reservation storage
+    let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
+    StableBTreeMap::<u64, Reservation, Memory>::init(reservation_storage)
+        .borrow()
+        .iter()
+        .map(|(_, reservation)| reservation)
+        .collect()
+}
+
+fn _get_car_reservations(car_id: &u64) -> Vec<Reservation> {
+    _get_reservations()
+        .into_iter()
+        .filter(|reservation| reservation.car_id == *car_id)
+        .collect()
+}
+
+fn _get_customer_reservations(customer_id: &u64) -> Vec<Reservation> {
+    _get_reservations()
+        .into_iter()
+        .filter(|reservation| reservation.customer_id == *customer_id)
+        .collect()
+}
+
+#[ic_cdk::update]
+fn cancel_reservation(id: u64) -> Result<Reservation, Error> {
+    _check_writable()?;
+    _archive_expired_reservations();
+    match _get_reservation(&id) {
+        Some(mut reservation) => {
+            _check_can_cancel(&reservation)?;
+            if reservation.checked_out_at.is_some() {
+                return Err(Error::InvalidState {
+                    msg: format!(
+                        "the reservation with id={} is checked out, check the car in instead",
+                        id
+                    ),
+                });
+            }
+            reservation.cancellation_fee = Some(_cancellation_fee(&reservation));
+            let car_id = reservation.car_id;
+            _archive_reservation(reservation, ReservationStatus::Cancelled);
+            _record_event(EventKind::ReservationCancelled, Some(id));
+            if let Some(mut car) = _get_car(&car_id) {
+                let grace_period = GRACE_PERIOD.with(|cell| *cell.borrow().get());
+                if grace_period > 0 {
+                    let grace_until = time().saturating_add(grace_period);
+                    car.grace_until = Some(car.grace_until.unwrap_or_default().max(grace_until));
+                    // Trapping rolls back the cancellation rather than leaving it half done
+                    do_insert_car(&mut car).expect("a grace period adds only a few bytes to a car");
+                }
+                _notify_owner(&car, _cancelled_message(&car, id));
+            }
+            RESERVATION_ARCHIVE
+                .with(|service| service.borrow().get(&id))
+                .ok_or_else(|| _internal_error("the reservation was just archived", id))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("a reservation with id={} not found", id),
+        }),
+    }
+}
+
+// Cancelling may charge a fee, so only the principal that made the reservation, the
+// creator of its customer and staff may do it
+fn _check_can_cancel(reservation: &Reservation) -> Result<(), Error> {
+    let caller = caller();
+    let created_customer = _get_customer(&reservation.customer_id)
+        .is_some_and(|customer| customer.created_by == Some(caller));
+    if reservation.reserved_by != caller && !created_customer && !_is_staff(&caller) {
+        return Err(Error::NotAuthorized {
+            msg: format!("caller can't cancel the reservation with id={}", reservation.id),
+        });
+    }
+    Ok(())
+}
+
+fn _cancellation_fee(reservation: &Reservation) -> u64 {
+    let policy = CANCELLATION_POLICY.with(|cell| *cell.borrow().get());
+    let now = time();
+    let start_time = reservation.start_time.as_nanos();
+    if now >= start_time {
+        policy.no_show_fee
+    } else if start_time - now < policy.free_cancellation_ns {
+        policy.late_cancellation_fee
+    } else {
+        0
+    }
+}
+
+// For renters who never turned up: archives the reservation as NoShow, charging the
+// policy's no_show_fee, so the rest of its window can be booked again. Staff or the
+// car's owner only.
+#[ic_cdk::update]
+fn mark_no_show(reservation_id: u64) -> Result<Reservation, Error> {
+    _check_writable()?;
+    // No sweep first: it would archive a no-show whose window has passed as Completed
+    let (mut reservation, _) = _handover(reservation_id)?;
+    if reservation.checked_out_at.is_some() {
+        return Err(Error::InvalidState {
+            msg: format!("the reservation with id={} is checked out", reservation_id),
+        });
+    }
+    if Timestamp::now() < reservation.start_time {
+        return Err(Error::InvalidState {
+            msg: format!("the reservation with id={} hasn't started yet", reservation_id),
+        });
+    }
+    reservation.cancellation_fee =
+        Some(CANCELLATION_POLICY.with(|cell| cell.borrow().get().no_show_fee));
+    _archive_reservation(reservation, ReservationStatus::NoShow);
+    _record_event(EventKind::ReservationNoShow, Some(reservation_id));
+    RESERVATION_ARCHIVE
+        .with(|service| service.borrow().get(&reservation_id))
+        .ok_or_else(|| _internal_error("the reservation was just archived", reservation_id))
+}
+
+// Bytes of make and model quoted in a notification; 64 characters may take up to 256 bytes
+// each, which would push a notification past its bound
+const NOTIFIED_NAME_BYTES: usize = 64;
+
+// The longest prefix of text within max_bytes that ends on a character boundary
+fn _truncate_bytes(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+// The make and model of a car as quoted in notifications
+fn _notified_name(car: &Car) -> String {
+    format!(
+        "{} {}",
+        _truncate_bytes(&car.make, NOTIFIED_NAME_BYTES),
+        _truncate_bytes(&car.model, NOTIFIED_NAME_BYTES)
+    )
+}
+
+fn _reserved_message(car: &Car, reservation_id: u64) -> String {
+    format!(
+        "Your {} (id={}) was reserved (reservation id={})",
+        _notified_name(car),
+        car.id,
+        reservation_id
+    )
+}
+
+fn _cancelled_message(car: &Car, reservation_id: u64) -> String {
+    format!(
+        "The reservation with id={} of your {} (id={}) was cancelled",
+        reservation_id,
+        _notified_name(car),
+        car.id
+    )
+}
+
+// How long before its window starts a reservation's car may be picked up, so a renter
+// arriving a little early isn't turned away
+const EARLY_PICKUP_NS: u64 = 60 * 60 * 1_000_000_000;
+
+// Records the hand-over of the car to the renter, from EARLY_PICKUP_NS before the
+// reservation's window starts until the window ends
+#[ic_cdk::update]
+fn check_out(reservation_id: u64, odometer: u64) -> Result<Reservation, Error> {
+    _check_writable()?;
+    _archive_expired_reservations();
+    let (mut reservation, mut car) = _handover(reservation_id)?;
+    if reservation.checked_out_at.is_some() {
+        return Err(Error::InvalidState {
+            msg: format!("the reservation with id={} is already checked out", reservation_id),
+        });
+    }
+    let now = time();
+    let earliest = reservation.start_time.as_nanos().saturating_sub(EARLY_PICKUP_NS);
+    if now < earliest || reservation.end_time.as_nanos() <= now {
+        return Err(Error::InvalidState {
+            msg: format!(
+                "the reservation with id={} can only be checked out from {} until {}",
+                reservation_id,
+                earliest,
+                reservation.end_time.as_nanos()
+            ),
+        });
+    }
+    _check_in_rotation(&car)?;
+    reservation.checked_out_at = Some(time());
+    reservation.checkout_odometer = Some(odometer);
+    do_insert_reservation(&reservation);
+    car.status = CarStatus::Booked;
+    do_insert_car(&mut car)?;
+    _record_event(EventKind::ReservationCheckedOut, Some(reservation_id));
+    Ok(reservation)
+}
+
+// Records the return of the car, adds the distance driven to its mileage and
+// completes the reservation, freeing the car unless other reservations hold it
+#[ic_cdk::update]
+fn check_in(reservation_id: u64, odometer: u64) -> Result<Reservation, Error> {
+    _check_writable()?;
+    let (mut reservation, mut car) = _handover(reservation_id)?;
+    let Some(checkout_odometer) = reservation.checkout_odometer else {
+        return Err(Error::InvalidState {
+            msg: format!("the reservation with id={} isn't checked out", reservation_id),
+        });
+    };
+    if odometer < checkout_odometer {
+        return Err(_field_error(
+            "odometer",
+            format!(
+                "odometer must not be below the check-out reading of {}",
+                checkout_odometer
+            ),
+        ));
+    }
+    car.mileage = car.mileage.saturating_add(odometer - checkout_odometer);
+    do_insert_car(&mut car)?;
+    let now = time();
+    reservation.checked_in_at = Some(now);
+    reservation.checkin_odometer = Some(odometer);
+    reservation.late_fee = Some(_late_fee(reservation.end_time.as_nanos(), now));
+    _archive_reservation(reservation, ReservationStatus::Completed);
+    _record_event(EventKind::ReservationCheckedIn, Some(reservation_id));
+    RESERVATION_ARCHIVE
+        .with(|service| service.borrow().get(&reservation_id))
+        .ok_or_else(|| _internal_error("the reservation was just archived", reservation_id))
+}
+
+// Gives a held deposit back ahead of the reservation ending; it happens anyway
+// when the reservation completes or is cancelled
+#[ic_cdk::update]
+fn release_deposit(reservation_id: u64) -> Result<Reservation, Error> {
+    _check_writable()?;
+    _settle_deposit(reservation_id, DepositState::Released, EventKind::DepositReleased)
+}
+
+// Keeps a held deposit, e.g. for damage found at check-in; forfeit before check_in,
+// which releases deposits still held
+#[ic_cdk::update]
+fn forfeit_deposit(reservation_id: u64, reason: String) -> Result<Reservation, Error> {
+    _check_writable()?;
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err(_field_error(
+            "reason",
+            format!("reason must be between 1 and {} bytes long", MAX_REASON_LEN),
+        ));
+    }
+    _settle_deposit(
+        reservation_id,
+        DepositState::Forfeited { reason },
+        EventKind::DepositForfeited,
+    )
+}
+
+fn _settle_deposit(reservation_id: u64, state: DepositState, event: EventKind) -> Result<Reservation, Error> {
+    let (mut reservation, car) = _get_any_reservation(reservation_id)?;
+    _check_if_owner_or_admin(&car)?;
+    if reservation.deposit_state != Some(DepositState::Held) {
+        return Err(Error::InvalidState {
+            msg: format!("the reservation with id={} holds no deposit", reservation_id),
+        });
+    }
+    reservation.deposit_state = Some(state);
+    if reservation.status == ReservationStatus::Active {
+        do_insert_reservation(&reservation);
+    } else {
+        RESERVATION_ARCHIVE.with(|service| service.borrow_mut().insert(reservation_id, reservation.clone()));
+    }
+    _record_event(event, Some(reservation_id));
+    Ok(reservation)
+}
+
+// A reservation, active or archived, and its car
+fn _get_any_reservation(reservation_id: u64) -> Result<(Reservation, Car), Error> {
+    let reservation = _get_reservation(&reservation_id)
+        .or_else(|| RESERVATION_ARCHIVE.with(|service| service.borrow().get(&reservation_id)))
+        .ok_or(Error::NotFound {
+            msg: format!("a reservation with id={} not found", reservation_id),
+        })?;
+    let car = _get_car(&reservation.car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", reservation.car_id),
+    })?;
+    Ok((reservation, car))
+}
+
+// An active reservation and its car, if the caller is staff or the car's owner
+fn _handover(reservation_id: u64) -> Result<(Reservation, Car), Error> {
+    let reservation = _get_reservation(&reservation_id).ok_or(Error::NotFound {
+        msg: format!("an active reservation with id={} not found", reservation_id),
+    })?;
+    let car = _get_car(&reservation.car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", reservation.car_id),
+    })?;
+    if !_is_staff(&caller()) {
+        _check_if_owner(&car)?;
+    }
+    Ok((reservation, car))
+}
+
+// Notifications are pulled: owners read their inbox through my_notifications
+fn _notify_owner(car: &Car, message: String) {
+    // Owners are free text; only those naming a principal can receive notifications
+    let Ok(recipient) = Principal::from_text(&car.owner) else {
+        return;
+    };
+    let id = _next_id(&NOTIFICATION_ID_COUNTER).expect("notification ids exhausted");
+    let notification = Notification {
+        id,
+        recipient,
+        message,
+        created_at: time(),
+        read: false,
+    };
+    NOTIFICATIONS.with(|service| {
+        service
+            .borrow_mut()
+            .insert((PrincipalKey(recipient), id), notification)
+    });
+}
+
+// The caller's notifications, oldest first
+#[ic_cdk::query]
+fn my_notifications() -> Vec<Notification> {
+    let recipient = PrincipalKey(caller());
+    NOTIFICATIONS.with(|service| {
+        service
+            .borrow()
+            .range((recipient, 0)..)
+            .take_while(|((key, _), _)| *key == recipient)
+            .map(|(_, notification)| notification)
+            .collect()
+    })
+}
+
+// Not recorded in the event log: read flags are the recipient's own inbox state, and
+// logging every read would crowd business events out of the capped log
+#[ic_cdk::update]
+fn mark_notification_read(id: u64) -> Result<Notification, Error> {
+    _check_writable()?;
+    let key = (PrincipalKey(caller()), id);
+    let mut notification = NOTIFICATIONS
+        .with(|service| service.borrow().get(&key))
+        .ok_or(Error::NotFound {
+            msg: format!("a notification with id={} not found", id),
+        })?;
+    notification.read = true;
+    NOTIFICATIONS.with(|service| service.borrow_mut().insert(key, notification.clone()));
+    Ok(notification)
+}
+
+// Cost of a reservation in its car's currency
+#[ic_cdk::query]
+fn reservation_cost(reservation_id: u64) -> Result<Price, Error> {
+    let reservation = _get_reservation(&reservation_id)
+        .or_else(|| RESERVATION_ARCHIVE.with(|service| service.borrow().get(&reservation_id)))
+        .ok_or(Error::NotFound {
+            msg: format!("a reservation with id={} not found", reservation_id),
+        })?;
+    if let Some(cost) = reservation.cost {
+        return Ok(cost);
+    }
+    let car = _get_car(&reservation.car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", reservation.car_id),
+    })?;
+    Ok(_compute_cost(
+        &car,
+        reservation.start_time.as_nanos(),
+        reservation.end_time.as_nanos(),
+    ))
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CostBreakdown {
+    base: u64,
+    late_fee: u64,
+    total: u64,
+}
+
+// The reservation's cost plus any late fee: the one charged at check-in or, for a
+// car still out past its end_time, the fee accrued so far
+#[ic_cdk::query]
+fn reservation_cost_breakdown(reservation_id: u64) -> Result<CostBreakdown, Error> {
+    let base = reservation_cost(reservation_id)?.amount;
+    let reservation = _get_reservation(&reservation_id)
+        .or_else(|| RESERVATION_ARCHIVE.with(|service| service.borrow().get(&reservation_id)))
+        .ok_or(Error::NotFound {
+            msg: format!("a reservation with id={} not found", reservation_id),
+        })?;
+    let late_fee = match (reservation.late_fee, reservation.checked_out_at) {
+        (Some(late_fee), _) => late_fee,
+        (None, Some(_)) if reservation.checked_in_at.is_none() => {
+            _late_fee(reservation.end_time.as_nanos(), time())
+        }
+        _ => 0,
+    };
+    Ok(CostBreakdown {
+        base,
+        late_fee,
+        total: base.saturating_add(late_fee),
+    })
+}
+
+// Every started day past end_time is charged in full
+fn _late_fee(end_time: u64, returned_at: u64) -> u64 {
+    let late_days = returned_at.saturating_sub(end_time).div_ceil(NANOS_PER_DAY);
+    let fee_per_day = LATE_FEE_PER_DAY.with(|cell| *cell.borrow().get());
+    late_days.saturating_mul(fee_per_day)
+}
+
+// Verifies with the ledger that the transfer in `block_index` paid at least the reservation's
+// cost into the canister's default account, then marks the reservation paid. The transfer's
+// memo must be the reservation id, so a payment can't be claimed for someone else's booking
+#[ic_cdk::update]
+async fn settle_reservation(reservation_id: u64, block_index: u64) -> Result<Reservation, Error> {
+    _check_writable()?;
+    let cost = _settleable_reservation(reservation_id, block_index)?
+        .cost
+        .ok_or(Error::PaymentFailed {
+            msg: format!("the reservation with id={} has no cost to settle", reservation_id),
+        })?;
+    if cost.currency != DEFAULT_CURRENCY {
+        return Err(Error::PaymentFailed {
+            msg: format!("only {} reservations can be settled on the ledger", DEFAULT_CURRENCY),
+        });
+    }
+    let ledger = LEDGER_CANISTER.with(|cell| cell.borrow().get().0);
+    let block = _fetch_block(ledger, block_index).await?;
+    let own_account = AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT);
+    _check_payment(&block, block_index, &own_account, reservation_id, cost.amount)?;
+    // State may have changed while the ledger was answering
+    let mut reservation = _settleable_reservation(reservation_id, block_index)?;
+    reservation.paid_block = Some(block_index);
+    if _get_reservation(&reservation_id).is_some() {
+        do_insert_reservation(&reservation);
+    } else {
+        RESERVATION_ARCHIVE.with(|service| service.borrow_mut().insert(reservation_id, reservation.clone()));
+    }
+    SETTLED_BLOCKS.with(|service| service.borrow_mut().insert(block_index, reservation_id));
+    _record_event(EventKind::ReservationSettled, Some(reservation_id));
+    Ok(reservation)
+}
+
+// Checks that a ledger block is a transfer of at least `required` e8s into `account`,
+// with the reservation id as its memo
+fn _check_payment(
+    block: &Block,
+    block_index: u64,
+    account: &AccountIdentifier,
+    reservation_id: u64,
+    required: u64,
+) -> Result<(), Error> {
+    let received = match block.transaction.operation {
+        Some(Operation::Transfer { to, amount, .. }) | Some(Operation::TransferFrom { to, amount, .. })
+            if to == *account =>
+        {
+            amount.e8s()
+        }
+        _ => {
+            return Err(Error::PaymentFailed {
+                msg: format!("block {} is not a transfer to this canister", block_index),
+            })
+        }
+    };
+    if block.transaction.memo != Memo(reservation_id) {
+        return Err(Error::PaymentFailed {
+            msg: format!(
+                "the transfer in block {} has memo {}, not the reservation id {}",
+                block_index, block.transaction.memo.0, reservation_id
+            ),
+        });
+    }
+    if received < required {
+        return Err(Error::InsufficientPayment { required, received });
+    }
+    Ok(())
+}
+
+// Cancelled and already paid reservations can't be settled, and a block pays only once
+fn _settleable_reservation(reservation_id: u64, block_index: u64) -> Result<Reservation, Error> {
+    let reservation = _get_reservation(&reservation_id)
+        .or_else(|| RESERVATION_ARCHIVE.with(|service| service.borrow().get(&reservation_id)))
+        .ok_or(Error::NotFound {
+            msg: format!("a reservation with id={} not found", reservation_id),
+        })?;
+    if reservation.status == ReservationStatus::Cancelled {
+        return Err(Error::PaymentFailed {
+            msg: format!("the reservation with id={} was cancelled", reservation_id),
+        });
+    }
+    if reservation.paid_block.is_some() {
+        return Err(Error::PaymentFailed {
+            msg: format!("the reservation with id={} is already paid", reservation_id),
+        });
+    }
+    if let Some(paid_for) = SETTLED_BLOCKS.with(|service| service.borrow().get(&block_index)) {
+        return Err(Error::PaymentFailed {
+            msg: format!(
+                "block {} already paid for the reservation with id={}",
+                block_index, paid_for
+            ),
+        });
+    }
+    Ok(reservation)
+}
+
+// Looks the block up on the ledger, following it into the ledger's archive if needed
+async fn _fetch_block(ledger: Principal, block_index: u64) -> Result<Block, Error> {
+    let ledger_error = |(code, msg): (ic_cdk::api::call::RejectionCode, String)| Error::PaymentFailed {
+        msg: format!("the ledger rejected the call ({:?}): {}", code, msg),
+    };
+    let args = GetBlocksArgs {
+        start: block_index,
+        length: 1,
+    };
+    let response = query_blocks(ledger, args.clone()).await.map_err(ledger_error)?;
+    if let Some(block) = response.blocks.into_iter().next() {
+        return Ok(block);
+    }
+    let archive = response
+        .archived_blocks
+        .into_iter()
+        .find(|range| range.start <= block_index && block_index - range.start < range.length);
+    if let Some(range) = archive {
+        if let Ok(blocks) = query_archived_blocks(&range.callback, args).await.map_err(ledger_error)? {
+            if let Some(block) = blocks.blocks.into_iter().next() {
+                return Ok(block);
+            }
+        }
+    }
+    Err(Error::PaymentFailed {
+        msg: format!("block {} not found on the ledger", block_index),
+    })
+}
+
+// Cost of a window with the coupon's discount taken off, rounded down
+fn _quote(car: &Car, start_time: u64, end_time: u64, coupon: Option<&Coupon>) -> Price {
+    let mut price = _compute_cost(car, start_time, end_time);
+    if let Some(coupon) = coupon {
+        let percent_paid = 100 - u128::from(coupon.percent_off);
+        price.amount = (u128::from(price.amount) * percent_paid / 100) as u64;
+    }
+    price
+}
+
+// Prices a changed reservation again, keeping a coupon it was booked with even if
+// the coupon has expired since
+fn _requote(car: &Car, reservation: &Reservation) -> Price {
+    let coupon = reservation
+        .coupon_code
+        .as_ref()
+        .and_then(|code| COUPONS.with(|service| service.borrow().get(&CouponCode(code.clone()))));
+    _quote(
+        car,
+        reservation.start_time.as_nanos(),
+        reservation.end_time.as_nanos(),
+        coupon.as_ref(),
+    )
+}
+
+fn _redeemable_coupon(code: &str) -> Result<Coupon, Error> {
+    let code = code.trim().to_uppercase();
+    if code.is_empty() || code.len() > MAX_COUPON_CODE_LEN {
+        return Err(Error::InvalidCoupon {
+            msg: format!("coupon code {} is not valid", code),
+        });
+    }
+    let coupon = COUPONS
+        .with(|service| service.borrow().get(&CouponCode(code.clone())))
+        .ok_or(Error::InvalidCoupon {
+            msg: format!("coupon code {} is not valid", code),
+        })?;
+    if coupon.valid_until < time() {
+        return Err(Error::InvalidCoupon {
+            msg: format!("coupon code {} has expired", code),
+        });
+    }
+    if coupon.uses >= coupon.max_uses {
+        return Err(Error::InvalidCoupon {
+            msg: format!("coupon code {} has been used up", code),
+        });
+    }
+    Ok(coupon)
+}
+
+// Every started day is charged in full. A day counts as a weekend day when it starts
+// on a Saturday or Sunday, and is charged at the car's weekend multiplier. Cars with
+// a monthly or weekly price charge full 30-day months, then full weeks, at those
+// prices and only the days left over by the day, unless paying by the day is cheaper.
+fn _compute_cost(car: &Car, start_time: u64, end_time: u64) -> Price {
+    let days = end_time.saturating_sub(start_time).div_ceil(NANOS_PER_DAY);
+    let months = if car.monthly_price.is_some() { days / 30 } else { 0 };
+    let weeks = if car.weekly_price.is_some() { (days - months * 30) / 7 } else { 0 };
+    let rest_days = days - months * 30 - weeks * 7;
+    let rest_start = start_time.saturating_add((days - rest_days) * NANOS_PER_DAY);
+    let long_term_amount = car
+        .monthly_price
+        .unwrap_or_default()
+        .saturating_mul(months)
+        .saturating_add(car.weekly_price.unwrap_or_default().saturating_mul(weeks))
+        .saturating_add(_daily_amount(car, rest_start, rest_days));
+    Price {
+        amount: _daily_amount(car, start_time, days).min(long_term_amount),
+        currency: car.currency.clone(),
+    }
+}
+
+fn _daily_amount(car: &Car, start_time: u64, days: u64) -> u64 {
+    let weekend_days = _weekend_days(start_time, days);
+    let weekday_amount = car.daily_price.saturating_mul(days - weekend_days);
+    let weekend_amount =
+        (car.daily_price as f64 * car.weekend_multiplier * weekend_days as f64).round() as u64;
+    weekday_amount.saturating_add(weekend_amount)
+}
+
+// Number of Saturdays and Sundays among `days` consecutive days, each starting a day
+// after the previous one, the first at `start_time`
+fn _weekend_days(start_time: u64, days: u64) -> u64 {
+    let first_weekday = _weekday_of(start_time);
+    let mut weekend_days = days / 7 * 2;
+    for offset in 0..days % 7 {
+        if matches!((first_weekday + offset) % 7, 0 | 6) {
+            weekend_days += 1;
+        }
+    }
+    weekend_days
+}
+
+// Day of the week of a timestamp in nanoseconds, UTC, from 0 for Sunday to 6 for Saturday
+fn _weekday_of(timestamp: u64) -> u64 {
+    // The Unix epoch fell on a Thursday
+    (timestamp / NANOS_PER_DAY + 4) % 7
+}
+
+// Moves a reservation out of the active storage into the archive
+fn _archive_reservation(mut reservation: Reservation, status: ReservationStatus) {
+    // Assuming MemoryId::new(3) is reserved for reservation storage
+    let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
+    StableBTreeMap::<u64, Reservation, Memory>::init(reservation_storage)
+        .borrow_mut()
+        .remove(&reservation.id);
+    // A completed reservation ended when the car was checked in or, failing that, when
+    // its window closed, not when the sweep noticed it
+    reservation.ended_at = Some(match status {
+        ReservationStatus::Completed => reservation
+            .checked_in_at
+            .unwrap_or(reservation.end_time.as_nanos()),
+        _ => time(),
+    });
+    reservation.status = status;
+    // Deposits not forfeited by the time the reservation ends are given back
+    if reservation.deposit_state == Some(DepositState::Held) {
+        reservation.deposit_state = Some(DepositState::Released);
+        _record_event(EventKind::DepositReleased, Some(reservation.id));
+    }
+    RESERVATION_ARCHIVE.with(|service| service.borrow_mut().insert(reservation.id, reservation.clone()));
+    _refresh_booking_status(&reservation.car_id);
+}
+
+// A car is booked while a reservation holds it right now and available otherwise, so
+// a reservation days ahead doesn't take it off the available list yet.
+// Cars in maintenance or retired keep their status.
+fn _refresh_booking_status(car_id: &u64) {
+    if let Some(mut car) = _get_car(car_id) {
+        let now = Timestamp::now();
+        let booked = _get_car_reservations(car_id)
+            .iter()
+            .any(|reservation| _holds_car_now(reservation, now));
+        let status = match car.status {
+            CarStatus::Available | CarStatus::Booked if booked => CarStatus::Booked,
+            CarStatus::Available | CarStatus::Booked => CarStatus::Available,
+            status => status,
+        };
+        // Rewriting an unchanged car would bump its version for nothing
+        if status != car.status {
+            car.status = status;
+            do_insert_car(&mut car).expect("changing the status doesn't change a car's size");
+        }
+    }
+}
+
+// The reservation's window is under way, or its car was checked out and hasn't been
+// checked in yet
+fn _holds_car_now(reservation: &Reservation, now: Timestamp) -> bool {
+    reservation.checked_out_at.is_some()
+        || (reservation.start_time <= now && now < reservation.end_time)
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReconcileReport {
+    cars_checked: u64,
+    cars_changed: u64,
+    changed_ids: Vec<u64>,
+}
+
+// One-shot repair for cars whose booked status drifted from their reservations
+#[ic_cdk::update]
+fn reconcile_booking_flags() -> Result<ReconcileReport, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    // The sweep refreshes some cars itself, so statuses are compared with those from before it
+    let cars = _get_cars();
+    _archive_expired_reservations();
+    let changed_ids: Vec<u64> = cars
+        .iter()
+        .filter(|car| {
+            _refresh_booking_status(&car.id);
+            _get_car(&car.id).is_some_and(|now| now.status != car.status)
+        })
+        .map(|car| car.id)
+        .collect();
+    for id in &changed_ids {
+        _record_event(EventKind::CarStatusChanged, Some(*id));
+    }
+    Ok(ReconcileReport {
+        cars_checked: cars.len() as u64,
+        cars_changed: changed_ids.len() as u64,
+        changed_ids,
+    })
+}
+
+const MAX_INTEGRITY_SAMPLES: usize = 10;
+
+// How many records have one kind of problem, and the ids of the first few
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct IntegrityIssue {
+    count: u64,
+    sample_ids: Vec<u64>,
+}
+
+impl IntegrityIssue {
+    fn from_ids(ids: impl Iterator<Item = u64>) -> Self {
+        let mut issue = IntegrityIssue::default();
+        for id in ids {
+            if issue.sample_ids.len() < MAX_INTEGRITY_SAMPLES {
+                issue.sample_ids.push(id);
+            }
+            issue.count += 1;
+        }
+        issue
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct IntegrityReport {
+    corrupt_cars: IntegrityIssue,
+    // Active reservations whose car or customer no longer exists
+    reservations_without_car: IntegrityIssue,
+    reservations_without_customer: IntegrityIssue,
+    booked_cars_without_reservation: IntegrityIssue,
+    // A counter at or below the highest id in use would hand that id out again
+    id_counter_behind: bool,
+    reservation_counter_behind: bool,
+}
+
+// Read-only consistency scan for operators; reconcile_booking_flags repairs booked
+// statuses, the other findings need a closer look
+#[ic_cdk::query]
+fn integrity_check() -> Result<IntegrityReport, Error> {
+    _check_if_admin()?;
+    let corrupt_cars: Vec<u64> = CAR_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(id, record)| record.decode(*id).is_err())
+            .map(|(id, _)| id)
+            .collect()
+    });
+    let reservations = _get_reservations();
+    let customer_ids: BTreeSet<u64> = _get_customers().iter().map(|customer| customer.id).collect();
+    // The same rule _refresh_booking_status applies, so reconcile_booking_flags would free these cars
+    let now = Timestamp::now();
+    let held_cars: BTreeSet<u64> = reservations
+        .iter()
+        .filter(|reservation| _holds_car_now(reservation, now))
+        .map(|reservation| reservation.car_id)
+        .collect();
+    let cars = _get_cars();
+    // Corrupt cars still exist, they just can't be read
+    let car_ids: BTreeSet<u64> = CAR_STORAGE.with(|service| service.borrow().iter().map(|(id, _)| id).collect());
+    let max_id = car_ids
+        .iter()
+        .copied()
+        .chain(DELETED_CAR_STORAGE.with(|service| {
+            service.borrow().iter().map(|(id, _)| id).collect::<Vec<u64>>()
+        }))
+        .chain(customer_ids.iter().copied())
+        .max();
+    let max_reservation_id = reservations
+        .iter()
+        .map(|reservation| reservation.id)
+        .chain(RESERVATION_ARCHIVE.with(|service| service.borrow().last_key_value().map(|(id, _)| id)))
+        .max();
+    let id_counter = ID_COUNTER.with(|counter| *counter.borrow().get());
+    let reservation_counter = ID_RESERVATION_COUNTER.with(|counter| *counter.borrow().get());
+    Ok(IntegrityReport {
+        corrupt_cars: IntegrityIssue::from_ids(corrupt_cars.iter().copied()),
+        reservations_without_car: IntegrityIssue::from_ids(
+            reservations
+                .iter()
+                .filter(|reservation| !car_ids.contains(&reservation.car_id))
+                .map(|reservation| reservation.id),
+        ),
+        reservations_without_customer: IntegrityIssue::from_ids(
+            reservations
+                .iter()
+                .filter(|reservation| !customer_ids.contains(&reservation.customer_id))
+                .map(|reservation| reservation.id),
+        ),
+        booked_cars_without_reservation: IntegrityIssue::from_ids(
+            cars.iter()
+                .filter(|car| car.status == CarStatus::Booked && !held_cars.contains(&car.id))
+                .map(|car| car.id),
+        ),
+        id_counter_behind: max_id.is_some_and(|max_id| id_counter <= max_id),
+        reservation_counter_behind: max_reservation_id.is_some_and(|max_id| reservation_counter <= max_id),
+    })
+}
+
+// Cars in maintenance or retired can't take new reservations
+fn _check_in_rotation(car: &Car) -> Result<(), Error> {
+    match car.status {
+        CarStatus::Maintenance | CarStatus::Retired => Err(Error::CarUnavailable {
+            msg: format!("a car with id={} is out of rotation", car.id),
+        }),
+        CarStatus::Available | CarStatus::Booked => Ok(()),
+    }
+}
+
+// There is no timer driving expiry, so reservation updates sweep finished reservations first
+// Cars checked out and not yet returned stay reserved past the window until check_in.
+fn _archive_expired_reservations() {
+    let now = Timestamp::now();
+    _get_reservations()
+        .into_iter()
+        .filter(|reservation| reservation.end_time <= now)
+        .filter(|reservation| reservation.checked_out_at.is_none())
+        .for_each(|reservation| _archive_reservation(reservation, ReservationStatus::Completed));
+    // Cars whose reservation started since the last sweep become booked
+    let started: std::collections::BTreeSet<u64> = _get_reservations()
+        .iter()
+        .filter(|reservation| reservation.start_time <= now)
+        .map(|reservation| reservation.car_id)
+        .collect();
+    for car_id in started {
+        _refresh_booking_status(&car_id);
+    }
+}
+
+#[ic_cdk::query]
+fn reservation_history(car_id: u64) -> Vec<Reservation> {
+    let mut history: Vec<Reservation> = RESERVATION_ARCHIVE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, reservation)| reservation.car_id == car_id)
+            .map(|(_, reservation)| reservation)
+            .collect()
+    });
+    history.sort_by_key(|reservation| reservation.start_time);
+    history
+}
+
+// Windows are treated as closed intervals here, so a reservation ending exactly at
+// the start of the period still counts towards it
+#[ic_cdk::query]
+fn reservations_in_range(start: Timestamp, end: Timestamp) -> Result<Vec<Reservation>, Error> {
+    if end <= start {
+        return Err(_field_error("end", "end must be after start".to_string()));
+    }
+    let mut reservations: Vec<Reservation> = _get_honored_reservations()
+        .into_iter()
+        .filter(|reservation| reservation.start_time <= end && start <= reservation.end_time)
+        .collect();
+    reservations.sort_by_key(|reservation| reservation.start_time);
+    Ok(reservations)
+}
+
+// What the caller's cars earned from paid reservations whose window lies within
+// [start, end]. Only ICP reservations can be paid, so the total is in e8s.
+#[ic_cdk::query]
+fn owner_revenue(start: Timestamp, end: Timestamp) -> Result<u64, Error> {
+    if end < start {
+        return Err(_field_error("end", "end must not be before start".to_string()));
+    }
+    let owner = caller().to_string();
+    let owns = |car_id: &u64| {
+        _get_car(car_id)
+            .or_else(|| {
+                DELETED_CAR_STORAGE
+                    .with(|service| service.borrow().get(car_id))
+                    .and_then(|record| record.decode(*car_id).ok())
+            })
+            .is_some_and(|car| car.owner == owner)
+    };
+    Ok(_get_honored_reservations()
+        .iter()
+        .filter(|reservation| reservation.paid_block.is_some())
+        .filter(|reservation| start <= reservation.start_time && reservation.end_time <= end)
+        .filter(|reservation| owns(&reservation.car_id))
+        .filter_map(|reservation| reservation.cost.as_ref().map(|cost| cost.amount))
+        .fold(0u64, u64::saturating_add))
+}
+
+// Share of the window, from 0.0 to 1.0, during which the car was reserved
+#[ic_cdk::query]
+fn car_occupancy(car_id: u64, window_start: Timestamp, window_end: Timestamp) -> Result<f64, Error> {
+    if _get_car(&car_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a car with id={} not found", car_id),
+        });
+    }
+    if window_end <= window_start {
+        return Err(_field_error(
+            "window_end",
+            "window_end must be after window_start".to_string(),
+        ));
+    }
+    // Reservations of one car never overlap each other, so their clamped durations add up
+    let reserved: u64 = _get_honored_reservations()
+        .iter()
+        .filter(|reservation| reservation.car_id == car_id)
+        .map(|reservation| {
+            let start = reservation.start_time.max(window_start);
+            let end = reservation.end_time.min(window_end);
+            end.as_nanos().saturating_sub(start.as_nanos())
+        })
+        .sum();
+    Ok(reserved as f64 / (window_end.as_nanos() - window_start.as_nanos()) as f64)
+}
+
+// Upper bound for planning: what the window would earn at current prices, weekend
+// rates included, if every car not retired were booked for all of it
+#[ic_cdk::query]
+fn revenue_potential(window_start: Timestamp, window_end: Timestamp) -> Result<u64, Error> {
+    if window_end <= window_start {
+        return Err(_field_error(
+            "window_end",
+            "window_end must be after window_start".to_string(),
+        ));
+    }
+    Ok(_get_cars()
+        .iter()
+        .filter(|car| car.status != CarStatus::Retired)
+        .map(|car| _compute_cost(car, window_start.as_nanos(), window_end.as_nanos()).amount)
+        .fold(0u64, u64::saturating_add))
+}
+
+// Active reservations plus archived ones that were neither cancelled nor no-shows
+fn _get_honored_reservations() -> Vec<Reservation> {
+    let archived: Vec<Reservation> = RESERVATION_ARCHIVE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, reservation)| {
+                !matches!(
+                    reservation.status,
+                    ReservationStatus::Cancelled | ReservationStatus::NoShow
+                )
+            })
+            .map(|(_, reservation)| reservation)
+            .collect()
+    });
+    _get_reservations().into_iter().chain(archived).collect()
+}
+
+#[ic_cdk::update]
+fn join_waitlist(car_id: u64, customer_id: u64) -> Result<WaitlistEntry, Error> {
+    _check_writable()?;
+    let car = _get_car(&car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", car_id),
+    })?;
+    let customer = _get_customer(&customer_id).ok_or(Error::NotFound {
+        msg: format!("a customer with id={} not found", customer_id),
+    })?;
+    _check_can_reserve_for(&customer)?;
+    if car.status != CarStatus::Booked {
+        return Err(Error::NotBooked {
+            msg: format!("a car with id={} is available, reserve it instead", car_id),
+        });
+    }
+    // Joining twice keeps the customer's original place in the queue
+    if let Some(entry) = WAITLIST_STORAGE.with(|service| service.borrow().get(&(car_id, customer_id))) {
+        return Ok(entry);
+    }
+    let entry = WaitlistEntry {
+        car_id,
+        customer_id,
+        joined_at: time(),
+    };
+    WAITLIST_STORAGE.with(|service| service.borrow_mut().insert((car_id, customer_id), entry.clone()));
+    _record_event(EventKind::WaitlistJoined, Some(car_id));
+    Ok(entry)
+}
+
+// The head of the list is the customer to offer the car to once it frees up
+#[ic_cdk::query]
+fn get_waitlist(car_id: u64) -> Vec<WaitlistEntry> {
+    let mut entries: Vec<WaitlistEntry> = WAITLIST_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    entries.sort_by_key(|entry| entry.joined_at);
+    entries
+}
+
+// Unlike the waitlist, watching a car doesn't queue for it; the customer just checks
+// watched_car_updates for when it is free
+#[ic_cdk::update]
+fn watch_car(car_id: u64, customer_id: u64) -> Result<Watch, Error> {
+    _check_writable()?;
+    if _get_car(&car_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a car with id={} not found", car_id),
+        });
+    }
+    let customer = _get_customer(&customer_id).ok_or(Error::NotFound {
+        msg: format!("a customer with id={} not found", customer_id),
+    })?;
+    _check_can_reserve_for(&customer)?;
+    if let Some(watch) = WATCHES.with(|service| service.borrow().get(&(customer_id, car_id))) {
+        return Ok(watch);
+    }
+    let watch = Watch {
+        customer_id,
+        car_id,
+        created_at: time(),
+    };
+    WATCHES.with(|service| service.borrow_mut().insert((customer_id, car_id), watch.clone()));
+    _record_event(EventKind::CarWatched, Some(car_id));
+    Ok(watch)
+}
+
+#[ic_cdk::update]
+fn unwatch_car(car_id: u64, customer_id: u64) -> Result<Watch, Error> {
+    _check_writable()?;
+    let customer = _get_customer(&customer_id).ok_or(Error::NotFound {
+        msg: format!("a customer with id={} not found", customer_id),
+    })?;
+    _check_can_reserve_for(&customer)?;
+    let watch = WATCHES
+        .with(|service| service.borrow_mut().remove(&(customer_id, car_id)))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "the customer with id={} isn't watching the car with id={}",
+                customer_id, car_id
+            ),
+        })?;
+    _record_event(EventKind::CarUnwatched, Some(car_id));
+    Ok(watch)
+}
+
+// Removes and returns all of a customer's watches, which are keyed customer first
+fn _take_watches(customer_id: u64) -> Vec<Watch> {
+    WATCHES.with(|service| {
+        let mut watches = service.borrow_mut();
+        let taken: Vec<Watch> = watches
+            .range((customer_id, 0)..=(customer_id, u64::MAX))
+            .map(|(_, watch)| watch)
+            .collect();
+        for watch in &taken {
+            watches.remove(&(customer_id, watch.car_id));
+        }
+        taken
+    })
+}
+
+// The watched cars that are available right now, for the customers the caller created
+#[ic_cdk::query]
+fn watched_car_updates() -> Vec<CarAvailabilityNotice> {
+    let caller = caller();
+    let watches: Vec<Watch> = WATCHES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, watch)| watch)
+            .collect()
+    });
+    watches
+        .into_iter()
+        .filter(|watch| {
+            _get_customer(&watch.customer_id).is_some_and(|customer| customer.created_by == Some(caller))
+        })
+        .filter_map(|watch| {
+            let car = _get_car(&watch.car_id).filter(|car| car.status == CarStatus::Available)?;
+            Some(CarAvailabilityNotice {
+                car_id: car.id,
+                customer_id: watch.customer_id,
+                make: car.make,
+                model: car.model,
+                watched_since: watch.created_at,
+            })
+        })
+        .collect()
+}
+
+#[ic_cdk::update]
+fn add_blackout(car_id: u64, start: Timestamp, end: Timestamp, reason: String) -> Result<Blackout, Error> {
+    _check_writable()?;
+    let car = _get_car(&car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", car_id),
+    })?;
+    _check_if_owner(&car)?;
+    // Seconds or milliseconds would put the blackout in 1970, where it blocks nothing
+    if start < MIN_PLAUSIBLE_TIMESTAMP {
+        return Err(_field_error(
+            "start",
+            "start must be in nanoseconds since the Unix epoch".to_string(),
+        ));
+    }
+    if end <= start {
+        return Err(_field_error("end", "end must be after start".to_string()));
+    }
+    let reason = reason.trim().to_string();
+    if reason.len() > MAX_REASON_LEN {
+        return Err(_field_error(
+            "reason",
+            format!("reason must be at most {} bytes long", MAX_REASON_LEN),
+        ));
+    }
+    let blackout = Blackout {
+        id: _next_id(&BLACKOUT_ID_COUNTER)?,
+        car_id,
+        start,
+        end,
+        reason,
+    };
+    BLACKOUTS.with(|service| service.borrow_mut().insert((car_id, blackout.id), blackout.clone()));
+    _record_event(EventKind::BlackoutAdded, Some(car_id));
+    Ok(blackout)
+}
+
+#[ic_cdk::update]
+fn remove_blackout(car_id: u64, blackout_id: u64) -> Result<Blackout, Error> {
+    _check_writable()?;
+    let car = _get_car(&car_id).ok_or(Error::NotFound {
+        msg: format!("a car with id={} not found", car_id),
+    })?;
+    _check_if_owner(&car)?;
+    match BLACKOUTS.with(|service| service.borrow_mut().remove(&(car_id, blackout_id))) {
+        Some(blackout) => {
+            _record_event(EventKind::BlackoutRemoved, Some(car_id));
+            Ok(blackout)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "a blackout with id={} not found for the car with id={}",
+                blackout_id, car_id
+            ),
+        }),
+    }
+}
+
+// Ordered by start
+#[ic_cdk::query]
+fn get_blackouts(car_id: u64) -> Vec<Blackout> {
+    let mut blackouts = _get_blackouts(car_id);
+    blackouts.sort_by_key(|blackout| blackout.start);
+    blackouts
+}
+
+fn _get_blackouts(car_id: u64) -> Vec<Blackout> {
+    BLACKOUTS.with(|service| {
+        service
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|(_, blackout)| blackout)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn generate_report() -> Vec<Car> {
+    _get_cars()
+}
+
+// Both bounds are inclusive
+#[ic_cdk::query]
+fn cars_created_between(start: Timestamp, end: Timestamp) -> Result<Vec<Car>, Error> {
+    if end < start {
+        return Err(_field_error("end", "end must not be before start".to_string()));
+    }
+    Ok(generate_report()
+        .into_iter()
+        .filter(|car| start <= Timestamp(car.created_at) && Timestamp(car.created_at) <= end)
+        .collect())
+}
+
+// Cars in rotation with no reservation overlapping [start_time, end_time)
+#[ic_cdk::query]
+fn available_cars(start_time: Timestamp, end_time: Timestamp) -> Result<Vec<Car>, Error> {
+    _validate_reservation_window(start_time, end_time)?;
+    let reservations = _get_reservations();
+    Ok(generate_report()
+        .into_iter()
+        .filter(|car| _check_in_rotation(car).is_ok())
+        .filter(|car| _check_grace_period(car, start_time).is_ok())
+        .filter(|car| _check_blackouts(car.id, start_time, end_time).is_ok())
+        .filter(|car| {
+            !reservations.iter().any(|reservation| {
+                reservation.car_id == car.id
+                    && reservation.start_time < end_time
+                    && start_time < reservation.end_time
+            })
+        })
+        .collect())
+}
+
+// Cars in rotation that no reservation holds at the moment
+#[ic_cdk::query]
+fn get_available_cars() -> Vec<Car> {
+    let now = Timestamp::now();
+    let reservations = _get_reservations();
+    generate_report()
+        .into_iter()
+        .filter(|car| _check_in_rotation(car).is_ok())
+        .filter(|car| {
+            !reservations
+                .iter()
+                .any(|reservation| reservation.car_id == car.id && _holds_car_now(reservation, now))
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn get_cars_sorted(sort_by: CarSortKey, descending: bool) -> Vec<Car> {
+    let mut cars = generate_report();
+    _sort_cars(&mut cars, sort_by, descending);
+    cars
+}
+
+#[ic_cdk::query]
+fn search_cars(filter: CarFilter) -> Vec<Car> {
+    _get_cars()
+        .into_iter()
+        .filter(|car| filter.matches(car))
+        .collect()
+}
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+// Cars with a location within radius_km of the point, nearest first. Distances are
+// great-circle (haversine) distances on a spherical Earth.
+#[ic_cdk::query]
+fn cars_near(lat: f64, lon: f64, radius_km: f64) -> Result<Vec<Car>, Error> {
+    if validate_latitude(lat).is_err() {
+        return Err(_field_error("lat", "lat must be between -90 and 90 degrees".to_string()));
+    }
+    if validate_longitude(lon).is_err() {
+        return Err(_field_error("lon", "lon must be between -180 and 180 degrees".to_string()));
+    }
+    if radius_km.is_nan() || radius_km < 0.0 {
+        return Err(_field_error("radius_km", "radius_km must not be negative".to_string()));
+    }
+    let mut nearby: Vec<(f64, Car)> = _get_cars()
+        .into_iter()
+        .filter_map(|car| match (car.latitude, car.longitude) {
+            (Some(latitude), Some(longitude)) => {
+                Some((_haversine_km(lat, lon, latitude, longitude), car))
+            }
+            _ => None,
+        })
+        .filter(|(distance, _)| *distance <= radius_km)
+        .collect();
+    nearby.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    Ok(nearby.into_iter().map(|(_, car)| car).collect())
+}
+
+fn _haversine_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CarPage {
+    items: Vec<Car>,
+    // Matches across all pages, for page counts
+    total_matches: u64,
+}
+
+// Filters, then sorts, then pages; limit is capped at MAX_PAGE_SIZE
+#[ic_cdk::query]
+fn query_cars(
+    filter: CarFilter,
+    sort_by: CarSortKey,
+    descending: bool,
+    offset: u64,
+    limit: u64,
+) -> CarPage {
+    let mut cars = search_cars(filter);
+    _sort_cars(&mut cars, sort_by, descending);
+    let total_matches = cars.len() as u64;
+    CarPage {
+        items: cars
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .collect(),
+        total_matches,
+    }
+}
+
+// The fields a catalog listing shows, without the owner, timestamps and counters
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CarSummary {
+    id: u64,
+    make: String,
+    model: String,
+    year: u32,
+    color: String,
+    status: CarStatus,
+    daily_price: u64,
+}
+
+impl From<Car> for CarSummary {
+    fn from(car: Car) -> Self {
+        CarSummary {
+            id: car.id,
+            make: car.make,
+            model: car.model,
+            year: car.year,
+            color: car.color,
+            status: car.status,
+            daily_price: car.daily_price,
+        }
+    }
+}
+
+// In the order requested, skipping ids with no car; at most MAX_PAGE_SIZE ids are
+// looked up, the rest are ignored
+#[ic_cdk::query]
+fn get_cars_by_ids(ids: Vec<u64>) -> Vec<Car> {
+    ids.iter()
+        .take(MAX_PAGE_SIZE as usize)
+        .filter_map(_get_car)
+        .collect()
+}
+
+// Cars in id order; limit is capped at MAX_PAGE_SIZE
+#[ic_cdk::query]
+fn list_car_summaries(offset: u64, limit: u64) -> Vec<CarSummary> {
+    _get_cars()
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit.min(MAX_PAGE_SIZE) as usize)
+        .map(CarSummary::from)
+        .collect()
+}
+
+#[ic_cdk::query]
+fn get_cars_by_status(status: CarStatus) -> Vec<Car> {
+    _get_cars()
+        .into_iter()
+        .filter(|car| car.status == status)
+        .collect()
+}
+
+// Idle inventory. Cars booked before total_bookings existed still count as
+// reserved if they appear in the reservation storage.
+#[ic_cdk::query]
+fn never_reserved_cars() -> Vec<Car> {
+    let mut reserved: std::collections::BTreeSet<u64> = _get_reservations()
+        .iter()
+        .map(|reservation| reservation.car_id)
+        .collect();
+    RESERVATION_ARCHIVE.with(|service| {
+        reserved.extend(service.borrow().iter().map(|(_, reservation)| reservation.car_id))
+    });
+    generate_report()
+        .into_iter()
+        .filter(|car| car.total_bookings == 0 && !reserved.contains(&car.id))
+        .collect()
+}
+
+// (car_id, count) of the active reservations on each car, past and future, busiest
+// first; ties are broken by car id. Cars without reservations are left out.
+#[ic_cdk::query]
+fn reservation_counts() -> Vec<(u64, u64)> {
+    let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+    for reservation in _get_reservations() {
+        *counts.entry(reservation.car_id).or_insert(0) += 1;
+    }
+    let mut counts: Vec<(u64, u64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
+
+// (make, average daily price) per make, makes compared ignoring case and reported
+// lowercase, alphabetically. Averages round down; unpriced cars are left out.
+#[ic_cdk::query]
+fn avg_price_by_make() -> Vec<(String, u64)> {
+    let mut totals: BTreeMap<String, (u128, u64)> = BTreeMap::new();
+    for car in _get_cars().into_iter().filter(|car| car.daily_price > 0) {
+        let (sum, count) = totals.entry(car.make.to_lowercase()).or_insert((0, 0));
+        *sum += car.daily_price as u128;
+        *count += 1;
+    }
+    totals
+        .into_iter()
+        .map(|(make, (sum, count))| (make, (sum / count as u128) as u64))
+        .collect()
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MakeModels {
+    make: String,
+    models: Vec<String>,
+}
+
+// Distinct makes and, per make, distinct models, for filter dropdowns. Names are
+// compared ignoring case and shown as spelled on the lowest-id car.
+#[ic_cdk::query]
+fn fleet_taxonomy() -> Vec<MakeModels> {
+    let mut makes: BTreeMap<String, (String, BTreeMap<String, String>)> = BTreeMap::new();
+    for car in _get_cars() {
+        let (_, models) = makes
+            .entry(car.make.to_lowercase())
+            .or_insert_with(|| (car.make.clone(), BTreeMap::new()));
+        models
+            .entry(car.model.to_lowercase())
+            .or_insert_with(|| car.model.clone());
+    }
+    makes
+        .into_values()
+        .map(|(make, models)| MakeModels {
+            make,
+            models: models.into_values().collect(),
+        })
+        .collect()
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct HealthStatus {
+    cycles_balance: u64,
+    low_balance_threshold: u64,
+    low_balance: bool,
+}
+
+#[ic_cdk::query]
+fn cycles_balance() -> u64 {
+    canister_balance()
+}
+
+#[ic_cdk::query]
+fn health() -> HealthStatus {
+    let cycles_balance = canister_balance();
+    let low_balance_threshold = LOW_BALANCE_THRESHOLD.with(|threshold| *threshold.borrow().get());
+    HealthStatus {
+        cycles_balance,
+        low_balance_threshold,
+        low_balance: cycles_balance < low_balance_threshold,
+    }
+}
+
+#[ic_cdk::update]
+fn set_low_balance_threshold(threshold: u64) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    LOW_BALANCE_THRESHOLD
+        .with(|cell| cell.borrow_mut().set(threshold))
+        .map_err(|error| _internal_error("cannot set the low balance threshold", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_color_policy() -> ColorPolicy {
+    COLOR_POLICY.with(|policy| policy.borrow().get().clone())
+}
+
+#[ic_cdk::update]
+fn set_color_policy(strict: bool, allowed_colors: Vec<String>) -> Result<ColorPolicy, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    let mut allowed_colors: Vec<String> = allowed_colors
+        .iter()
+        .map(|color| color.trim().to_lowercase())
+        .filter(|color| !color.is_empty())
+        .collect();
+    allowed_colors.sort();
+    allowed_colors.dedup();
+    let policy = ColorPolicy {
+        strict,
+        allowed_colors,
+    };
+    COLOR_POLICY
+        .with(|cell| cell.borrow_mut().set(policy.clone()))
+        .map_err(|error| _internal_error("cannot set the color policy", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_currency_settings() -> CurrencySettings {
+    CURRENCY_SETTINGS.with(|settings| settings.borrow().get().clone())
+}
+
+#[ic_cdk::update]
+fn set_currency_settings(
+    default_currency: String,
+    allow_other_currencies: bool,
+) -> Result<CurrencySettings, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    if validate_currency_code(&default_currency).is_err() {
+        return Err(_field_error(
+            "default_currency",
+            "must be a three-letter currency code".to_string(),
+        ));
+    }
+    let settings = CurrencySettings {
+        default_currency: default_currency.to_uppercase(),
+        allow_other_currencies,
+    };
+    CURRENCY_SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings.clone()))
+        .map_err(|error| _internal_error("cannot set the currency settings", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(settings)
+}
+
+#[ic_cdk::query]
+fn get_coupons() -> Result<Vec<Coupon>, Error> {
+    _check_if_admin()?;
+    Ok(COUPONS.with(|service| service.borrow().iter().map(|(_, coupon)| coupon).collect()))
+}
+
+// Creating a coupon with an existing code replaces it and resets its uses
+#[ic_cdk::update]
+fn create_coupon(
+    code: String,
+    percent_off: u8,
+    valid_until: u64,
+    max_uses: u32,
+) -> Result<Coupon, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    let code = code.trim().to_uppercase();
+    if code.is_empty()
+        || code.len() > MAX_COUPON_CODE_LEN
+        || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(_field_error(
+            "code",
+            format!(
+                "code must be 1 to {} letters, digits, dashes or underscores",
+                MAX_COUPON_CODE_LEN
+            ),
+        ));
+    }
+    if percent_off == 0 || percent_off > 100 {
+        return Err(_field_error(
+            "percent_off",
+            "percent_off must be between 1 and 100".to_string(),
+        ));
+    }
+    if max_uses == 0 {
+        return Err(_field_error(
+            "max_uses",
+            "max_uses must be at least 1".to_string(),
+        ));
+    }
+    let coupon = Coupon {
+        code: code.clone(),
+        percent_off,
+        valid_until,
+        max_uses,
+        uses: 0,
+    };
+    COUPONS.with(|service| service.borrow_mut().insert(CouponCode(code), coupon.clone()));
+    _record_event(EventKind::CouponCreated, None);
+    Ok(coupon)
+}
+
+// Expired coupons are kept so reservations booked with them keep their discount
+#[ic_cdk::update]
+fn expire_coupon(code: String) -> Result<Coupon, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    let key = CouponCode(code.trim().to_uppercase());
+    let mut coupon = COUPONS
+        .with(|service| service.borrow().get(&key))
+        .ok_or(Error::NotFound {
+            msg: format!("a coupon with code={} not found", key.0),
+        })?;
+    coupon.valid_until = coupon.valid_until.min(time().saturating_sub(1));
+    COUPONS.with(|service| service.borrow_mut().insert(key, coupon.clone()));
+    _record_event(EventKind::CouponExpired, None);
+    Ok(coupon)
+}
+
+#[ic_cdk::query]
+fn get_ledger_canister() -> Principal {
+    LEDGER_CANISTER.with(|cell| cell.borrow().get().0)
+}
+
+#[ic_cdk::update]
+fn set_ledger_canister(ledger: Principal) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    LEDGER_CANISTER
+        .with(|cell| cell.borrow_mut().set(PrincipalKey(ledger)))
+        .map_err(|error| _internal_error("cannot set the ledger canister", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_car_rate_limit() -> RateLimit {
+    CAR_RATE_LIMIT.with(|cell| *cell.borrow().get())
+}
+
+#[ic_cdk::update]
+fn set_car_rate_limit(max_calls: u32, window_ns: u64) -> Result<RateLimit, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    if max_calls == 0 {
+        return Err(_field_error(
+            "max_calls",
+            "max_calls must be at least 1".to_string(),
+        ));
+    }
+    let limit = RateLimit {
+        max_calls,
+        window_ns,
+    };
+    CAR_RATE_LIMIT
+        .with(|cell| cell.borrow_mut().set(limit))
+        .map_err(|error| _internal_error("cannot set the car rate limit", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(limit)
+}
+
+#[ic_cdk::query]
+fn get_rental_policy() -> RentalPolicy {
+    RENTAL_POLICY.with(|cell| *cell.borrow().get())
+}
+
+#[ic_cdk::update]
+fn set_rental_policy(min_rental_ns: u64, max_rental_ns: u64) -> Result<RentalPolicy, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    if max_rental_ns < min_rental_ns {
+        return Err(_field_error(
+            "max_rental_ns",
+            "max_rental_ns must not be less than min_rental_ns".to_string(),
+        ));
+    }
+    let policy = RentalPolicy {
+        min_rental_ns,
+        max_rental_ns,
+    };
+    RENTAL_POLICY
+        .with(|cell| cell.borrow_mut().set(policy))
+        .map_err(|error| _internal_error("cannot set the rental policy", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_default_rental() -> u64 {
+    DEFAULT_RENTAL.with(|cell| *cell.borrow().get())
+}
+
+#[ic_cdk::update]
+fn set_default_rental(default_rental_ns: u64) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    if default_rental_ns == 0 {
+        return Err(_field_error(
+            "default_rental_ns",
+            "default_rental_ns must be greater than 0".to_string(),
+        ));
+    }
+    DEFAULT_RENTAL
+        .with(|cell| cell.borrow_mut().set(default_rental_ns))
+        .map_err(|error| _internal_error("cannot set the default rental length", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_min_lead_time() -> u64 {
+    MIN_LEAD_TIME.with(|cell| *cell.borrow().get())
+}
+
+#[ic_cdk::update]
+fn set_min_lead_time(min_lead_time_ns: u64) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    MIN_LEAD_TIME
+        .with(|cell| cell.borrow_mut().set(min_lead_time_ns))
+        .map_err(|error| _internal_error("cannot set the minimum lead time", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_cancellation_policy() -> CancellationPolicy {
+    CANCELLATION_POLICY.with(|cell| *cell.borrow().get())
+}
+
+#[ic_cdk::update]
+fn set_cancellation_policy(
+    free_cancellation_ns: u64,
+    late_cancellation_fee: u64,
+    no_show_fee: u64,
+) -> Result<CancellationPolicy, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    let policy = CancellationPolicy {
+        free_cancellation_ns,
+        late_cancellation_fee,
+        no_show_fee,
+    };
+    CANCELLATION_POLICY
+        .with(|cell| cell.borrow_mut().set(policy))
+        .map_err(|error| _internal_error("cannot set the cancellation policy", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_late_fee_per_day() -> u64 {
+    LATE_FEE_PER_DAY.with(|cell| *cell.borrow().get())
+}
+
+#[ic_cdk::update]
+fn set_late_fee_per_day(late_fee_per_day: u64) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    LATE_FEE_PER_DAY
+        .with(|cell| cell.borrow_mut().set(late_fee_per_day))
+        .map_err(|error| _internal_error("cannot set the late fee", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_grace_period() -> u64 {
+    GRACE_PERIOD.with(|cell| *cell.borrow().get())
+}
+
+#[ic_cdk::update]
+fn set_grace_period(grace_period_ns: u64) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    GRACE_PERIOD
+        .with(|cell| cell.borrow_mut().set(grace_period_ns))
+        .map_err(|error| _internal_error("cannot set the grace period", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_loyalty_thresholds() -> LoyaltyThresholds {
+    LOYALTY_THRESHOLDS.with(|cell| *cell.borrow().get())
+}
+
+#[ic_cdk::update]
+fn set_loyalty_thresholds(silver: u64, gold: u64) -> Result<LoyaltyThresholds, Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    if gold < silver {
+        return Err(_field_error(
+            "gold",
+            "gold must not be less than silver".to_string(),
+        ));
+    }
+    let thresholds = LoyaltyThresholds { silver, gold };
+    LOYALTY_THRESHOLDS
+        .with(|cell| cell.borrow_mut().set(thresholds))
+        .map_err(|error| _internal_error("cannot set the loyalty thresholds", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(thresholds)
+}
+
+// Whether the caller may operate reservations for others: staff members and admins
+#[ic_cdk::query]
+fn is_staff() -> bool {
+    _is_staff(&caller())
+}
+
+#[ic_cdk::update]
+fn add_staff(principal: Principal) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    STAFF.with(|service| service.borrow_mut().insert(PrincipalKey(principal), ()));
+    _record_event(EventKind::StaffAdded, None);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_staff(principal: Principal) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    match STAFF.with(|service| service.borrow_mut().remove(&PrincipalKey(principal))) {
+        Some(_) => {
+            _record_event(EventKind::StaffRemoved, None);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("a staff member with principal={} not found", principal),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn is_read_only() -> bool {
+    READ_ONLY.with(|cell| cell.borrow().get().0)
+}
+
+// The one update that works in read-only mode, so that it can be turned off
+#[ic_cdk::update]
+fn set_read_only(read_only: bool) -> Result<(), Error> {
+    _check_if_admin()?;
+    READ_ONLY
+        .with(|cell| cell.borrow_mut().set(Flag(read_only)))
+        .map_err(|error| _internal_error("cannot set the read-only flag", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_listing_access() -> Result<ListingAccess, Error> {
+    _check_if_admin()?;
+    let principals = |set: &StableBTreeMap<PrincipalKey, (), Memory>| -> Vec<Principal> {
+        set.iter().map(|(key, _)| key.0).collect()
+    };
+    Ok(ListingAccess {
+        mode: LISTING_MODE.with(|cell| *cell.borrow().get()),
+        allowed_listers: ALLOWED_LISTERS.with(|service| principals(&service.borrow())),
+        blocked_principals: BLOCKED_PRINCIPALS.with(|service| principals(&service.borrow())),
+    })
+}
+
+#[ic_cdk::update]
+fn set_listing_mode(mode: ListingMode) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    LISTING_MODE
+        .with(|cell| cell.borrow_mut().set(mode))
+        .map_err(|error| _internal_error("cannot set the listing mode", error))?;
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn add_allowed_lister(principal: Principal) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    ALLOWED_LISTERS.with(|service| service.borrow_mut().insert(PrincipalKey(principal), ()));
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_allowed_lister(principal: Principal) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    match ALLOWED_LISTERS.with(|service| service.borrow_mut().remove(&PrincipalKey(principal))) {
+        Some(_) => {
+            _record_event(EventKind::SettingsChanged, None);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("an allowed lister with principal={} not found", principal),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn block_principal(principal: Principal) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    BLOCKED_PRINCIPALS.with(|service| service.borrow_mut().insert(PrincipalKey(principal), ()));
+    _record_event(EventKind::SettingsChanged, None);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unblock_principal(principal: Principal) -> Result<(), Error> {
+    _check_writable()?;
+    _check_if_admin()?;
+    match BLOCKED_PRINCIPALS.with(|service| service.borrow_mut().remove(&PrincipalKey(principal))) {
+        Some(_) => {
+            _record_event(EventKind::SettingsChanged, None);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("a blocked principal={} not found", principal),
+        }),
+    }
+}
+
+// Events in the order they happened, oldest first
+#[ic_cdk::query]
+fn get_events(offset: u64, limit: u64) -> Result<Vec<Event>, Error> {
+    _check_if_admin()?;
+    Ok(EVENT_LOG.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .map(|(_, event)| event)
+            .collect()
+    }))
+}
+
+// Appends to the audit log, evicting the oldest events beyond MAX_EVENTS
+fn _record_event(kind: EventKind, target_id: Option<u64>) {
+    let id = _next_id(&EVENT_ID_COUNTER).expect("event ids exhausted");
+    let event = Event {
+        id,
+        kind,
+        actor: caller(),
+        target_id,
+        timestamp: time(),
+    };
+    EVENT_LOG.with(|service| {
+        let mut log = service.borrow_mut();
+        log.insert(id, event);
+        while log.len() > MAX_EVENTS {
+            match log.first_key_value() {
+                Some((oldest, _)) => log.remove(&oldest),
+                None => break,
+            };
+        }
+    });
+}
+
+// Guards every update, so that maintenance such as a migration sees no writes
+fn _check_writable() -> Result<(), Error> {
+    if READ_ONLY.with(|cell| cell.borrow().get().0) {
+        return Err(Error::ReadOnlyMode {
+            msg: "the canister is read-only for maintenance, try again later".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Admins can do anything staff can
+fn _is_staff(principal: &Principal) -> bool {
+    is_controller(principal) || STAFF.with(|service| service.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+// Controllers of the canister act as its admins
+fn _check_if_admin() -> Result<(), Error> {
+    if !is_controller(&caller()) {
+        return Err(Error::NotAuthorized {
+            msg: "caller isn't an admin".to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
+enum Error {
+    NotFound { msg: String },
+    HasActiveReservation { msg: String },
+    NotAuthorized { msg: String },
+    NotBooked { msg: String },
+    CarUnavailable { msg: String },
+    InvalidCoupon { msg: String },
+    PaymentFailed { msg: String },
+    InsufficientPayment { required: u64, received: u64 },
+    // retry_after is the time at which the caller may try again
+    RateLimited { retry_after: u64 },
+    InvalidDuration { msg: String },
+    InsufficientLeadTime { msg: String },
+    ReadOnlyMode { msg: String },
+    // The operation doesn't apply to the record in its current state
+    InvalidState { msg: String },
+    // A broken internal invariant, such as a store refusing a write; a bug, not bad input
+    Internal { msg: String },
+    IdSpaceExhausted,
+    CorruptRecord { id: u64 },
+    RecordTooLarge { size: u32, max: u32 },
+    // The car was written since the caller read it; re-read and retry
+    VersionConflict { current_version: u64 },
+    ReservationConflict {
+        car_id: u64,
+        conflicting_reservation_id: u64,
+        available_after: u64,
+    },
+    // The conflicting reservation is the same customer's; extend it instead
+    SameCustomerOverlap {
+        car_id: u64,
+        conflicting_reservation_id: u64,
+    },
+    ValidationErrors { errors: Vec<FieldError> },
+    // The request at `index` of a batch failed with `error`; nothing in the batch was made
+    BatchRejected { index: u64, error: Box<Error> },
+}
+
+// Corrupt cars are treated as missing; see _try_get_car to tell them apart
+fn _get_car(id: &u64) -> Option<Car> {
+    _try_get_car(id).ok().flatten()
+}
+
+fn _try_get_car(id: &u64) -> Result<Option<Car>, Error> {
+    CAR_STORAGE
+        .with(|service| service.borrow().get(id))
+        .map(|record| record.decode(*id))
+        .transpose()
+}
+
+// Every decodable car in id order; corrupt records are logged and skipped
+fn _get_cars() -> Vec<Car> {
+    CAR_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter_map(|(id, record)| record.decode(id).ok())
+            .collect()
+    })
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    _migrate_legacy_cars(MemoryId::new(1), &CAR_STORAGE);
+    _migrate_legacy_cars(MemoryId::new(12), &DELETED_CAR_STORAGE);
+    _migrate_legacy_customers(time());
+    _seed_reservation_counter();
+    _migrate_legacy_reservations();
+}
+
+// Hands out the counter's current value and advances it, refusing to wrap around
+// and reuse ids once the id space is used up
+fn _next_id(counter: &'static std::thread::LocalKey<RefCell<IdCell>>) -> Result<u64, Error> {
+    counter.with(|counter| {
+        let current_value = *counter.borrow().get();
+        let next_value = current_value
+            .checked_add(1)
+            .ok_or(Error::IdSpaceExhausted)?;
+        counter
+            .borrow_mut()
+            .set(next_value)
+            .map_err(|error| _internal_error("cannot increment id counter", error))?;
+        Ok(current_value)
+    })
+}
+
+fn _internal_error(context: &str, error: impl std::fmt::Debug) -> Error {
+    print(format!("internal error: {}: {:?}", context, error));
+    Error::Internal {
+        msg: format!("{}: {:?}", context, error),
+    }
+}
+
+// On the first upgrade with a dedicated counter, starts it after every id the shared
+// counter has handed out, so reservations created before the split keep unique ids
+fn _seed_reservation_counter() {
+    let shared = ID_COUNTER.with(|counter| *counter.borrow().get());
+    ID_RESERVATION_COUNTER.with(|counter| {
+        if *counter.borrow().get() == 0 {
+            counter
+                .borrow_mut()
+                .set(shared)
+                .expect("cannot seed the reservation id counter");
+        }
+    });
+}
+
+// Moves cars stored under the old 1024-byte bound into the map that replaced it
+fn _migrate_legacy_cars(
+    legacy_id: MemoryId,
+    target: &'static std::thread::LocalKey<RefCell<StableBTreeMap<u64, CarRecord, Memory>>>,
+) {
+    let legacy_memory = MEMORY_MANAGER.with(|m| m.borrow().get(legacy_id));
+    if legacy_memory.size() == 0 {
+        return;
+    }
+    let mut legacy = StableBTreeMap::<u64, LegacyCar, Memory>::init(legacy_memory);
+    let ids: Vec<u64> = legacy.iter().map(|(id, _)| id).collect();
+    for id in ids {
+        if let Some(LegacyCar(car)) = legacy.remove(&id) {
+            target.with(|service| service.borrow_mut().insert(id, CarRecord::new(&car)));
+        }
+    }
+}
+
+// Stamps customers stored before created_at existed with the time of the upgrade, a
+// time by which they certainly existed
+fn _migrate_legacy_customers(now: u64) {
+    let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+    let mut storage = StableBTreeMap::<u64, LegacyCustomer, Memory>::init(customer_storage);
+    let legacy_ids: Vec<u64> = storage
+        .iter()
+        .filter(|(_, LegacyCustomer(stored))| stored.created_at.is_none())
+        .map(|(id, _)| id)
+        .collect();
+    for id in legacy_ids {
+        if let Some(LegacyCustomer(mut stored)) = storage.get(&id) {
+            stored.created_at = Some(now);
+            storage.insert(id, LegacyCustomer(stored));
+        }
+    }
+}
+
+// Rekeys reservations stored by car id, from before reservations had ids, under fresh
+// ids from the reservation counter. They are all taken out before any is put back, and
+// the seeded counter starts after every car id, so no new key collides with an old one.
+fn _migrate_legacy_reservations() {
+    let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
+    let mut storage =
+        StableBTreeMap::<u64, LegacyReservation, Memory>::init(reservation_storage);
+    let legacy_keys: Vec<u64> = storage
+        .iter()
+        .filter(|(_, LegacyReservation(stored))| stored.id.is_none())
+        .map(|(key, _)| key)
+        .collect();
+    let legacy: Vec<StoredReservation> = legacy_keys
+        .into_iter()
+        .filter_map(|key| storage.remove(&key))
+        .map(|LegacyReservation(stored)| stored)
+        .collect();
+    drop(storage);
+    for stored in legacy {
+        let id = _next_id(&ID_RESERVATION_COUNTER).expect("reservation ids exhausted");
+        let reservation = Reservation {
+            id,
+            ..stored.into()
+        };
+        do_insert_reservation(&reservation);
+    }
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    // Stand-ins for the system API, which traps outside a canister. Every test runs on
+    // its own thread, so each starts from empty storage and the default clock.
+    pub(crate) mod env {
+        use candid::Principal;
+        use std::cell::Cell;
+
+        thread_local! {
+            static TIME: Cell<u64> = const { Cell::new(super::NOW) };
+            static CALLER: Cell<Principal> = Cell::new(super::user(1));
+            static BALANCE: Cell<u64> = const { Cell::new(5_000_000_000_000) };
+        }
+
+        pub(crate) fn time() -> u64 {
+            TIME.with(Cell::get)
+        }
+
+        pub(crate) fn set_time(time: u64) {
+            TIME.with(|cell| cell.set(time))
+        }
+
+        pub(crate) fn caller() -> Principal {
+            CALLER.with(Cell::get)
+        }
+
+        pub(crate) fn set_caller(caller: Principal) {
+            CALLER.with(|cell| cell.set(caller))
+        }
+
+        pub(crate) fn is_controller(principal: &Principal) -> bool {
+            *principal == super::admin()
+        }
+
+        pub(crate) fn canister_balance() -> u64 {
+            BALANCE.with(Cell::get)
+        }
+
+        pub(crate) fn set_balance(balance: u64) {
+            BALANCE.with(|cell| cell.set(balance))
+        }
+
+        pub(crate) fn print<S: AsRef<str>>(_: S) {}
+    }
+
+    use env::{set_balance, set_caller, set_time};
+
+    fn user(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    // The canister's only controller
+    fn admin() -> Principal {
+        user(0)
+    }
+
+    fn customer(name: &str) -> Customer {
+        add_customer(name.to_string(), Contact::Email(format!("{}@example.com", name.replace(' ', ".")))).unwrap()
+    }
+
+    fn payload() -> CarPayload {
+        CarPayload {
+            make: "Toyota".to_string(),
+            model: "Corolla".to_string(),
+            year: 2020,
+            color: "red".to_string(),
+            owner: caller().to_string(),
+            ..Default::default()
+        }
+    }
+
+    // A car owned by the current caller
+    fn car() -> Car {
+        add_car(payload()).unwrap()
+    }
+
+    // Where the test clock starts
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn day(n: u64) -> Timestamp {
+        Timestamp(NOW + n * NANOS_PER_DAY)
+    }
+
+    // Reserves the car from the start of one day after NOW to the start of another
+    fn reserve(car: &Car, customer: &Customer, from: u64, to: u64) -> Result<Reservation, Error> {
+        make_reservation(car.id, customer.id, day(from), Some(day(to)), None, None)
+    }
+
+    #[test]
+    fn customers_holding_reservations_cannot_be_deleted() {
+        let customer = customer("ada");
+        let car = car();
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        let Err(Error::HasActiveReservation { msg }) = delete_customer(customer.id) else {
+            panic!("a customer holding a reservation was deleted");
+        };
+        assert!(msg.contains(&format!("ids=[{}]", reservation.id)));
+        assert!(get_customer(customer.id).is_ok());
+    }
+
+    #[test]
+    fn customers_can_be_deleted_once_reservations_are_cancelled() {
+        let customer = customer("ada");
+        let car = car();
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        cancel_reservation(reservation.id).unwrap();
+        assert_eq!(delete_customer(customer.id).unwrap().id, customer.id);
+        assert!(get_customer(customer.id).is_err());
+    }
+
+    #[test]
+    fn patching_the_color_leaves_other_fields_alone() {
+        let car = car();
+        let patch = CarPatch {
+            color: Some("blue".to_string()),
+            ..Default::default()
+        };
+        let patched = patch_car(car.id, patch, None).unwrap();
+        assert_eq!(patched.color, "blue");
+        assert_eq!(
+            (patched.make, patched.model, patched.year),
+            (car.make, car.model, car.year)
+        );
+    }
+
+    #[test]
+    fn patching_an_invalid_year_is_rejected() {
+        let car = car();
+        let patch = CarPatch {
+            year: Some(1700),
+            ..Default::default()
+        };
+        assert!(matches!(patch_car(car.id, patch, None), Err(Error::ValidationErrors { .. })));
+        assert_eq!(get_car(car.id).unwrap().year, 2020);
+    }
+
+    #[test]
+    fn only_the_owner_may_update_or_patch_a_car() {
+        let car = car();
+        set_caller(user(2));
+        let mut takeover = payload();
+        takeover.color = "black".to_string();
+        assert!(matches!(update_car(car.id, takeover, None), Err(Error::NotAuthorized { .. })));
+        let patch = CarPatch {
+            color: Some("black".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(patch_car(car.id, patch, None), Err(Error::NotAuthorized { .. })));
+        assert_eq!(get_car(car.id).unwrap().owner, user(1).to_string());
+
+        set_caller(user(1));
+        let mut repaint = payload();
+        repaint.color = "black".to_string();
+        assert_eq!(update_car(car.id, repaint, None).unwrap().color, "black");
+    }
+
+    #[test]
+    fn health_flags_a_balance_below_the_threshold() {
+        set_balance(2_000_000_000_000);
+        assert!(!health().low_balance);
+        set_caller(admin());
+        set_low_balance_threshold(3_000_000_000_000).unwrap();
+        let health = health();
+        assert!(health.low_balance);
+        assert_eq!(health.cycles_balance, 2_000_000_000_000);
+        assert_eq!(cycles_balance(), 2_000_000_000_000);
+    }
+
+    #[test]
+    fn only_admins_set_the_low_balance_threshold() {
+        assert!(matches!(set_low_balance_threshold(1), Err(Error::NotAuthorized { .. })));
+        assert_eq!(health().low_balance_threshold, DEFAULT_LOW_BALANCE_THRESHOLD);
+    }
+
+    #[test]
+    fn search_by_fuel_type_returns_only_matching_cars() {
+        car();
+        let electric = add_car(CarPayload {
+            model: "bZ4X".to_string(),
+            fuel_type: FuelType::Electric,
+            ..payload()
+        })
+        .unwrap();
+        let found = search_cars(CarFilter {
+            fuel_type: Some(FuelType::Electric),
+            ..Default::default()
+        });
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, electric.id);
+    }
+
+    // A car as the first version of the canister stored it
+    #[derive(candid::CandidType)]
+    struct BaselineCar {
+        id: u64,
+        make: String,
+        model: String,
+        year: u32,
+        color: String,
+        created_at: u64,
+        updated_at: Option<u64>,
+        owner: String,
+        is_booked: bool,
+    }
+
+    #[test]
+    fn baseline_car_decodes_with_defaults() {
+        let baseline = BaselineCar {
+            id: 2,
+            make: "Toyota".to_string(),
+            model: "Corolla".to_string(),
+            year: 2020,
+            color: "red".to_string(),
+            created_at: 1,
+            updated_at: None,
+            owner: "Bob".to_string(),
+            is_booked: true,
+        };
+        let car = Car::from_bytes(Cow::Owned(Encode!(&baseline).unwrap()));
+        assert_eq!(car.id, 2);
+        assert_eq!(car.make, "Toyota");
+        assert!(car.status == CarStatus::Booked);
+        assert!(car.fuel_type == FuelType::Petrol);
+        assert!(car.transmission == Transmission::Manual);
+    }
+
+    #[test]
+    fn validation_errors_name_each_failing_field() {
+        let result = add_car(CarPayload {
+            make: "T".to_string(),
+            year: 1700,
+            ..payload()
+        });
+        let Err(Error::ValidationErrors { errors }) = result else {
+            panic!("expected validation errors");
+        };
+        let fields: Vec<&str> = errors.iter().map(|error| error.field.as_str()).collect();
+        assert_eq!(fields, ["make", "year"]);
+        assert!(errors.iter().all(|error| !error.message.is_empty()));
+    }
+
+    fn booked_car() -> Car {
+        add_car(CarPayload {
+            status: CarStatus::Booked,
+            ..payload()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn waitlist_is_ordered_by_joining_time() {
+        let car = booked_car();
+        let (first, second) = (customer("Ada"), customer("Grace"));
+        set_time(time() + 10);
+        join_waitlist(car.id, second.id).unwrap();
+        set_time(time() - 5);
+        join_waitlist(car.id, first.id).unwrap();
+        // Joining again keeps the original place
+        set_time(time() + 100);
+        join_waitlist(car.id, second.id).unwrap();
+        let waitlist: Vec<u64> = get_waitlist(car.id).iter().map(|entry| entry.customer_id).collect();
+        assert_eq!(waitlist, [first.id, second.id]);
+    }
+
+    #[test]
+    fn available_cars_have_no_waitlist() {
+        let car = car();
+        let customer = customer("Ada");
+        assert!(matches!(join_waitlist(car.id, customer.id), Err(Error::NotBooked { .. })));
+        assert!(get_waitlist(car.id).is_empty());
+    }
+
+    #[test]
+    fn reservations_can_be_extended_into_free_time() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        let extended = modify_reservation(reservation.id, day(1), day(4)).unwrap();
+        assert_eq!(extended.end_time, day(4));
+        assert_eq!(get_reservation(reservation.id).unwrap().end_time, day(4));
+    }
+
+    #[test]
+    fn extensions_may_not_collide_with_other_reservations() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        reserve(&car, &self::customer("bob"), 3, 4).unwrap();
+        assert!(matches!(
+            modify_reservation(reservation.id, day(1), day(4)),
+            Err(Error::ReservationConflict { .. })
+        ));
+        assert_eq!(get_reservation(reservation.id).unwrap().end_time, day(2));
+        // Back to back with the other reservation is fine
+        assert!(modify_reservation(reservation.id, day(1), day(3)).is_ok());
+    }
+
+    #[test]
+    fn modifying_ignores_the_reservations_own_window() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 1, 3).unwrap();
+        assert!(modify_reservation(reservation.id, day(2), day(4)).is_ok());
+    }
+
+    #[test]
+    fn only_the_booker_may_modify_a_reservation() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        set_caller(user(2));
+        assert!(matches!(
+            modify_reservation(reservation.id, day(1), day(3)),
+            Err(Error::NotAuthorized { .. })
+        ));
+        assert_eq!(get_reservation(reservation.id).unwrap().end_time, day(2));
+    }
+
+    #[test]
+    fn modifying_a_reservation_of_a_deleted_car_fails() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        // Only records stored before deletes checked for reservations can be left like this
+        CAR_STORAGE.with(|service| service.borrow_mut().remove(&car.id));
+        assert!(matches!(
+            modify_reservation(reservation.id, day(1), day(3)),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn windows_must_end_after_they_start() {
+        let (car, customer) = (car(), customer("ada"));
+        assert!(matches!(reserve(&car, &customer, 2, 2), Err(Error::ValidationErrors { .. })));
+    }
+
+    // A reservation as the first version of the canister stored it, keyed by car id
+    #[derive(candid::CandidType)]
+    struct BaselineReservation {
+        car_id: u64,
+        customer_id: u64,
+        reservation_time: u64,
+    }
+
+    impl Storable for BaselineReservation {
+        fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+            Cow::Owned(Encode!(self).unwrap())
+        }
+
+        fn from_bytes(_: std::borrow::Cow<[u8]>) -> Self {
+            unimplemented!("only written by tests")
+        }
+    }
+
+    impl BoundedStorable for BaselineReservation {
+        const MAX_SIZE: u32 = 1024;
+        const IS_FIXED_SIZE: bool = false;
+    }
+
+    #[test]
+    fn baseline_reservation_decodes() {
+        let bytes = BaselineReservation {
+            car_id: 4,
+            customer_id: 5,
+            reservation_time: NOW,
+        }
+        .to_bytes();
+        let reservation = Reservation::from_bytes(bytes);
+        assert_eq!(reservation.car_id, 4);
+        assert_eq!(reservation.customer_id, 5);
+        assert!(reservation.status == ReservationStatus::Active);
+        assert_eq!(reservation.reserved_by, Principal::management_canister());
+        assert_eq!(reservation.start_time, Timestamp(NOW));
+        assert_eq!(reservation.end_time, day(1));
+    }
+
+    #[test]
+    fn baseline_reservations_are_rekeyed_by_id() {
+        let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
+        let mut baseline = StableBTreeMap::<u64, BaselineReservation, Memory>::init(memory);
+        for car_id in [1, 2] {
+            baseline.insert(
+                car_id,
+                BaselineReservation {
+                    car_id,
+                    customer_id: 3,
+                    reservation_time: NOW,
+                },
+            );
+        }
+        drop(baseline);
+        // Cars 1 and 2 and customer 3 took ids 0 to 3
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(4).unwrap());
+        _seed_reservation_counter();
+        _migrate_legacy_reservations();
+
+        let reservations = _get_reservations();
+        assert_eq!(reservations.len(), 2);
+        for (reservation, (id, car_id)) in reservations.iter().zip([(4, 1), (5, 2)]) {
+            assert_eq!(reservation.id, id);
+            assert_eq!(reservation.car_id, car_id);
+        }
+        assert!(get_reservation(1).is_err());
+    }
+
+    #[test]
+    fn cancelled_reservations_move_to_the_history() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        set_time(NOW + 5);
+        cancel_reservation(reservation.id).unwrap();
+        assert!(get_reservation(reservation.id).is_err());
+        assert!(_get_car_reservations(&car.id).is_empty());
+        let history = reservation_history(car.id);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].status == ReservationStatus::Cancelled);
+        assert_eq!(history[0].ended_at, Some(NOW + 5));
+    }
+
+    #[test]
+    fn finished_reservations_are_archived_as_completed() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        set_time(day(3).as_nanos());
+        reserve(&car, &customer, 4, 5).unwrap();
+        assert!(get_reservation(reservation.id).is_err());
+        let history = reservation_history(car.id);
+        assert!(history[0].status == ReservationStatus::Completed);
+        assert_eq!(history[0].ended_at, Some(day(2).as_nanos()));
+    }
+
+    #[test]
+    fn retries_with_the_same_key_return_the_first_reservation() {
+        let (car, customer) = (car(), customer("ada"));
+        let key = Some("retry-1".to_string());
+        let first = make_reservation(car.id, customer.id, day(1), Some(day(2)), key.clone(), None).unwrap();
+        let retry = make_reservation(car.id, customer.id, day(1), Some(day(2)), key, None).unwrap();
+        assert_eq!(retry.id, first.id);
+        assert_eq!(retry.reservation_time, first.reservation_time);
+        assert_eq!(_get_car_reservations(&car.id).len(), 1);
+    }
+
+    #[test]
+    fn idempotency_keys_are_scoped_per_customer() {
+        let (car, ada, grace) = (car(), customer("ada"), customer("grace"));
+        let key = Some("retry-1".to_string());
+        let first = make_reservation(car.id, ada.id, day(1), Some(day(2)), key.clone(), None).unwrap();
+        let other = make_reservation(car.id, grace.id, day(2), Some(day(3)), key, None).unwrap();
+        assert_ne!(other.id, first.id);
+    }
+
+    fn ids(cars: Vec<Car>) -> Vec<u64> {
+        cars.iter().map(|car| car.id).collect()
+    }
+
+    #[test]
+    fn cars_sort_by_year_in_either_direction() {
+        let old = add_car(CarPayload { year: 2010, ..payload() }).unwrap();
+        let new = add_car(CarPayload { year: 2022, ..payload() }).unwrap();
+        let tie = add_car(CarPayload { year: 2010, ..payload() }).unwrap();
+        assert_eq!(ids(get_cars_sorted(CarSortKey::Year, false)), [old.id, tie.id, new.id]);
+        // Ties stay in id order either way
+        assert_eq!(ids(get_cars_sorted(CarSortKey::Year, true)), [new.id, old.id, tie.id]);
+    }
+
+    #[test]
+    fn cars_sort_by_make_in_either_direction() {
+        let toyota = car();
+        let audi = add_car(CarPayload { make: "Audi".to_string(), ..payload() }).unwrap();
+        let mazda = add_car(CarPayload { make: "Mazda".to_string(), ..payload() }).unwrap();
+        assert_eq!(ids(get_cars_sorted(CarSortKey::Make, false)), [audi.id, mazda.id, toyota.id]);
+        assert_eq!(ids(get_cars_sorted(CarSortKey::Make, true)), [toyota.id, mazda.id, audi.id]);
+    }
+
+    #[test]
+    fn colors_match_whatever_their_case() {
+        let upper = add_car(CarPayload { color: "Red".to_string(), ..payload() }).unwrap();
+        let lower = add_car(CarPayload { color: "red".to_string(), ..payload() }).unwrap();
+        assert_eq!(upper.color, "red");
+        let filter = |color: &str| CarFilter {
+            color: Some(color.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(search_cars(filter("RED"))), [upper.id, lower.id]);
+        assert_eq!(ids(search_cars(filter("red"))), [upper.id, lower.id]);
+    }
+
+    #[test]
+    fn strict_mode_rejects_colors_outside_the_palette() {
+        set_caller(admin());
+        set_color_policy(true, vec!["Red".to_string(), "blue".to_string()]).unwrap();
+        let Err(Error::ValidationErrors { errors }) =
+            add_car(CarPayload { color: "mauve".to_string(), ..payload() })
+        else {
+            panic!("a color outside the palette was accepted");
+        };
+        assert_eq!(errors[0].field, "color");
+        assert!(add_car(CarPayload { color: "RED".to_string(), ..payload() }).is_ok());
+    }
+
+    #[test]
+    fn only_admins_set_the_color_policy() {
+        assert!(matches!(set_color_policy(true, vec![]), Err(Error::NotAuthorized { .. })));
+        assert!(!get_color_policy().strict);
+    }
+
+    #[test]
+    fn range_report_includes_overlapping_reservations() {
+        let (car, customer) = (car(), customer("ada"));
+        let inside = reserve(&car, &customer, 3, 4).unwrap();
+        let overlapping = reserve(&car, &customer, 1, 3).unwrap();
+        reserve(&car, &customer, 6, 7).unwrap();
+        let found: Vec<u64> = reservations_in_range(day(2), day(5))
+            .unwrap()
+            .iter()
+            .map(|reservation| reservation.id)
+            .collect();
+        assert_eq!(found.len(), 2);
+        assert!(found.contains(&inside.id) && found.contains(&overlapping.id));
+    }
+
+    #[test]
+    fn range_report_needs_a_forward_range() {
+        assert!(matches!(reservations_in_range(day(2), day(2)), Err(Error::ValidationErrors { .. })));
+    }
+
+    #[test]
+    fn occupancy_is_the_reserved_share_of_the_window() {
+        let (car, customer) = (car(), customer("ada"));
+        // Two days inside a four-day window, one of them only half in it
+        reserve(&car, &customer, 0, 1).unwrap();
+        reserve(&car, &customer, 3, 5).unwrap();
+        let occupancy = car_occupancy(car.id, Timestamp(NOW + NANOS_PER_DAY / 2), Timestamp(day(4).as_nanos() + NANOS_PER_DAY / 2)).unwrap();
+        assert!((occupancy - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn occupancy_needs_a_known_car_and_a_forward_window() {
+        let car = car();
+        assert!(matches!(car_occupancy(car.id + 1, day(0), day(1)), Err(Error::NotFound { .. })));
+        assert!(matches!(car_occupancy(car.id, day(1), day(0)), Err(Error::ValidationErrors { .. })));
+    }
+
+    #[test]
+    fn bulk_delete_skips_unowned_and_reserved_cars() {
+        let (owned, reserved) = (car(), car());
+        reserve(&reserved, &customer("ada"), 1, 2).unwrap();
+        set_caller(user(2));
+        let unowned = car();
+        set_caller(user(1));
+        let missing = unowned.id + 1;
+        let deleted = delete_cars(vec![owned.id, unowned.id, reserved.id, missing]).unwrap();
+        assert_eq!(deleted, [owned.id]);
+        assert!(get_car(owned.id).is_err());
+        assert!(get_car(unowned.id).is_ok() && get_car(reserved.id).is_ok());
+    }
+
+    #[test]
+    fn customers_record_when_they_were_added_and_updated() {
+        let customer = customer("ada");
+        assert_eq!(customer.created_at, NOW);
+        assert_eq!(customer.updated_at, None);
+        set_time(NOW + 5);
+        let updated = update_customer(customer.id, "ada".to_string(), Contact::Email("ada@example.org".to_string())).unwrap();
+        assert_eq!(updated.created_at, NOW);
+        assert_eq!(updated.updated_at, Some(NOW + 5));
+    }
+
+    // A customer as the first version of the canister stored it
+    #[derive(candid::CandidType)]
+    struct BaselineCustomer {
+        id: u64,
+        name: String,
+        contact: String,
+    }
+
+    impl Storable for BaselineCustomer {
+        fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+            Cow::Owned(Encode!(self).unwrap())
+        }
+
+        fn from_bytes(_: std::borrow::Cow<[u8]>) -> Self {
+            unimplemented!("only written by tests")
+        }
+    }
+
+    impl BoundedStorable for BaselineCustomer {
+        const MAX_SIZE: u32 = 1024;
+        const IS_FIXED_SIZE: bool = false;
+    }
+
+    #[test]
+    fn baseline_customers_are_stamped_on_upgrade() {
+        let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+        StableBTreeMap::<u64, BaselineCustomer, Memory>::init(memory).insert(
+            3,
+            BaselineCustomer {
+                id: 3,
+                name: "ada".to_string(),
+                contact: "ada@example.com".to_string(),
+            },
+        );
+        assert_eq!(get_customer(3).unwrap().created_at, 0);
+        _migrate_legacy_customers(NOW);
+        let customer = get_customer(3).unwrap();
+        assert_eq!(customer.created_at, NOW);
+        assert_eq!(customer.updated_at, None);
+        assert_eq!(customer.created_by, None);
+        // Customers that have a created_at keep it
+        _migrate_legacy_customers(NOW + 5);
+        assert_eq!(get_customer(3).unwrap().created_at, NOW);
+    }
+
+    #[test]
+    fn customer_search_matches_name_substrings_in_any_case() {
+        let (alice, alicia, bob) = (customer("Alice"), customer("alicia"), customer("Bob"));
+        let found: Vec<u64> = search_customers("ali".to_string(), 0, 10).iter().map(|customer| customer.id).collect();
+        assert_eq!(found, [alice.id, alicia.id]);
+        assert_eq!(search_customers(String::new(), 0, 10).len(), 3);
+        assert_eq!(search_customers("BOB".to_string(), 0, 10)[0].id, bob.id);
+    }
+
+    #[test]
+    fn staff_may_reserve_for_any_customer() {
+        let (car, customer) = (car(), customer("ada"));
+        set_caller(admin());
+        add_staff(user(3)).unwrap();
+        set_caller(user(3));
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        assert_eq!(reservation.reserved_by, user(3));
+        assert_eq!(reservation.customer_id, customer.id);
+    }
+
+    #[test]
+    fn other_callers_may_only_reserve_for_their_own_customers() {
+        let (car, customer) = (car(), customer("ada"));
+        set_caller(user(2));
+        assert!(matches!(reserve(&car, &customer, 1, 2), Err(Error::NotAuthorized { .. })));
+        let own = self::customer("grace");
+        assert_eq!(reserve(&car, &own, 1, 2).unwrap().reserved_by, user(2));
+    }
+
+    #[test]
+    fn only_staff_or_the_creator_may_queue_a_customer() {
+        let (car, customer) = (booked_car(), customer("ada"));
+        set_caller(user(2));
+        assert!(matches!(join_waitlist(car.id, customer.id), Err(Error::NotAuthorized { .. })));
+        set_caller(admin());
+        add_staff(user(3)).unwrap();
+        set_caller(user(3));
+        assert!(join_waitlist(car.id, customer.id).is_ok());
+    }
+
+    #[test]
+    fn current_reservation_is_the_one_under_way() {
+        let (car, customer) = (car(), customer("ada"));
+        let now = reserve(&car, &customer, 0, 1).unwrap();
+        reserve(&car, &customer, 2, 3).unwrap();
+        assert_eq!(current_reservation(car.id).unwrap().unwrap().id, now.id);
+    }
+
+    #[test]
+    fn cars_reserved_only_later_have_no_current_reservation() {
+        let (car, customer) = (car(), customer("ada"));
+        reserve(&car, &customer, 2, 3).unwrap();
+        assert!(current_reservation(car.id).unwrap().is_none());
+        assert!(current_reservation(self::car().id).unwrap().is_none());
+        assert!(matches!(current_reservation(car.id + 100), Err(Error::NotFound { .. })));
+    }
+
+    fn assert_fits<T: BoundedStorable>(value: &T) {
+        let size = value.to_bytes().len();
+        assert!(size <= T::MAX_SIZE as usize, "{} bytes exceed the bound of {}", size, T::MAX_SIZE);
+    }
+
+    #[test]
+    fn largest_event_fits_its_bound() {
+        assert_fits(&Event {
+            id: u64::MAX,
+            kind: EventKind::StaffRemoved,
+            // Principals are at most 29 bytes long
+            actor: Principal::from_slice(&[u8::MAX; 29]),
+            target_id: Some(u64::MAX),
+            timestamp: u64::MAX,
+        });
+    }
+
+    #[test]
+    fn adding_and_deleting_a_car_is_logged() {
+        let car = car();
+        delete_car(car.id).unwrap();
+        set_caller(admin());
+        let events = get_events(0, 10).unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].kind == EventKind::CarAdded);
+        assert!(events[1].kind == EventKind::CarDeleted);
+        assert!(events.iter().all(|event| event.actor == user(1) && event.target_id == Some(car.id)));
+    }
+
+    #[test]
+    fn only_admins_read_the_event_log() {
+        car();
+        assert!(matches!(get_events(0, 10), Err(Error::NotAuthorized { .. })));
+    }
+
+    #[test]
+    fn deleted_cars_are_hidden_until_restored() {
+        let car = car();
+        delete_car(car.id).unwrap();
+        assert!(get_car(car.id).is_err());
+        assert!(generate_report().is_empty());
+        assert!(search_cars(CarFilter::default()).is_empty());
+        assert_eq!(restore_car(car.id).unwrap().id, car.id);
+        assert!(get_car(car.id).is_ok());
+    }
+
+    #[test]
+    fn only_the_owner_deletes_or_restores_a_car() {
+        let car = car();
+        set_caller(user(2));
+        assert!(matches!(delete_car(car.id), Err(Error::NotAuthorized { .. })));
+        set_caller(user(1));
+        delete_car(car.id).unwrap();
+        set_caller(user(2));
+        assert!(matches!(restore_car(car.id), Err(Error::NotAuthorized { .. })));
+    }
+
+    #[test]
+    fn reserved_cars_cannot_be_deleted_or_purged() {
+        let car = car();
+        let reservation = reserve(&car, &customer("ada"), 1, 2).unwrap();
+        let Err(Error::HasActiveReservation { msg }) = delete_car(car.id) else {
+            panic!("a reserved car was deleted");
+        };
+        assert!(msg.contains(&format!("ids=[{}]", reservation.id)));
+        set_caller(admin());
+        assert!(matches!(purge_car(car.id), Err(Error::HasActiveReservation { .. })));
+        assert!(get_car(car.id).is_ok());
+    }
+
+    #[test]
+    fn purging_a_car_drops_its_waitlist() {
+        let (car, other) = (booked_car(), booked_car());
+        let customer = customer("ada");
+        join_waitlist(car.id, customer.id).unwrap();
+        join_waitlist(other.id, customer.id).unwrap();
+        set_caller(admin());
+        purge_car(car.id).unwrap();
+        assert!(get_waitlist(car.id).is_empty());
+        assert_eq!(get_waitlist(other.id).len(), 1);
+        assert!(restore_car(car.id).is_err());
+    }
+
+    #[test]
+    fn admins_list_every_reservation_in_id_order() {
+        let (first, second, customer) = (car(), car(), customer("ada"));
+        let a = reserve(&first, &customer, 1, 2).unwrap();
+        let b = reserve(&second, &customer, 1, 2).unwrap();
+        let c = reserve(&first, &customer, 3, 4).unwrap();
+        assert!(matches!(get_all_reservations(), Err(Error::NotAuthorized { .. })));
+        set_caller(admin());
+        let ids: Vec<u64> = get_all_reservations().unwrap().iter().map(|reservation| reservation.id).collect();
+        assert_eq!(ids, [a.id, b.id, c.id]);
+    }
+
+    // 1 June 2025, 00:00 UTC
+    const JUNE_2025: u64 = 1_748_736_000 * 1_000_000_000;
+
+    #[test]
+    fn next_model_year_is_accepted() {
+        set_time(JUNE_2025);
+        assert_eq!(add_car(CarPayload { year: 2025, ..payload() }).unwrap().year, 2025);
+        assert_eq!(add_car(CarPayload { year: 2026, ..payload() }).unwrap().year, 2026);
+    }
+
+    #[test]
+    fn years_beyond_next_year_are_rejected() {
+        set_time(JUNE_2025);
+        let Err(Error::ValidationErrors { errors }) = add_car(CarPayload { year: 2027, ..payload() }) else {
+            panic!("a far-future year was accepted");
+        };
+        assert_eq!(errors[0].field, "year");
+        let car = car();
+        assert!(update_car(car.id, CarPayload { year: 3000, ..payload() }, None).is_err());
+    }
+
+    #[test]
+    fn years_turn_over_at_midnight_on_new_years_day() {
+        // 1 January 2024, 00:00 UTC
+        let new_year = 1_704_067_200 * 1_000_000_000;
+        assert_eq!(_year_of(new_year - 1), 2023);
+        assert_eq!(_year_of(new_year), 2024);
+        assert_eq!(_year_of(0), 1970);
+    }
+
+    #[test]
+    fn reservations_can_move_to_a_free_car() {
+        let (broken, spare, customer) = (car(), car(), customer("ada"));
+        let reservation = reserve(&broken, &customer, 0, 2).unwrap();
+        assert!(get_car(broken.id).unwrap().status == CarStatus::Booked);
+        let moved = reassign_reservation(reservation.id, spare.id).unwrap();
+        assert_eq!(moved.car_id, spare.id);
+        assert!(get_car(broken.id).unwrap().status == CarStatus::Available);
+        assert!(get_car(spare.id).unwrap().status == CarStatus::Booked);
+        assert_eq!(get_reservation(reservation.id).unwrap().car_id, spare.id);
+    }
+
+    #[test]
+    fn reservations_cannot_move_onto_a_booked_car() {
+        let (broken, spare, customer) = (car(), car(), customer("ada"));
+        let reservation = reserve(&broken, &customer, 1, 3).unwrap();
+        reserve(&spare, &self::customer("bob"), 2, 4).unwrap();
+        assert!(matches!(
+            reassign_reservation(reservation.id, spare.id),
+            Err(Error::ReservationConflict { .. })
+        ));
+        assert_eq!(get_reservation(reservation.id).unwrap().car_id, broken.id);
+    }
+
+    #[test]
+    fn only_staff_or_the_booker_reassign_a_reservation() {
+        let (broken, spare, customer) = (car(), car(), customer("ada"));
+        let reservation = reserve(&broken, &customer, 1, 2).unwrap();
+        set_caller(user(2));
+        assert!(matches!(
+            reassign_reservation(reservation.id, spare.id),
+            Err(Error::NotAuthorized { .. })
+        ));
+        set_caller(admin());
+        assert!(reassign_reservation(reservation.id, spare.id).is_ok());
+    }
+
+    #[test]
+    fn conflicts_report_when_the_car_is_free_again() {
+        let (car, customer, other) = (car(), customer("ada"), customer("bob"));
+        // Another customer's two back-to-back reservations, then a day's gap before a third
+        let first = reserve(&car, &other, 1, 2).unwrap();
+        reserve(&car, &other, 2, 3).unwrap();
+        reserve(&car, &other, 4, 5).unwrap();
+        let Err(Error::ReservationConflict {
+            car_id,
+            conflicting_reservation_id,
+            available_after,
+        }) = make_reservation(car.id, customer.id, Timestamp(day(1).as_nanos() + NANOS_PER_DAY / 2), Some(day(6)), None, None)
+        else {
+            panic!("an overlapping reservation was accepted");
+        };
+        assert_eq!(car_id, car.id);
+        assert_eq!(conflicting_reservation_id, first.id);
+        assert_eq!(available_after, day(3).as_nanos());
+    }
+
+    #[test]
+    fn cars_created_within_the_range_are_listed() {
+        car();
+        set_time(day(1).as_nanos());
+        let inside = car();
+        set_time(day(2).as_nanos());
+        let edge = car();
+        set_time(day(3).as_nanos());
+        car();
+        assert_eq!(ids(cars_created_between(day(1), day(2)).unwrap()), [inside.id, edge.id]);
+        assert!(cars_created_between(day(5), day(6)).unwrap().is_empty());
+        assert!(matches!(cars_created_between(day(2), day(1)), Err(Error::ValidationErrors { .. })));
+    }
+
+    #[test]
+    fn image_urls_must_use_https() {
+        let url = "https://example.com/corolla.jpg".to_string();
+        let car = add_car(CarPayload { image_url: Some(url.clone()), ..payload() }).unwrap();
+        assert_eq!(car.image_url, Some(url));
+        let Err(Error::ValidationErrors { errors }) = add_car(CarPayload {
+            image_url: Some("http://example.com/corolla.jpg".to_string()),
+            ..payload()
+        }) else {
+            panic!("an http image URL was accepted");
+        };
+        assert_eq!(errors[0].field, "image_url");
+        assert_eq!(add_car(CarPayload { image_url: None, ..payload() }).unwrap().image_url, None);
+    }
+
+    #[test]
+    fn patched_image_urls_are_validated_too() {
+        let car = car();
+        let patch = CarPatch {
+            image_url: Some("ftp://example.com/corolla.jpg".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(patch_car(car.id, patch, None), Err(Error::ValidationErrors { .. })));
+    }
+
+    #[test]
+    fn cars_with_the_longest_image_url_fit() {
+        let url = format!("https://{}.com", "a".repeat(244));
+        assert_eq!(url.len(), 256);
+        let car = add_car(CarPayload { image_url: Some(url), ..payload() }).unwrap();
+        assert!(get_car(car.id).is_ok());
+    }
+
+    #[test]
+    fn oversize_cars_are_refused_with_their_size() {
+        // Validation keeps payloads well within the bound, so only a car built in
+        // code can exceed it
+        let result = do_insert_car(&mut Car {
+            make: "M".repeat(Car::MAX_SIZE as usize),
+            ..Default::default()
+        });
+        let Err(Error::RecordTooLarge { size, max }) = result else {
+            panic!("an oversize car was stored");
+        };
+        assert!(size > max);
+        assert_eq!(max, Car::MAX_SIZE);
+        assert!(generate_report().is_empty());
+    }
+
+    #[test]
+    fn cars_under_the_old_bound_move_on_upgrade() {
+        let legacy = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)));
+        let car = Car {
+            id: 7,
+            make: "Toyota".to_string(),
+            ..Default::default()
+        };
+        StableBTreeMap::<u64, LegacyCar, Memory>::init(legacy).insert(7, LegacyCar(car));
+        _migrate_legacy_cars(MemoryId::new(1), &CAR_STORAGE);
+        assert_eq!(get_car(7).unwrap().make, "Toyota");
+        let legacy = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)));
+        assert!(StableBTreeMap::<u64, LegacyCar, Memory>::init(legacy).is_empty());
+    }
+
+    #[test]
+    fn available_cars_leave_out_reserved_and_maintenance_cars() {
+        let (booked, free, later) = (car(), car(), car());
+        let maintenance = add_car(CarPayload { status: CarStatus::Maintenance, ..payload() }).unwrap();
+        let customer = customer("ada");
+        reserve(&booked, &customer, 1, 3).unwrap();
+        // Back to back with the window doesn't overlap it
+        reserve(&later, &customer, 3, 4).unwrap();
+        assert_eq!(ids(available_cars(day(2), day(3)).unwrap()), [free.id, later.id]);
+        assert!(ids(available_cars(day(5), day(6)).unwrap()) == [booked.id, free.id, later.id]);
+        assert!(get_car(maintenance.id).is_ok());
+        assert!(available_cars(day(3), day(2)).is_err());
+    }
+
+    #[test]
+    fn cars_out_of_rotation_cannot_be_reserved_or_rebooked() {
+        let car = car();
+        let customer = customer("ada");
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        patch_car(car.id, CarPatch { status: Some(CarStatus::Maintenance), ..Default::default() }, None).unwrap();
+        assert!(matches!(reserve(&car, &customer, 3, 4), Err(Error::CarUnavailable { .. })));
+        assert!(matches!(
+            modify_reservation(reservation.id, day(1), day(3)),
+            Err(Error::CarUnavailable { .. })
+        ));
+    }
+
+    #[test]
+    fn currency_codes_must_be_three_letters() {
+        for code in ["EURO", "E1", ""] {
+            let Err(Error::ValidationErrors { errors }) =
+                add_car(CarPayload { currency: Some(code.to_string()), ..payload() })
+            else {
+                panic!("the currency code {:?} was accepted", code);
+            };
+            assert_eq!(errors[0].field, "currency");
+        }
+        set_caller(admin());
+        assert!(set_currency_settings("EURO".to_string(), false).is_err());
+        assert_eq!(get_currency_settings().default_currency, DEFAULT_CURRENCY);
+    }
+
+    #[test]
+    fn other_currencies_need_to_be_allowed() {
+        let eur = || CarPayload { currency: Some("eur".to_string()), ..payload() };
+        assert!(matches!(add_car(eur()), Err(Error::ValidationErrors { .. })));
+        assert_eq!(car().currency, DEFAULT_CURRENCY);
+        set_caller(admin());
+        set_currency_settings("ICP".to_string(), true).unwrap();
+        set_caller(user(1));
+        assert_eq!(add_car(eur()).unwrap().currency, "EUR");
+    }
+
+    #[test]
+    fn reservation_cost_charges_started_days_in_the_cars_currency() {
+        set_caller(admin());
+        set_currency_settings("ICP".to_string(), true).unwrap();
+        set_caller(user(1));
+        let car = add_car(CarPayload {
+            currency: Some("EUR".to_string()),
+            daily_price: 100,
+            ..payload()
+        })
+        .unwrap();
+        let reservation =
+            make_reservation(car.id, customer("ada").id, day(1), Some(Timestamp(day(2).as_nanos() + 1)), None, None).unwrap();
+        let cost = reservation_cost(reservation.id).unwrap();
+        assert_eq!(cost.amount, 200);
+        assert_eq!(cost.currency, "EUR");
+    }
+
+    fn coupon(code: &str, percent_off: u8, valid_until: u64, max_uses: u32) -> Coupon {
+        let caller = caller();
+        set_caller(admin());
+        let coupon = create_coupon(code.to_string(), percent_off, valid_until, max_uses).unwrap();
+        set_caller(caller);
+        coupon
+    }
+
+    fn priced_car(daily_price: u64) -> Car {
+        add_car(CarPayload { daily_price, ..payload() }).unwrap()
+    }
+
+    #[test]
+    fn coupons_take_their_discount_off_the_cost() {
+        let car = priced_car(333);
+        coupon("spring", 10, day(30).as_nanos(), 5);
+        let reservation =
+            make_reservation(car.id, customer("ada").id, day(1), Some(day(2)), None, Some("Spring".to_string()))
+                .unwrap();
+        // 10% off 333 rounds down
+        assert_eq!(reservation.cost.unwrap().amount, 299);
+        assert_eq!(reservation.coupon_code, Some("SPRING".to_string()));
+        set_caller(admin());
+        assert_eq!(get_coupons().unwrap()[0].uses, 1);
+    }
+
+    #[test]
+    fn expired_coupons_are_rejected() {
+        let (car, customer) = (priced_car(100), customer("ada"));
+        coupon("old", 10, NOW - 1, 5);
+        let result = make_reservation(car.id, customer.id, day(1), Some(day(2)), None, Some("OLD".to_string()));
+        assert!(matches!(result, Err(Error::InvalidCoupon { .. })));
+        coupon("new", 10, day(30).as_nanos(), 5);
+        set_caller(admin());
+        expire_coupon("new".to_string()).unwrap();
+        set_caller(user(1));
+        let result = make_reservation(car.id, customer.id, day(1), Some(day(2)), None, Some("NEW".to_string()));
+        assert!(matches!(result, Err(Error::InvalidCoupon { .. })));
+        assert!(_get_car_reservations(&car.id).is_empty());
+    }
+
+    #[test]
+    fn coupons_stop_at_their_maximum_uses() {
+        let (car, customer) = (priced_car(100), customer("ada"));
+        coupon("once", 50, day(30).as_nanos(), 1);
+        let code = || Some("ONCE".to_string());
+        assert!(make_reservation(car.id, customer.id, day(1), Some(day(2)), None, code()).is_ok());
+        let result = make_reservation(car.id, customer.id, day(2), Some(day(3)), None, code());
+        assert!(matches!(result, Err(Error::InvalidCoupon { .. })));
+        assert!(matches!(
+            make_reservation(car.id, customer.id, day(2), Some(day(3)), None, Some("NOPE".to_string())),
+            Err(Error::InvalidCoupon { .. })
+        ));
+    }
+
+    #[test]
+    fn largest_coupon_fits_its_bound() {
+        assert_fits(&Coupon {
+            code: "C".repeat(MAX_COUPON_CODE_LEN),
+            percent_off: 100,
+            valid_until: u64::MAX,
+            max_uses: u32::MAX,
+            uses: u32::MAX,
+        });
+        assert_fits(&CouponCode("C".repeat(MAX_COUPON_CODE_LEN)));
+    }
+
+    #[test]
+    fn largest_reservation_fits_its_bound() {
+        assert_fits(&Reservation {
+            id: u64::MAX,
+            car_id: u64::MAX,
+            customer_id: u64::MAX,
+            reserved_by: Principal::from_slice(&[u8::MAX; 29]),
+            start_time: Timestamp(u64::MAX),
+            end_time: Timestamp(u64::MAX),
+            reservation_time: u64::MAX,
+            status: ReservationStatus::Cancelled,
+            ended_at: Some(u64::MAX),
+            cost: Some(Price {
+                amount: u64::MAX,
+                currency: "XAU".to_string(),
+            }),
+            coupon_code: Some("C".repeat(MAX_COUPON_CODE_LEN)),
+            paid_block: Some(u64::MAX),
+            confirmation_code: Some(_confirmation_code(u64::MAX)),
+            checked_out_at: Some(u64::MAX),
+            checkout_odometer: Some(u64::MAX),
+            checked_in_at: Some(u64::MAX),
+            checkin_odometer: Some(u64::MAX),
+            late_fee: Some(u64::MAX),
+            deposit_held: Some(u64::MAX),
+            deposit_state: Some(DepositState::Forfeited {
+                reason: "r".repeat(MAX_REASON_LEN),
+            }),
+            cancellation_fee: Some(u64::MAX),
+        });
+    }
+
+    fn customer_ids(customers: Vec<Customer>) -> Vec<u64> {
+        customers.iter().map(|customer| customer.id).collect()
+    }
+
+    #[test]
+    fn customers_page_in_id_order() {
+        let all: Vec<u64> = ["a", "b", "c", "d", "e"].iter().map(|name| customer(name).id).collect();
+        assert_eq!(customer_ids(get_customers_paginated(0, 2)), all[..2]);
+        assert_eq!(customer_ids(get_customers_paginated(2, 2)), all[2..4]);
+        assert_eq!(customer_ids(get_customers_paginated(4, 2)), all[4..]);
+        assert!(get_customers_paginated(5, 2).is_empty());
+        assert!(get_customers_paginated(u64::MAX, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn customer_search_pages_its_matches() {
+        let (anna, _, annie) = (customer("anna"), customer("bob"), customer("annie"));
+        assert_eq!(customer_ids(search_customers("ann".to_string(), 0, 1)), [anna.id]);
+        assert_eq!(customer_ids(search_customers("ann".to_string(), 1, 1)), [annie.id]);
+        assert!(search_customers("ann".to_string(), 2, 1).is_empty());
+    }
+
+    #[test]
+    fn page_sizes_are_capped() {
+        for n in 0..=MAX_PAGE_SIZE {
+            customer(&format!("c{}", n));
+        }
+        assert_eq!(get_customers_paginated(0, u64::MAX).len() as u64, MAX_PAGE_SIZE);
+    }
+
+    #[test]
+    fn admins_release_a_stuck_car() {
+        let car = car();
+        let reservation = reserve(&car, &customer("ada"), 0, 1).unwrap();
+        assert!(get_car(car.id).unwrap().status == CarStatus::Booked);
+        set_caller(admin());
+        set_time(NOW + 5);
+        let released = admin_release_car(car.id).unwrap();
+        assert!(released.status == CarStatus::Available);
+        assert_eq!(released.updated_at, Some(NOW + 5));
+        assert!(get_reservation(reservation.id).is_err());
+        assert!(reservation_history(car.id)[0].status == ReservationStatus::Cancelled);
+        assert!(get_events(0, 100).unwrap().iter().any(|event| event.kind == EventKind::CarReleased));
+    }
+
+    #[test]
+    fn only_admins_release_cars() {
+        let car = car();
+        reserve(&car, &customer("ada"), 0, 1).unwrap();
+        assert!(matches!(admin_release_car(car.id), Err(Error::NotAuthorized { .. })));
+        assert!(get_car(car.id).unwrap().status == CarStatus::Booked);
+    }
+
+    #[test]
+    fn reservation_ids_stay_unique_across_upgrades() {
+        let (car, customer) = (car(), customer("ada"));
+        let before: Vec<u64> = (1..3).map(|n| reserve(&car, &customer, n, n + 1).unwrap().id).collect();
+        post_upgrade();
+        let after = reserve(&car, &customer, 3, 4).unwrap();
+        assert!(!before.contains(&after.id));
+        assert_eq!(after.id, before[1] + 1);
+    }
+
+    #[test]
+    fn the_reservation_counter_starts_after_shared_ids() {
+        // Reservations made before the dedicated counter took ids 0 to 9 from the shared one
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(10).unwrap());
+        _seed_reservation_counter();
+        let reservation = reserve(&car(), &customer("ada"), 1, 2).unwrap();
+        assert_eq!(reservation.id, 10);
+        // Seeding only happens once
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(50).unwrap());
+        _seed_reservation_counter();
+        assert_eq!(reserve(&car(), &self::customer("bob"), 1, 2).unwrap().id, 11);
+    }
+
+    #[test]
+    fn cars_are_listed_by_status() {
+        let with = |status| {
+            add_car(CarPayload {
+                status,
+                ..payload()
+            })
+            .unwrap()
+            .id
+        };
+        let available = [with(CarStatus::Available), with(CarStatus::Available)];
+        let maintenance = with(CarStatus::Maintenance);
+        let booked = with(CarStatus::Booked);
+        assert_eq!(ids(get_cars_by_status(CarStatus::Available)), available);
+        assert_eq!(ids(get_cars_by_status(CarStatus::Maintenance)), [maintenance]);
+        assert_eq!(ids(get_cars_by_status(CarStatus::Booked)), [booked]);
+        assert!(get_cars_by_status(CarStatus::Retired).is_empty());
+    }
+
+    fn transfer(to: &AccountIdentifier, e8s: u64, memo: u64) -> Block {
+        let at = ic_ledger_types::Timestamp { timestamp_nanos: NOW };
+        Block {
+            parent_hash: None,
+            transaction: ic_ledger_types::Transaction {
+                memo: Memo(memo),
+                operation: Some(Operation::Transfer {
+                    from: AccountIdentifier::new(&user(1), &DEFAULT_SUBACCOUNT),
+                    to: *to,
+                    amount: ic_ledger_types::Tokens::from_e8s(e8s),
+                    fee: ic_ledger_types::DEFAULT_FEE,
+                }),
+                created_at_time: at,
+            },
+            timestamp: at,
+        }
+    }
+
+    #[test]
+    fn a_transfer_of_the_full_cost_settles() {
+        let account = AccountIdentifier::new(&user(9), &DEFAULT_SUBACCOUNT);
+        assert!(_check_payment(&transfer(&account, 500, 7), 1, &account, 7, 500).is_ok());
+        assert!(_check_payment(&transfer(&account, 600, 7), 1, &account, 7, 500).is_ok());
+    }
+
+    #[test]
+    fn an_insufficient_transfer_is_refused() {
+        let account = AccountIdentifier::new(&user(9), &DEFAULT_SUBACCOUNT);
+        assert!(matches!(
+            _check_payment(&transfer(&account, 499, 7), 1, &account, 7, 500),
+            Err(Error::InsufficientPayment {
+                required: 500,
+                received: 499
+            })
+        ));
+    }
+
+    #[test]
+    fn a_transfer_must_name_the_reservation_and_reach_the_canister() {
+        let account = AccountIdentifier::new(&user(9), &DEFAULT_SUBACCOUNT);
+        let elsewhere = AccountIdentifier::new(&user(8), &DEFAULT_SUBACCOUNT);
+        assert!(matches!(
+            _check_payment(&transfer(&account, 500, 8), 1, &account, 7, 500),
+            Err(Error::PaymentFailed { .. })
+        ));
+        assert!(matches!(
+            _check_payment(&transfer(&elsewhere, 500, 7), 1, &account, 7, 500),
+            Err(Error::PaymentFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn a_block_settles_only_one_reservation() {
+        let reservation = reserve(&car(), &customer("ada"), 1, 2).unwrap();
+        assert!(_settleable_reservation(reservation.id, 3).is_ok());
+        SETTLED_BLOCKS.with(|service| service.borrow_mut().insert(3, 99));
+        assert!(matches!(
+            _settleable_reservation(reservation.id, 3),
+            Err(Error::PaymentFailed { .. })
+        ));
+    }
+
+    // Monday, 1 January 2024, 00:00 UTC
+    const MONDAY: u64 = 1_704_067_200 * 1_000_000_000;
+
+    fn weekend_priced_car() -> Car {
+        add_car(CarPayload {
+            daily_price: 100,
+            weekend_multiplier: Some(1.5),
+            ..payload()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn weekdays_are_counted_from_the_epoch() {
+        assert_eq!(_weekday_of(MONDAY), 1);
+        assert_eq!(_weekday_of(MONDAY - 1), 0);
+        assert_eq!(_weekend_days(MONDAY, 5), 0);
+        assert_eq!(_weekend_days(MONDAY, 7), 2);
+        assert_eq!(_weekend_days(MONDAY, 15), 4);
+    }
+
+    #[test]
+    fn weekday_windows_cost_the_daily_price() {
+        let car = weekend_priced_car();
+        assert_eq!(_compute_cost(&car, MONDAY, MONDAY + 5 * NANOS_PER_DAY).amount, 500);
+    }
+
+    #[test]
+    fn weekend_windows_cost_the_multiplied_price() {
+        let car = weekend_priced_car();
+        let saturday = MONDAY + 5 * NANOS_PER_DAY;
+        assert_eq!(_compute_cost(&car, saturday, saturday + 2 * NANOS_PER_DAY).amount, 300);
+    }
+
+    #[test]
+    fn mixed_windows_blend_both_prices() {
+        let car = weekend_priced_car();
+        // Friday to Monday: one weekday and two weekend days
+        let friday = MONDAY + 4 * NANOS_PER_DAY;
+        assert_eq!(_compute_cost(&car, friday, friday + 3 * NANOS_PER_DAY).amount, 400);
+        // A whole week
+        assert_eq!(_compute_cost(&car, MONDAY, MONDAY + 7 * NANOS_PER_DAY).amount, 800);
+    }
+
+    #[test]
+    fn multipliers_outside_the_range_are_rejected() {
+        let result = add_car(CarPayload {
+            weekend_multiplier: Some(11.0),
+            ..payload()
+        });
+        assert!(matches!(result, Err(Error::ValidationErrors { .. })));
+    }
+
+    #[test]
+    fn owner_only_contacts_are_masked_for_other_callers() {
+        let ada = customer("ada");
+        set_contact_visibility(ada.id, ContactVisibility::OwnerOnly).unwrap();
+        set_caller(user(2));
+        assert!(get_customer(ada.id).unwrap().contact == Contact::Email("a***@example.com".to_string()));
+        assert!(get_customers_paginated(0, 10)[0].contact == Contact::Email("a***@example.com".to_string()));
+        assert!(matches!(
+            set_contact_visibility(ada.id, ContactVisibility::Public),
+            Err(Error::NotAuthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn creators_and_admins_see_the_full_contact() {
+        let ada = customer("ada");
+        set_contact_visibility(ada.id, ContactVisibility::OwnerOnly).unwrap();
+        assert!(get_customer(ada.id).unwrap().contact == Contact::Email("ada@example.com".to_string()));
+        set_caller(admin());
+        assert!(get_customer(ada.id).unwrap().contact == Contact::Email("ada@example.com".to_string()));
+        assert!(search_customers("ada".to_string(), 0, 10)[0].contact == Contact::Email("ada@example.com".to_string()));
+    }
+
+    #[test]
+    fn public_contacts_are_shown_to_everyone() {
+        let ada = customer("ada");
+        set_caller(user(2));
+        assert!(get_customer(ada.id).unwrap().contact == Contact::Email("ada@example.com".to_string()));
+        assert_eq!(_mask_contact("+44 20 7946 0000"), "+***");
+    }
+
+    #[test]
+    fn confirmation_codes_are_unique() {
+        let codes: std::collections::BTreeSet<String> = (0..10_000).map(_confirmation_code).collect();
+        assert_eq!(codes.len(), 10_000);
+        assert!(codes.iter().all(|code| code.len() == CONFIRMATION_CODE_LEN));
+        assert_eq!(_confirmation_code(42), _confirmation_code(42));
+    }
+
+    #[test]
+    fn reservations_are_found_by_their_code() {
+        let (car, customer) = (car(), customer("ada"));
+        reserve(&car, &customer, 1, 2).unwrap();
+        let second = reserve(&car, &customer, 2, 3).unwrap();
+        let code = second.confirmation_code.clone().unwrap();
+        assert_eq!(get_reservation_by_code(code.to_lowercase()).unwrap().id, second.id);
+        cancel_reservation(second.id).unwrap();
+        assert_eq!(get_reservation_by_code(code).unwrap().id, second.id);
+        assert!(matches!(
+            get_reservation_by_code("NOPE".to_string()),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn bulk_status_changes_skip_cars_the_caller_does_not_own() {
+        let (mine, also_mine) = (car(), car());
+        set_caller(user(2));
+        let theirs = car();
+        set_caller(user(1));
+        let updated = set_cars_status(vec![mine.id, theirs.id, also_mine.id, 999], CarStatus::Maintenance).unwrap();
+        assert_eq!(updated, [mine.id, also_mine.id]);
+        assert!(get_car(theirs.id).unwrap().status == CarStatus::Available);
+        // Admins may change any car
+        set_caller(admin());
+        assert_eq!(set_cars_status(vec![theirs.id], CarStatus::Retired).unwrap(), [theirs.id]);
+    }
+
+    #[test]
+    fn cars_under_way_are_not_made_available() {
+        let (held, later) = (car(), car());
+        let customer = customer("ada");
+        make_reservation(held.id, customer.id, Timestamp(NOW), Some(day(2)), None, None).unwrap();
+        reserve(&later, &customer, 1, 2).unwrap();
+        let updated = set_cars_status(vec![held.id, later.id], CarStatus::Available).unwrap();
+        assert_eq!(updated, [later.id]);
+        assert!(get_car(held.id).unwrap().status == CarStatus::Booked);
+        // update_car and patch_car go through the same guard
+        let payload = CarPayload {
+            status: CarStatus::Available,
+            ..payload()
+        };
+        assert!(matches!(update_car(held.id, payload, None), Err(Error::HasActiveReservation { .. })));
+        let patch = CarPatch {
+            status: Some(CarStatus::Available),
+            ..Default::default()
+        };
+        assert!(matches!(patch_car(held.id, patch, None), Err(Error::HasActiveReservation { .. })));
+        // Once the window is over the car may be made available
+        set_time(day(2).as_nanos());
+        assert_eq!(set_cars_status(vec![held.id], CarStatus::Available).unwrap(), [held.id]);
+    }
+
+    #[test]
+    fn admins_may_update_and_patch_any_car() {
+        let car = car();
+        set_caller(admin());
+        let mut repaint = payload();
+        repaint.owner = car.owner.clone();
+        repaint.color = "black".to_string();
+        assert_eq!(update_car(car.id, repaint, None).unwrap().color, "black");
+        let patch = CarPatch {
+            color: Some("blue".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(patch_car(car.id, patch, None).unwrap().color, "blue");
+    }
+
+    #[test]
+    fn free_cars_are_available_now() {
+        let car = car();
+        assert_eq!(next_available_time(car.id).unwrap(), NOW);
+        reserve(&car, &customer("ada"), 1, 2).unwrap();
+        assert_eq!(next_available_time(car.id).unwrap(), NOW);
+        assert!(matches!(next_available_time(999), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn a_current_reservation_frees_the_car_at_its_end() {
+        let car = car();
+        make_reservation(car.id, customer("ada").id, Timestamp(NOW), Some(day(1)), None, None).unwrap();
+        assert_eq!(next_available_time(car.id).unwrap(), day(1).as_nanos());
+    }
+
+    #[test]
+    fn back_to_back_reservations_free_the_car_at_the_far_end() {
+        let (car, customer) = (car(), customer("ada"));
+        make_reservation(car.id, customer.id, Timestamp(NOW), Some(day(1)), None, None).unwrap();
+        reserve(&car, &customer, 1, 2).unwrap();
+        reserve(&car, &customer, 2, 3).unwrap();
+        // A gap ends the run
+        reserve(&car, &customer, 4, 5).unwrap();
+        assert_eq!(next_available_time(car.id).unwrap(), day(3).as_nanos());
+    }
+
+    #[test]
+    fn owners_are_notified_of_reservations_and_cancellations() {
+        let car = car();
+        set_caller(user(2));
+        let reservation = reserve(&car, &customer("ada"), 1, 2).unwrap();
+        assert!(my_notifications().is_empty());
+        cancel_reservation(reservation.id).unwrap();
+        set_caller(user(1));
+        let inbox = my_notifications();
+        assert_eq!(inbox.len(), 2);
+        assert_eq!(inbox[0].message, _reserved_message(&car, reservation.id));
+        assert_eq!(inbox[1].message, _cancelled_message(&car, reservation.id));
+        assert!(inbox.iter().all(|notification| notification.recipient == user(1) && !notification.read));
+    }
+
+    #[test]
+    fn marking_a_notification_read_flips_its_flag() {
+        let car = car();
+        reserve(&car, &customer("ada"), 1, 2).unwrap();
+        let id = my_notifications()[0].id;
+        set_caller(user(2));
+        assert!(matches!(mark_notification_read(id), Err(Error::NotFound { .. })));
+        set_caller(user(1));
+        assert!(mark_notification_read(id).unwrap().read);
+        assert!(my_notifications()[0].read);
+    }
+
+    #[test]
+    fn truncation_keeps_whole_characters() {
+        assert_eq!(_truncate_bytes("abc", 8), "abc");
+        assert_eq!(_truncate_bytes("😀😀", 5), "😀");
+        assert_eq!(_truncate_bytes("a😀", 4), "a");
+    }
+
+    #[test]
+    fn largest_notification_fits_its_bound() {
+        let car = Car {
+            id: u64::MAX,
+            make: "😀".repeat(64),
+            model: "😀".repeat(64),
+            ..Default::default()
+        };
+        for message in [
+            _reserved_message(&car, u64::MAX),
+            _cancelled_message(&car, u64::MAX),
+        ] {
+            assert_fits(&Notification {
+                id: u64::MAX,
+                recipient: Principal::from_slice(&[u8::MAX; 29]),
+                message,
+                created_at: u64::MAX,
+                read: false,
+            });
+        }
+    }
+
+    #[test]
+    fn names_with_control_characters_are_rejected() {
+        let result = add_car(CarPayload {
+            make: "Toy\nota".to_string(),
+            ..payload()
+        });
+        let Err(Error::ValidationErrors { errors }) = result else {
+            panic!("a make with a newline was accepted");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "make");
+        let car = car();
+        let patch = CarPatch {
+            model: Some("Corolla\0".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(patch_car(car.id, patch, None), Err(Error::ValidationErrors { .. })));
+        let blank = CarPayload {
+            model: "   ".to_string(),
+            ..payload()
+        };
+        assert!(matches!(update_car(car.id, blank, None), Err(Error::ValidationErrors { .. })));
+    }
+
+    #[test]
+    fn names_are_trimmed_and_keep_internal_spaces() {
+        let car = add_car(CarPayload {
+            make: "  Land Rover ".to_string(),
+            model: "Range Rover Sport\t".to_string(),
+            ..payload()
+        })
+        .unwrap();
+        assert_eq!(car.make, "Land Rover");
+        assert_eq!(car.model, "Range Rover Sport");
+    }
+
+    fn snapshot_car(id: u64) -> Car {
+        Car {
+            id,
+            make: "Toyota".to_string(),
+            model: "Corolla".to_string(),
+            year: 2020,
+            color: "red".to_string(),
+            owner: user(5).to_string(),
+            currency: DEFAULT_CURRENCY.to_string(),
+            weekend_multiplier: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn imported_cars_keep_their_ids_and_advance_the_counter() {
+        set_caller(admin());
+        assert_eq!(import_cars(vec![snapshot_car(40), snapshot_car(7)]).unwrap(), 2);
+        let imported = get_car(40).unwrap();
+        assert_eq!(imported.owner, user(5).to_string());
+        assert!(get_car(7).is_ok());
+        // The next car is numbered after the highest imported id
+        assert_eq!(car().id, 41);
+    }
+
+    #[test]
+    fn one_invalid_car_aborts_the_whole_import() {
+        set_caller(admin());
+        let invalid = Car {
+            year: 1700,
+            ..snapshot_car(8)
+        };
+        let Err(Error::ValidationErrors { errors }) = import_cars(vec![snapshot_car(7), invalid]) else {
+            panic!("an invalid car was imported");
+        };
+        assert!(errors[0].field.starts_with("cars[1]."));
+        assert!(get_car(7).is_err());
+        assert_eq!(car().id, 0);
+        // Duplicate ids and an id with no room after it are refused too
+        set_caller(admin());
+        assert!(import_cars(vec![snapshot_car(7), snapshot_car(7)]).is_err());
+        assert!(import_cars(vec![snapshot_car(u64::MAX)]).is_err());
+        assert!(get_car(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn only_admins_import_cars() {
+        assert!(matches!(import_cars(vec![snapshot_car(7)]), Err(Error::NotAuthorized { .. })));
+    }
+
+    // Empties every store a snapshot covers, as on a freshly installed canister
+    fn wipe() {
+        for car in generate_report() {
+            CAR_STORAGE.with(|service| service.borrow_mut().remove(&car.id));
+        }
+        for customer in _get_customers() {
+            let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+            StableBTreeMap::<u64, Customer, Memory>::init(customer_storage).remove(&customer.id);
+        }
+        for reservation in _get_reservations() {
+            let reservation_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)));
+            StableBTreeMap::<u64, Reservation, Memory>::init(reservation_storage).remove(&reservation.id);
+        }
+        let archived: Vec<u64> = RESERVATION_ARCHIVE.with(|service| service.borrow().iter().map(|(id, _)| id).collect());
+        for id in archived {
+            RESERVATION_ARCHIVE.with(|service| service.borrow_mut().remove(&id));
+        }
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(0).unwrap());
+        ID_RESERVATION_COUNTER.with(|counter| counter.borrow_mut().set(0).unwrap());
+    }
+
+    #[test]
+    fn export_all_round_trips_through_import_all() {
+        let (car, other) = (car(), car());
+        let customer = customer("ada");
+        reserve(&car, &customer, 1, 2).unwrap();
+        let cancelled = reserve(&other, &customer, 1, 2).unwrap();
+        cancel_reservation(cancelled.id).unwrap();
+        set_caller(admin());
+        let exported = export_all().unwrap();
+        assert_eq!(exported.cars.len(), 2);
+        assert_eq!(exported.reservations.len(), 2);
+
+        wipe();
+        assert!(export_all().unwrap().cars.is_empty());
+        import_all(exported.clone()).unwrap();
+        assert_eq!(Encode!(&export_all().unwrap()).unwrap(), Encode!(&exported).unwrap());
+        assert!(get_reservation(cancelled.id).is_err());
+        // New records continue after the imported ids
+        assert!(add_customer("bob".to_string(), Contact::Email("bob@example.com".to_string())).unwrap().id > customer.id);
+    }
+
+    #[test]
+    fn snapshots_with_dangling_reservations_change_nothing() {
+        let (car, customer) = (car(), customer("ada"));
+        reserve(&car, &customer, 1, 2).unwrap();
+        set_caller(admin());
+        let mut snapshot = export_all().unwrap();
+        wipe();
+        snapshot.customers.clear();
+        let Err(Error::ValidationErrors { errors }) = import_all(snapshot.clone()) else {
+            panic!("a reservation without its customer was imported");
+        };
+        assert_eq!(errors[0].field, "reservations[0].customer_id");
+        assert!(export_all().unwrap().cars.is_empty());
+        set_caller(user(1));
+        assert!(matches!(import_all(snapshot), Err(Error::NotAuthorized { .. })));
+    }
+
+    #[test]
+    fn reservations_count_towards_the_car_bookings() {
+        let (car, customer) = (car(), customer("ada"));
+        assert_eq!(car.total_bookings, 0);
+        assert_eq!(car.last_booked_at, None);
+        reserve(&car, &customer, 1, 2).unwrap();
+        set_time(NOW + 60);
+        reserve(&car, &customer, 2, 3).unwrap();
+        let car = get_car(car.id).unwrap();
+        assert_eq!(car.total_bookings, 2);
+        assert_eq!(car.last_booked_at, Some(NOW + 60));
+        assert_eq!(generate_report()[0].total_bookings, 2);
+    }
+
+    #[test]
+    fn customers_who_never_reserved_are_churn_candidates() {
+        let car = car();
+        let (ada, bob, cy) = (customer("ada"), customer("bob"), customer("cy"));
+        reserve(&car, &ada, 1, 2).unwrap();
+        // A cancelled reservation still counts
+        let cancelled = reserve(&car, &cy, 2, 3).unwrap();
+        cancel_reservation(cancelled.id).unwrap();
+        assert_eq!(customer_ids(customers_without_reservations()), [bob.id]);
+    }
+
+    #[test]
+    fn rapid_car_creation_is_rate_limited() {
+        set_caller(admin());
+        set_car_rate_limit(3, 1_000).unwrap();
+        set_caller(user(1));
+        for _ in 0..3 {
+            car();
+        }
+        assert!(matches!(
+            add_car(payload()),
+            Err(Error::RateLimited { retry_after }) if retry_after == NOW + 1_000
+        ));
+        // Other callers have their own allowance
+        set_caller(user(2));
+        assert!(add_car(payload()).is_ok());
+    }
+
+    #[test]
+    fn car_creation_resumes_once_the_window_passes() {
+        set_caller(admin());
+        set_car_rate_limit(2, 1_000).unwrap();
+        set_caller(user(1));
+        car();
+        set_time(NOW + 500);
+        car();
+        assert!(add_car(payload()).is_err());
+        // The first creation leaves the window, freeing one slot
+        set_time(NOW + 1_000);
+        assert!(add_car(payload()).is_ok());
+        assert!(add_car(payload()).is_err());
+    }
+
+    #[test]
+    fn car_detail_splits_current_and_upcoming_reservations() {
+        let (car, customer) = (car(), customer("ada"));
+        let current = make_reservation(car.id, customer.id, Timestamp(NOW), Some(day(1)), None, None).unwrap();
+        let later = reserve(&car, &customer, 3, 4).unwrap();
+        let sooner = reserve(&car, &customer, 1, 2).unwrap();
+        let detail = car_detail(car.id).unwrap();
+        assert_eq!(detail.car.id, car.id);
+        assert_eq!(detail.current_reservation.map(|reservation| reservation.id), Some(current.id));
+        let upcoming: Vec<u64> = detail.upcoming_reservations.iter().map(|reservation| reservation.id).collect();
+        assert_eq!(upcoming, [sooner.id, later.id]);
+        assert!(matches!(car_detail(999), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn timestamps_convert_from_seconds_and_milliseconds() {
+        assert_eq!(Timestamp::from_secs(1_700_000_000), Timestamp(NOW));
+        assert_eq!(Timestamp::from_millis(1_700_000_000_000), Timestamp(NOW));
+        assert_eq!(Timestamp::from_secs(u64::MAX), Timestamp(u64::MAX));
+        assert_eq!(Timestamp::now().as_nanos(), NOW);
+    }
+
+    #[test]
+    fn timestamps_are_plain_nat64_on_the_wire() {
+        assert_eq!(Encode!(&Timestamp(NOW)).unwrap(), Encode!(&NOW).unwrap());
+        assert_eq!(Decode!(&Encode!(&NOW).unwrap(), Timestamp).unwrap(), Timestamp(NOW));
+    }
+
+    #[test]
+    fn windows_that_look_like_seconds_or_milliseconds_are_rejected() {
+        let (car, customer) = (car(), customer("ada"));
+        let in_seconds = Timestamp(1_700_000_000);
+        let in_millis = Timestamp(1_700_000_000_000);
+        for start in [in_seconds, in_millis] {
+            let result = make_reservation(car.id, customer.id, start, Some(day(1)), None, None);
+            let Err(Error::ValidationErrors { errors }) = result else {
+                panic!("a window in the wrong unit was accepted");
+            };
+            assert_eq!(errors[0].field, "start_time");
+        }
+        assert!(available_cars(in_seconds, Timestamp(1_700_086_400)).is_err());
+        assert!(_validate_reservation_window(MIN_PLAUSIBLE_TIMESTAMP, day(0)).is_ok());
+    }
+
+    #[test]
+    fn upcoming_reservations_fall_within_the_lead_time() {
+        let (car, ada) = (car(), customer("ada"));
+        let soon = reserve(&car, &ada, 1, 2).unwrap();
+        let sooner = make_reservation(car.id, ada.id, Timestamp(NOW + 60), Some(day(1)), None, None).unwrap();
+        reserve(&car, &ada, 3, 4).unwrap();
+        let ids = |reservations: Vec<Reservation>| -> Vec<u64> {
+            reservations.iter().map(|reservation| reservation.id).collect()
+        };
+        assert_eq!(ids(upcoming_reservations(2 * NANOS_PER_DAY)), [sooner.id, soon.id]);
+        assert_eq!(ids(upcoming_reservations(60)), [sooner.id]);
+        // Other callers see only reservations of customers they created
+        set_caller(user(2));
+        assert!(upcoming_reservations(2 * NANOS_PER_DAY).is_empty());
+        set_caller(admin());
+        assert_eq!(upcoming_reservations(2 * NANOS_PER_DAY).len(), 2);
+    }
+
+    fn paid(reservation: Reservation) -> Reservation {
+        let reservation = Reservation {
+            paid_block: Some(reservation.id),
+            ..reservation
+        };
+        do_insert_reservation(&reservation);
+        reservation
+    }
+
+    #[test]
+    fn owner_revenue_sums_paid_reservations_of_the_callers_cars() {
+        let (first, second) = (priced_car(100), priced_car(250));
+        set_caller(user(2));
+        let theirs = priced_car(1_000);
+        set_caller(user(1));
+        let ada = customer("ada");
+        paid(reserve(&first, &ada, 1, 2).unwrap());
+        paid(reserve(&second, &ada, 2, 4).unwrap());
+        paid(reserve(&theirs, &ada, 1, 2).unwrap());
+        // Unpaid reservations don't count
+        reserve(&first, &ada, 2, 3).unwrap();
+        assert_eq!(owner_revenue(day(0), day(5)).unwrap(), 600);
+        // Nor do windows reaching outside the period
+        assert_eq!(owner_revenue(day(0), day(3)).unwrap(), 100);
+        set_caller(user(3));
+        assert_eq!(owner_revenue(day(0), day(5)).unwrap(), 0);
+    }
+
+    fn rental_policy(min_days: u64, max_days: u64) {
+        let caller = caller();
+        set_caller(admin());
+        set_rental_policy(min_days * NANOS_PER_DAY, max_days * NANOS_PER_DAY).unwrap();
+        set_caller(caller);
+    }
+
+    #[test]
+    fn reservations_must_respect_the_rental_duration() {
+        rental_policy(1, 3);
+        let (car, ada) = (car(), customer("ada"));
+        let short = make_reservation(car.id, ada.id, day(1), Some(Timestamp(day(2).as_nanos() - 1)), None, None);
+        assert!(matches!(short, Err(Error::InvalidDuration { .. })));
+        assert!(matches!(reserve(&car, &ada, 1, 5), Err(Error::InvalidDuration { .. })));
+        let reservation = reserve(&car, &ada, 1, 4).unwrap();
+        // Modified windows are held to the same bounds
+        assert!(matches!(
+            modify_reservation(reservation.id, day(1), day(5)),
+            Err(Error::InvalidDuration { .. })
+        ));
+        assert!(modify_reservation(reservation.id, day(1), day(2)).is_ok());
+    }
+
+    #[test]
+    fn rental_policies_need_a_consistent_range() {
+        set_caller(admin());
+        assert!(set_rental_policy(2, 1).is_err());
+        assert_eq!(get_rental_policy().max_rental_ns, u64::MAX);
+        set_caller(user(1));
+        assert!(matches!(set_rental_policy(0, 1), Err(Error::NotAuthorized { .. })));
+    }
+
+    #[test]
+    fn fleet_taxonomy_groups_models_by_make() {
+        for (make, model) in [
+            ("Toyota", "Corolla"),
+            ("Honda", "Civic"),
+            ("toyota", "Yaris"),
+            ("TOYOTA", "corolla"),
+            ("Honda", "Accord"),
+        ] {
+            add_car(CarPayload {
+                make: make.to_string(),
+                model: model.to_string(),
+                ..payload()
+            })
+            .unwrap();
+        }
+        // Soft-deleted cars are left out
+        let deleted = add_car(CarPayload {
+            make: "Ford".to_string(),
+            ..payload()
+        })
+        .unwrap();
+        delete_car(deleted.id).unwrap();
+        let taxonomy: Vec<(String, Vec<String>)> = fleet_taxonomy()
+            .into_iter()
+            .map(|entry| (entry.make, entry.models))
+            .collect();
+        assert_eq!(
+            taxonomy,
+            [
+                ("Honda".to_string(), vec!["Accord".to_string(), "Civic".to_string()]),
+                ("Toyota".to_string(), vec!["Corolla".to_string(), "Yaris".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn loyalty_tiers_start_at_their_thresholds() {
+        set_caller(admin());
+        set_loyalty_thresholds(2, 4).unwrap();
+        set_caller(user(1));
+        let (car, ada) = (car(), customer("ada"));
+        let mut tiers = Vec::new();
+        for n in 0..5 {
+            tiers.push(customer_tier(ada.id).unwrap());
+            reserve(&car, &ada, n + 1, n + 2).unwrap();
+        }
+        tiers.push(customer_tier(ada.id).unwrap());
+        use LoyaltyTier::*;
+        assert!(tiers == [Bronze, Bronze, Silver, Silver, Gold, Gold]);
+    }
+
+    #[test]
+    fn cancelled_reservations_do_not_count_towards_a_tier() {
+        set_caller(admin());
+        set_loyalty_thresholds(1, 2).unwrap();
+        set_caller(user(1));
+        let (car, ada) = (car(), customer("ada"));
+        let cancelled = reserve(&car, &ada, 1, 2).unwrap();
+        cancel_reservation(cancelled.id).unwrap();
+        assert!(customer_tier(ada.id).unwrap() == LoyaltyTier::Bronze);
+        assert!(matches!(customer_tier(999), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn exhausted_counters_refuse_to_wrap() {
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(u64::MAX - 1).unwrap());
+        assert_eq!(_next_id(&ID_COUNTER).unwrap(), u64::MAX - 1);
+        assert!(matches!(_next_id(&ID_COUNTER), Err(Error::IdSpaceExhausted)));
+        assert_eq!(ID_COUNTER.with(|counter| *counter.borrow().get()), u64::MAX);
+        assert!(matches!(add_car(payload()), Err(Error::IdSpaceExhausted)));
+        assert!(matches!(
+            add_customer("ada".to_string(), Contact::Email("ada@example.com".to_string())),
+            Err(Error::IdSpaceExhausted)
+        ));
+    }
+
+    #[test]
+    fn never_reserved_cars_are_idle_inventory() {
+        let (reserved, idle, legacy) = (car(), car(), car());
+        reserve(&reserved, &customer("ada"), 1, 2).unwrap();
+        // A car booked before total_bookings was tracked
+        let archived = Reservation {
+            car_id: legacy.id,
+            ..reserve(&reserved, &self::customer("bob"), 2, 3).unwrap()
+        };
+        RESERVATION_ARCHIVE.with(|service| service.borrow_mut().insert(archived.id, archived));
+        assert_eq!(ids(never_reserved_cars()), [idle.id]);
+    }
+
+    #[test]
+    fn total_matches_counts_every_page() {
+        for year in [2015, 2018, 2021, 2024] {
+            add_car(CarPayload { year, ..payload() }).unwrap();
+        }
+        add_car(CarPayload {
+            make: "Honda".to_string(),
+            ..payload()
+        })
+        .unwrap();
+        let toyotas = || CarFilter {
+            make: Some("toyota".to_string()),
+            ..Default::default()
+        };
+        let first = query_cars(toyotas(), CarSortKey::Year, true, 0, 3);
+        assert_eq!(first.total_matches, 4);
+        let years: Vec<u32> = first.items.iter().map(|car| car.year).collect();
+        assert_eq!(years, [2024, 2021, 2018]);
+        let second = query_cars(toyotas(), CarSortKey::Year, true, 3, 3);
+        assert_eq!(second.total_matches, 4);
+        assert_eq!(second.items.len(), 1);
+        let beyond = query_cars(toyotas(), CarSortKey::Year, true, 10, 3);
+        assert_eq!(beyond.total_matches, 4);
+        assert!(beyond.items.is_empty());
+    }
+
+    // Four bytes per character, the most a string of a given length can take
+    fn widest(chars: usize) -> String {
+        "😀".repeat(chars)
+    }
+
+    #[test]
+    fn largest_car_fits_its_bound() {
+        let car = Car {
+            id: u64::MAX,
+            make: widest(64),
+            model: widest(64),
+            year: u32::MAX,
+            color: widest(32),
+            created_at: u64::MAX,
+            updated_at: Some(u64::MAX),
+            owner: widest(128),
+            status: CarStatus::Maintenance,
+            fuel_type: FuelType::Electric,
+            transmission: Transmission::Automatic,
+            image_url: Some(format!("https://{}", widest(248))),
+            daily_price: u64::MAX,
+            currency: "XAU".to_string(),
+            weekend_multiplier: 10.0,
+            weekly_price: Some(u64::MAX),
+            monthly_price: Some(u64::MAX),
+            deposit: u64::MAX,
+            total_bookings: u64::MAX,
+            last_booked_at: Some(u64::MAX),
+            grace_until: Some(u64::MAX),
+            mileage: u64::MAX,
+            tags: vec![widest(32); 10],
+            latitude: Some(-90.0),
+            longitude: Some(-180.0),
+            version: u64::MAX,
+        };
+        assert_fits(&car);
+        assert_fits(&CarRecord::new(&car));
+    }
+
+    #[test]
+    fn a_corrupt_car_is_skipped_by_listings() {
+        let kept = car();
+        CAR_STORAGE.with(|service| service.borrow_mut().insert(99, CarRecord(vec![0xde, 0xad])));
+        let ids = |cars: Vec<Car>| cars.iter().map(|car| car.id).collect::<Vec<_>>();
+        assert_eq!(ids(generate_report()), [kept.id]);
+        assert_eq!(ids(search_cars(CarFilter::default())), [kept.id]);
+        assert!(matches!(get_car(99), Err(Error::CorruptRecord { id: 99 })));
+        assert_eq!(get_car(kept.id).unwrap().id, kept.id);
+    }
+
+    #[test]
+    fn reservation_counts_put_the_busiest_car_first() {
+        let customer = customer("ada");
+        let (idle, once, twice) = (car(), car(), car());
+        reserve(&once, &customer, 1, 2).unwrap();
+        reserve(&twice, &customer, 1, 2).unwrap();
+        reserve(&twice, &customer, 3, 4).unwrap();
+        let counts = reservation_counts();
+        assert_eq!(counts, [(twice.id, 2), (once.id, 1)]);
+        assert!(counts.iter().all(|(car_id, _)| *car_id != idle.id));
+    }
+
+    #[test]
+    fn a_cancelled_car_rests_for_the_grace_period() {
+        let customer = customer("ada");
+        let car = car();
+        set_caller(admin());
+        set_grace_period(NANOS_PER_DAY).unwrap();
+        set_caller(user(1));
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        set_time(day(0).as_nanos());
+        cancel_reservation(reservation.id).unwrap();
+        // Only windows starting a day after the cancellation are bookable
+        assert!(matches!(reserve(&car, &customer, 0, 2), Err(Error::CarUnavailable { .. })));
+        assert_eq!(next_available_time(car.id).unwrap(), day(1).as_nanos());
+        assert!(available_cars(day(0), day(2)).unwrap().is_empty());
+        set_time(day(1).as_nanos());
+        reserve(&car, &customer, 1, 2).unwrap();
+    }
+
+    #[test]
+    fn without_a_grace_period_a_cancelled_car_is_bookable_at_once() {
+        let customer = customer("ada");
+        let car = car();
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        cancel_reservation(reservation.id).unwrap();
+        reserve(&car, &customer, 1, 2).unwrap();
+    }
+
+    #[test]
+    fn merging_moves_reservations_to_the_kept_customer() {
+        let (keep, duplicate) = (customer("ada"), customer("ada lovelace"));
+        let car = car();
+        let reservation = reserve(&car, &duplicate, 1, 2).unwrap();
+        set_caller(admin());
+        assert!(merge_customers(keep.id, keep.id).is_err());
+        assert!(matches!(merge_customers(keep.id, 99), Err(Error::NotFound { .. })));
+        assert_eq!(merge_customers(keep.id, duplicate.id).unwrap().id, keep.id);
+        assert!(_get_customer(&duplicate.id).is_none());
+        assert_eq!(_get_reservation(&reservation.id).unwrap().customer_id, keep.id);
+        assert!(_get_customer_reservations(&duplicate.id).is_empty());
+    }
+
+    #[test]
+    fn only_admins_merge_customers() {
+        let (keep, duplicate) = (customer("ada"), customer("ada lovelace"));
+        assert!(matches!(
+            merge_customers(keep.id, duplicate.id),
+            Err(Error::NotAuthorized { .. })
+        ));
+        assert!(_get_customer(&duplicate.id).is_some());
+    }
+
+    #[test]
+    fn largest_blackout_fits_its_bound() {
+        assert_fits(&Blackout {
+            id: u64::MAX,
+            car_id: u64::MAX,
+            start: Timestamp(u64::MAX),
+            end: Timestamp(u64::MAX),
+            reason: "r".repeat(MAX_REASON_LEN),
+        });
+    }
+
+    #[test]
+    fn reservations_touching_a_blackout_are_refused() {
+        let customer = customer("ada");
+        let car = car();
+        add_blackout(car.id, day(2), day(4), "family trip".to_string()).unwrap();
+        // Overlapping blackouts are allowed and both count
+        add_blackout(car.id, day(3), day(6), "service".to_string()).unwrap();
+        assert!(matches!(reserve(&car, &customer, 1, 3), Err(Error::CarUnavailable { .. })));
+        assert!(matches!(reserve(&car, &customer, 5, 7), Err(Error::CarUnavailable { .. })));
+        assert!(available_cars(day(1), day(3)).unwrap().is_empty());
+        // Windows ending as a blackout starts or starting as it ends are fine
+        let before = reserve(&car, &customer, 1, 2).unwrap();
+        reserve(&car, &customer, 6, 7).unwrap();
+        assert!(matches!(
+            modify_reservation(before.id, day(1), day(3)),
+            Err(Error::CarUnavailable { .. })
+        ));
+    }
+
+    #[test]
+    fn blackouts_are_given_in_nanoseconds_by_the_owner() {
+        let car = car();
+        let in_seconds = Timestamp(day(2).as_nanos() / 1_000_000_000);
+        assert!(matches!(
+            add_blackout(car.id, in_seconds, day(4), String::new()),
+            Err(Error::ValidationErrors { .. })
+        ));
+        set_caller(user(2));
+        assert!(matches!(
+            add_blackout(car.id, day(2), day(4), String::new()),
+            Err(Error::NotAuthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn car_summaries_leave_out_the_owner_and_timestamps() {
+        let wire = <CarSummary as candid::CandidType>::ty().to_string();
+        for field in ["owner", "created_at", "updated_at", "total_bookings"] {
+            assert!(!wire.contains(field), "{} is sent in {}", field, wire);
+        }
+        let (available, booked) = (car(), booked_car());
+        let summaries = list_car_summaries(0, 10);
+        let statuses: Vec<(u64, CarStatus)> =
+            summaries.into_iter().map(|summary| (summary.id, summary.status)).collect();
+        assert!(statuses == [(available.id, CarStatus::Available), (booked.id, CarStatus::Booked)]);
+        assert_eq!(list_car_summaries(1, 10).len(), 1);
+    }
+
+    #[test]
+    fn reserve_first_available_takes_the_free_matching_car() {
+        let customer = customer("ada");
+        let (taken, free) = (car(), car());
+        let honda = add_car(CarPayload {
+            make: "Honda".to_string(),
+            ..payload()
+        })
+        .unwrap();
+        reserve(&taken, &customer, 1, 3).unwrap();
+        let toyotas = || CarFilter {
+            make: Some("Toyota".to_string()),
+            ..Default::default()
+        };
+        let reservation = reserve_first_available(toyotas(), customer.id, day(2), day(4)).unwrap();
+        assert_eq!(reservation.car_id, free.id);
+        // Both Toyotas are now taken and the Honda doesn't match
+        assert!(matches!(
+            reserve_first_available(toyotas(), customer.id, day(2), day(3)),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(_get_car_reservations(&honda.id).is_empty());
+    }
+
+    #[test]
+    fn largest_customer_fits_its_bound() {
+        assert_fits(&Customer {
+            id: u64::MAX,
+            name: widest(64),
+            contact: Contact::Email(widest(128)),
+            created_at: u64::MAX,
+            updated_at: Some(u64::MAX),
+            created_by: Some(Principal::from_slice(&[u8::MAX; 29])),
+            contact_visibility: ContactVisibility::OwnerOnly,
+        });
+    }
+
+    #[test]
+    fn over_long_fields_are_rejected_with_a_field_error() {
+        let too_long = |field: &str, result: Result<Car, Error>| {
+            let Err(Error::ValidationErrors { errors }) = result else {
+                panic!("an over-long {} was accepted", field);
+            };
+            assert!(errors.iter().any(|error| error.field == field), "{:?}", errors);
+        };
+        too_long(
+            "model",
+            add_car(CarPayload {
+                model: "x".repeat(65),
+                ..payload()
+            }),
+        );
+        let car = car();
+        too_long(
+            "color",
+            patch_car(
+                car.id,
+                CarPatch {
+                    color: Some("x".repeat(33)),
+                    ..Default::default()
+                },
+                None,
+            ),
+        );
+        let Err(Error::ValidationErrors { errors }) =
+            add_customer("x".repeat(65), Contact::Email("ada@example.com".to_string()))
+        else {
+            panic!("an over-long name was accepted");
+        };
+        assert_eq!(errors[0].field, "name");
+        // Limits count characters, not bytes
+        add_car(CarPayload {
+            make: widest(64),
+            ..payload()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn reservations_without_an_end_last_the_default_rental() {
+        let customer = customer("ada");
+        let car = car();
+        let day_long = make_reservation(car.id, customer.id, day(1), None, None, None).unwrap();
+        assert_eq!(day_long.end_time, day(2));
+        set_caller(admin());
+        set_default_rental(3 * NANOS_PER_DAY).unwrap();
+        set_caller(user(1));
+        let longer = make_reservation(car.id, customer.id, day(2), None, None, None).unwrap();
+        assert_eq!(longer.end_time, day(5));
+        let explicit = make_reservation(car.id, customer.id, day(5), Some(day(6)), None, None).unwrap();
+        assert_eq!(explicit.end_time, day(6));
+    }
+
+    #[test]
+    fn reservation_status_follows_the_clock() {
+        let customer = customer("ada");
+        let car = car();
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        let cancelled = reserve(&car, &customer, 3, 4).unwrap();
+        cancel_reservation(cancelled.id).unwrap();
+        let status = || reservation_status(reservation.id).unwrap();
+        assert!(status() == ReservationStatus::Upcoming);
+        set_time(day(1).as_nanos());
+        assert!(status() == ReservationStatus::Active);
+        // The end is exclusive
+        set_time(day(2).as_nanos());
+        assert!(status() == ReservationStatus::Completed);
+        assert!(reservation_status(cancelled.id).unwrap() == ReservationStatus::Cancelled);
+        assert!(matches!(reservation_status(99), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn largest_owner_contact_fits_its_bound() {
+        assert_fits(&OwnerContact(widest(128)));
+    }
+
+    #[test]
+    fn only_renters_read_the_owner_contact() {
+        let car = add_car(CarPayload {
+            owner_contact: Some("+1 (555) 010-2030".to_string()),
+            ..payload()
+        })
+        .unwrap();
+        assert_eq!(get_owner_contact(car.id).unwrap(), "+1 (555) 010-2030");
+        set_caller(user(2));
+        assert!(matches!(get_owner_contact(car.id), Err(Error::NotAuthorized { .. })));
+        reserve(&car, &customer("ada"), 1, 2).unwrap();
+        assert_eq!(get_owner_contact(car.id).unwrap(), "+1 (555) 010-2030");
+        set_caller(user(3));
+        assert!(matches!(get_owner_contact(car.id), Err(Error::NotAuthorized { .. })));
+    }
+
+    #[test]
+    fn owner_contacts_must_be_an_email_or_a_phone_number() {
+        let result = add_car(CarPayload {
+            owner_contact: Some("call me".to_string()),
+            ..payload()
+        });
+        assert!(matches!(result, Err(Error::ValidationErrors { .. })));
+        add_car(CarPayload {
+            owner_contact: Some("owner@example.com".to_string()),
+            ..payload()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn cars_are_booked_only_while_a_reservation_is_under_way() {
+        let customer = customer("ada");
+        let car = car();
+        reserve(&car, &customer, 1, 2).unwrap();
+        let status = || get_car(car.id).unwrap().status;
+        assert!(status() == CarStatus::Available);
+        // The next update call after the start books the car
+        set_time(day(1).as_nanos());
+        _archive_expired_reservations();
+        assert!(status() == CarStatus::Booked);
+        set_time(day(2).as_nanos());
+        _archive_expired_reservations();
+        assert!(status() == CarStatus::Available);
+    }
+
+    #[test]
+    fn reconcile_fixes_drifted_statuses() {
+        let customer = customer("ada");
+        let (held, idle, resting) = (car(), car(), car());
+        reserve(&held, &customer, 0, 1).unwrap();
+        set_caller(admin());
+        set_cars_status(vec![resting.id], CarStatus::Maintenance).unwrap();
+        // Statuses written by earlier versions that didn't keep them in step
+        for (car, status) in [(&held, CarStatus::Available), (&idle, CarStatus::Booked)] {
+            let mut drifted = _get_car(&car.id).unwrap();
+            drifted.status = status;
+            do_insert_car(&mut drifted).unwrap();
+        }
+        let report = reconcile_booking_flags().unwrap();
+        assert_eq!(report.cars_checked, 3);
+        assert_eq!(report.cars_changed, 2);
+        assert_eq!(report.changed_ids, [held.id, idle.id]);
+        assert!(get_car(held.id).unwrap().status == CarStatus::Booked);
+        assert!(get_car(idle.id).unwrap().status == CarStatus::Available);
+        assert!(get_car(resting.id).unwrap().status == CarStatus::Maintenance);
+        assert_eq!(reconcile_booking_flags().unwrap().cars_changed, 0);
+    }
+
+    #[test]
+    fn returns_due_in_a_range_are_listed_by_end_time() {
+        let customer = customer("ada");
+        let (first, second) = (car(), car());
+        let late = reserve(&first, &customer, 0, 3).unwrap();
+        let early = reserve(&second, &customer, 1, 2).unwrap();
+        let outside = reserve(&second, &customer, 4, 5).unwrap();
+        let ids = |reservations: Vec<Reservation>| {
+            reservations.iter().map(|reservation| reservation.id).collect::<Vec<_>>()
+        };
+        // Both bounds are inclusive
+        assert_eq!(ids(reservations_ending_between(day(2), day(3)).unwrap()), [early.id, late.id]);
+        assert!(!ids(reservations_ending_between(day(0), day(4)).unwrap()).contains(&outside.id));
+        // Completed reservations are still due back
+        set_time(day(2).as_nanos());
+        _archive_expired_reservations();
+        assert_eq!(ids(reservations_ending_between(day(2), day(2)).unwrap()), [early.id]);
+        assert!(reservations_ending_between(day(3), day(2)).is_err());
+        set_caller(user(2));
+        assert!(reservations_ending_between(day(0), day(5)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_out_and_in_track_the_distance_driven() {
+        let customer = customer("ada");
+        let car = car();
+        let reservation = reserve(&car, &customer, 1, 3).unwrap();
+        set_time(day(1).as_nanos());
+        let out = check_out(reservation.id, 12_000).unwrap();
+        assert_eq!(out.checkout_odometer, Some(12_000));
+        assert!(get_car(car.id).unwrap().status == CarStatus::Booked);
+        assert!(matches!(cancel_reservation(reservation.id), Err(Error::InvalidState { .. })));
+        // A checked-out car stays out past its window until it comes back
+        set_time(day(4).as_nanos());
+        _archive_expired_reservations();
+        assert!(get_car(car.id).unwrap().status == CarStatus::Booked);
+        let back = check_in(reservation.id, 12_345).unwrap();
+        assert!(back.status == ReservationStatus::Completed);
+        assert_eq!(back.checkin_odometer, Some(12_345));
+        assert_eq!(back.ended_at, Some(day(4).as_nanos()));
+        let car = get_car(car.id).unwrap();
+        assert_eq!(car.mileage, 345);
+        assert!(car.status == CarStatus::Available);
+    }
+
+    #[test]
+    fn check_in_refuses_a_lower_odometer() {
+        let customer = customer("ada");
+        let car = car();
+        let reservation = reserve(&car, &customer, 0, 2).unwrap();
+        assert!(matches!(check_in(reservation.id, 100), Err(Error::InvalidState { .. })));
+        check_out(reservation.id, 500).unwrap();
+        assert!(matches!(check_in(reservation.id, 499), Err(Error::ValidationErrors { .. })));
+        assert_eq!(get_car(car.id).unwrap().mileage, 0);
+        check_in(reservation.id, 500).unwrap();
+    }
+
+    #[test]
+    fn cars_are_checked_out_only_around_the_window() {
+        let customer = customer("ada");
+        let car = car();
+        let early = reserve(&car, &customer, 1, 2).unwrap();
+        let late = reserve(&car, &customer, 3, 4).unwrap();
+        let pickup = day(1).as_nanos() - EARLY_PICKUP_NS;
+        set_time(pickup - 1);
+        assert!(matches!(check_out(early.id, 0), Err(Error::InvalidState { .. })));
+        // A renter arriving within the allowance gets the car
+        set_time(pickup);
+        assert_eq!(check_out(early.id, 0).unwrap().checked_out_at, Some(pickup));
+        // Once the window is over the reservation is completed
+        set_time(day(4).as_nanos());
+        assert!(matches!(check_out(late.id, 0), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn only_staff_or_the_owner_hand_cars_over() {
+        let customer = customer("ada");
+        let car = car();
+        let reservation = reserve(&car, &customer, 0, 1).unwrap();
+        set_caller(user(2));
+        assert!(matches!(check_out(reservation.id, 0), Err(Error::NotAuthorized { .. })));
+        set_caller(admin());
+        add_staff(user(2)).unwrap();
+        set_caller(user(2));
+        check_out(reservation.id, 0).unwrap();
+    }
+
+    #[test]
+    fn store_failures_become_internal_errors() {
+        // A cell refusing a write, as when stable memory can't grow
+        let refused: Result<u64, Error> = Err("out of memory")
+            .map_err(|error| _internal_error("cannot increment id counter", error));
+        let Err(Error::Internal { msg }) = refused else {
+            panic!("a failed write wasn't reported as internal");
+        };
+        assert_eq!(msg, "cannot increment id counter: \"out of memory\"");
+    }
+
+    #[test]
+    fn on_time_returns_pay_no_late_fee() {
+        let customer = customer("ada");
+        let car = car();
+        set_caller(admin());
+        set_late_fee_per_day(50).unwrap();
+        set_caller(user(1));
+        let reservation = reserve(&car, &customer, 0, 1).unwrap();
+        check_out(reservation.id, 0).unwrap();
+        set_time(day(1).as_nanos());
+        assert_eq!(check_in(reservation.id, 10).unwrap().late_fee, Some(0));
+        let breakdown = reservation_cost_breakdown(reservation.id).unwrap();
+        assert_eq!(breakdown.late_fee, 0);
+        assert_eq!(breakdown.total, breakdown.base);
+    }
+
+    #[test]
+    fn late_returns_pay_for_every_started_day() {
+        let customer = customer("ada");
+        let car = car();
+        set_caller(admin());
+        set_late_fee_per_day(50).unwrap();
+        set_caller(user(1));
+        let reservation = reserve(&car, &customer, 0, 1).unwrap();
+        check_out(reservation.id, 0).unwrap();
+        // A day and an hour late counts as two days
+        set_time(day(2).as_nanos() + NANOS_PER_DAY / 24);
+        assert_eq!(reservation_cost_breakdown(reservation.id).unwrap().late_fee, 100);
+        assert_eq!(check_in(reservation.id, 10).unwrap().late_fee, Some(100));
+        // The fee is fixed at check-in
+        set_time(day(9).as_nanos());
+        let breakdown = reservation_cost_breakdown(reservation.id).unwrap();
+        assert_eq!(breakdown.late_fee, 100);
+        assert_eq!(breakdown.total, breakdown.base + 100);
+    }
+
+    #[test]
+    fn cars_by_ids_skip_missing_ids_and_keep_the_order() {
+        let (first, second) = (car(), car());
+        let found: Vec<u64> = get_cars_by_ids(vec![second.id, 99, first.id, 100])
+            .iter()
+            .map(|car| car.id)
+            .collect();
+        assert_eq!(found, [second.id, first.id]);
+        // Ids beyond the cap aren't looked up
+        let mut many = vec![99; MAX_PAGE_SIZE as usize];
+        many.push(first.id);
+        assert!(get_cars_by_ids(many).is_empty());
+    }
+
+    #[test]
+    fn reservations_need_the_minimum_lead_time() {
+        let customer = customer("ada");
+        let car = car();
+        let hour = NANOS_PER_DAY / 24;
+        set_caller(admin());
+        set_min_lead_time(hour).unwrap();
+        set_caller(user(1));
+        let in_a_minute = Timestamp(NOW + 60_000_000_000);
+        assert!(matches!(
+            make_reservation(car.id, customer.id, in_a_minute, Some(day(1)), None, None),
+            Err(Error::InsufficientLeadTime { .. })
+        ));
+        let reservation =
+            make_reservation(car.id, customer.id, Timestamp(NOW + hour), Some(day(1)), None, None).unwrap();
+        assert!(matches!(
+            modify_reservation(reservation.id, in_a_minute, day(1)),
+            Err(Error::InsufficientLeadTime { .. })
+        ));
+    }
+
+    fn deposit_car(deposit: u64) -> Car {
+        add_car(CarPayload {
+            deposit: Some(deposit),
+            ..payload()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn completed_reservations_release_their_deposit() {
+        let customer = customer("ada");
+        let held = deposit_car(300);
+        let reservation = reserve(&held, &customer, 0, 1).unwrap();
+        assert_eq!(reservation.deposit_held, Some(300));
+        assert!(reservation.deposit_state == Some(DepositState::Held));
+        check_out(reservation.id, 0).unwrap();
+        let back = check_in(reservation.id, 10).unwrap();
+        assert!(back.deposit_state == Some(DepositState::Released));
+        assert!(matches!(release_deposit(reservation.id), Err(Error::InvalidState { .. })));
+        // Cars without a deposit hold none
+        let free = reserve(&car(), &customer, 1, 2).unwrap();
+        assert!(free.deposit_held.is_none() && free.deposit_state.is_none());
+    }
+
+    #[test]
+    fn forfeited_deposits_keep_the_reason_and_amount() {
+        let customer = customer("ada");
+        let car = deposit_car(300);
+        let reservation = reserve(&car, &customer, 0, 1).unwrap();
+        assert!(forfeit_deposit(reservation.id, " ".to_string()).is_err());
+        set_caller(user(2));
+        assert!(matches!(
+            forfeit_deposit(reservation.id, "dented door".to_string()),
+            Err(Error::NotAuthorized { .. })
+        ));
+        set_caller(user(1));
+        forfeit_deposit(reservation.id, "dented door".to_string()).unwrap();
+        check_out(reservation.id, 0).unwrap();
+        let back = check_in(reservation.id, 10).unwrap();
+        assert_eq!(back.deposit_held, Some(300));
+        assert!(
+            back.deposit_state
+                == Some(DepositState::Forfeited {
+                    reason: "dented door".to_string()
+                })
+        );
+        set_caller(admin());
+        let kinds: Vec<EventKind> = get_events(0, 100).unwrap().into_iter().map(|event| event.kind).collect();
+        assert!(kinds.contains(&EventKind::DepositForfeited));
+        assert!(!kinds.contains(&EventKind::DepositReleased));
+    }
+
+    #[test]
+    fn anyone_may_list_cars_in_open_mode() {
+        assert!(add_car(payload()).is_ok());
+        set_caller(user(2));
+        assert!(add_car(payload()).is_ok());
+    }
+
+    #[test]
+    fn allowlist_mode_admits_only_approved_listers() {
+        set_caller(admin());
+        set_listing_mode(ListingMode::Allowlist).unwrap();
+        add_allowed_lister(user(1)).unwrap();
+        set_caller(user(1));
+        assert!(add_car(payload()).is_ok());
+        assert!(matches!(set_listing_mode(ListingMode::Open), Err(Error::NotAuthorized { .. })));
+        set_caller(user(2));
+        assert!(matches!(add_car(payload()), Err(Error::NotAuthorized { .. })));
+        set_caller(admin());
+        remove_allowed_lister(user(1)).unwrap();
+        assert!(matches!(remove_allowed_lister(user(1)), Err(Error::NotFound { .. })));
+        set_caller(user(1));
+        assert!(matches!(add_car(payload()), Err(Error::NotAuthorized { .. })));
+    }
+
+    #[test]
+    fn blocked_principals_cannot_list_even_when_allowlisted() {
+        set_caller(admin());
+        add_allowed_lister(user(1)).unwrap();
+        block_principal(user(1)).unwrap();
+        set_caller(user(1));
+        assert!(matches!(add_car(payload()), Err(Error::NotAuthorized { .. })));
+        set_caller(admin());
+        set_listing_mode(ListingMode::Allowlist).unwrap();
+        set_caller(user(1));
+        assert!(matches!(add_car(payload()), Err(Error::NotAuthorized { .. })));
+        set_caller(admin());
+        unblock_principal(user(1)).unwrap();
+        let access = get_listing_access().unwrap();
+        assert!(access.mode == ListingMode::Allowlist);
+        assert_eq!(access.allowed_listers, vec![user(1)]);
+        assert!(access.blocked_principals.is_empty());
+        set_caller(user(1));
+        assert!(add_car(payload()).is_ok());
+    }
+
+    #[test]
+    fn average_prices_are_grouped_by_make_ignoring_case() {
+        for (make, daily_price) in [("Toyota", 100), ("toyota", 201), ("Audi", 300), ("AUDI", 500), ("Audi", 0)] {
+            add_car(CarPayload {
+                make: make.to_string(),
+                daily_price,
+                ..payload()
+            })
+            .unwrap();
+        }
+        assert_eq!(
+            avg_price_by_make(),
+            vec![("audi".to_string(), 400), ("toyota".to_string(), 150)]
+        );
+    }
+
+    #[test]
+    fn read_only_mode_refuses_updates_but_serves_queries() {
+        let car = car();
+        set_caller(admin());
+        assert!(!is_read_only());
+        set_read_only(true).unwrap();
+        assert!(is_read_only());
+        set_caller(user(1));
+        assert!(matches!(add_car(payload()), Err(Error::ReadOnlyMode { .. })));
+        assert!(matches!(add_customer("ada".to_string(), Contact::Email("ada@example.com".to_string())), Err(Error::ReadOnlyMode { .. })));
+        assert_eq!(get_car(car.id).unwrap().id, car.id);
+        // Only admins may toggle it, even while updates are refused
+        assert!(matches!(set_read_only(false), Err(Error::NotAuthorized { .. })));
+        set_caller(admin());
+        set_read_only(false).unwrap();
+        set_caller(user(1));
+        assert!(add_car(payload()).is_ok());
+    }
+
+    #[test]
+    fn overlaps_with_the_same_customer_are_told_apart() {
+        let car = car();
+        let ada = customer("ada");
+        let bob = customer("bob");
+        let first = reserve(&car, &ada, 1, 3).unwrap();
+        // Back-to-back is fine for anyone
+        assert!(reserve(&car, &ada, 3, 4).is_ok());
+        assert!(reserve(&car, &bob, 0, 1).is_ok());
+        assert!(matches!(
+            reserve(&car, &ada, 2, 5),
+            Err(Error::SameCustomerOverlap { car_id, conflicting_reservation_id })
+                if car_id == car.id && conflicting_reservation_id == first.id
+        ));
+        assert!(matches!(
+            reserve(&car, &bob, 2, 5),
+            Err(Error::ReservationConflict { conflicting_reservation_id, .. })
+                if conflicting_reservation_id == first.id
+        ));
+    }
+
+    #[test]
+    fn gaps_shorter_than_the_minimum_are_left_out() {
+        let (car, customer) = (car(), customer("ada"));
+        reserve(&car, &customer, 1, 2).unwrap();
+        // A three-day gap, then a half-day one
+        reserve(&car, &customer, 5, 6).unwrap();
+        make_reservation(car.id, customer.id, Timestamp(day(6).as_nanos() + NANOS_PER_DAY / 2), Some(day(7)), None, None)
+            .unwrap();
+        assert_eq!(
+            find_gaps(car.id, day(8), NANOS_PER_DAY).unwrap(),
+            vec![
+                (NOW, day(1).as_nanos()),
+                (day(2).as_nanos(), day(5).as_nanos()),
+                (day(7).as_nanos(), day(8).as_nanos()),
+            ]
+        );
+        assert_eq!(
+            find_gaps(car.id, day(8), 2 * NANOS_PER_DAY).unwrap(),
+            vec![(day(2).as_nanos(), day(5).as_nanos())]
+        );
+        assert!(matches!(find_gaps(404, day(8), 0), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn contacts_are_validated_by_kind() {
+        let rejects = |contact: Contact| {
+            matches!(
+                add_customer("ada".to_string(), contact),
+                Err(Error::ValidationErrors { errors }) if errors[0].field == "contact"
+            )
+        };
+        assert!(rejects(Contact::Email("ada.example.com".to_string())));
+        assert!(rejects(Contact::Email("ada@".to_string())));
+        assert!(rejects(Contact::Phone("4155552671".to_string())));
+        assert!(rejects(Contact::Phone("+0155552671".to_string())));
+        assert!(rejects(Contact::Phone("+1415555267123456".to_string())));
+        assert!(rejects(Contact::Phone("+1 415 555 2671".to_string())));
+        assert!(add_customer("ada".to_string(), Contact::Email("ada@example.com".to_string())).is_ok());
+        let customer = add_customer("bob".to_string(), Contact::Phone("+14155552671".to_string())).unwrap();
+        assert!(customer.contact == Contact::Phone("+14155552671".to_string()));
+    }
+
+    #[test]
+    fn free_text_contacts_are_classified_on_read() {
+        let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+        let mut storage = StableBTreeMap::<u64, BaselineCustomer, Memory>::init(memory);
+        for (id, contact) in [(3, "ada@example.com"), (4, "+1 (415) 555-2671"), (5, "0155 5526")] {
+            storage.insert(
+                id,
+                BaselineCustomer {
+                    id,
+                    name: "ada".to_string(),
+                    contact: contact.to_string(),
+                },
+            );
+        }
+        assert!(get_customer(3).unwrap().contact == Contact::Email("ada@example.com".to_string()));
+        assert!(get_customer(4).unwrap().contact == Contact::Phone("+14155552671".to_string()));
+        assert!(get_customer(5).unwrap().contact == Contact::Phone("01555526".to_string()));
+        // Rewriting a classified customer keeps its contact
+        _migrate_legacy_customers(NOW);
+        assert!(get_customer(4).unwrap().contact == Contact::Phone("+14155552671".to_string()));
+    }
+
+    fn watched(customer: &Customer) -> Vec<u64> {
+        WATCHES.with(|service| {
+            service
+                .borrow()
+                .range((customer.id, 0)..=(customer.id, u64::MAX))
+                .map(|((_, car_id), _)| car_id)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn watched_cars_show_up_once_available() {
+        let customer = customer("ada");
+        let (freed, booked) = (booked_car(), booked_car());
+        watch_car(freed.id, customer.id).unwrap();
+        watch_car(booked.id, customer.id).unwrap();
+        assert!(watched_car_updates().is_empty());
+        set_cars_status(vec![freed.id], CarStatus::Available).unwrap();
+        let updates = watched_car_updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!((updates[0].car_id, updates[0].customer_id), (freed.id, customer.id));
+        assert_eq!(updates[0].watched_since, NOW);
+        // Digests only cover the caller's own customers
+        set_caller(user(2));
+        assert!(watched_car_updates().is_empty());
+        assert!(matches!(unwatch_car(freed.id, customer.id), Err(Error::NotAuthorized { .. })));
+        set_caller(user(1));
+        unwatch_car(freed.id, customer.id).unwrap();
+        assert!(watched_car_updates().is_empty());
+        assert!(matches!(unwatch_car(freed.id, customer.id), Err(Error::NotFound { .. })));
+        set_caller(admin());
+        let kinds: Vec<EventKind> = get_events(0, 100).unwrap().into_iter().map(|event| event.kind).collect();
+        assert!(kinds.contains(&EventKind::CarWatched) && kinds.contains(&EventKind::CarUnwatched));
+    }
+
+    #[test]
+    fn watches_follow_their_customer_and_car() {
+        let (keep, duplicate, gone) = (customer("ada"), customer("ada lovelace"), customer("bob"));
+        let (shared, own, purged) = (car(), car(), car());
+        watch_car(shared.id, keep.id).unwrap();
+        set_time(NOW - 5);
+        watch_car(shared.id, duplicate.id).unwrap();
+        watch_car(own.id, duplicate.id).unwrap();
+        watch_car(purged.id, gone.id).unwrap();
+        watch_car(own.id, gone.id).unwrap();
+        set_time(NOW);
+        set_caller(admin());
+        merge_customers(keep.id, duplicate.id).unwrap();
+        assert_eq!(watched(&keep), vec![shared.id, own.id]);
+        assert!(watched(&duplicate).is_empty());
+        // The earlier of two watches on the same car is kept
+        let since = WATCHES.with(|service| service.borrow().get(&(keep.id, shared.id)).unwrap().created_at);
+        assert_eq!(since, NOW - 5);
+        purge_car(purged.id).unwrap();
+        assert_eq!(watched(&gone), vec![own.id]);
+        delete_customer(gone.id).unwrap();
+        assert!(watched(&gone).is_empty());
+    }
+
+    #[test]
+    fn cancellations_are_charged_by_how_late_they_come() {
+        set_caller(admin());
+        set_cancellation_policy(NANOS_PER_DAY, 40, 100).unwrap();
+        set_caller(user(1));
+        let (car, customer) = (car(), customer("ada"));
+        let early = reserve(&car, &customer, 2, 3).unwrap();
+        let late = reserve(&car, &customer, 1, 2).unwrap();
+        let started = reserve(&car, &customer, 0, 1).unwrap();
+        assert_eq!(cancel_reservation(early.id).unwrap().cancellation_fee, Some(0));
+        set_time(day(1).as_nanos() - 1);
+        assert_eq!(cancel_reservation(late.id).unwrap().cancellation_fee, Some(40));
+        set_time(NOW + 1);
+        let no_show = cancel_reservation(started.id).unwrap();
+        assert_eq!(no_show.cancellation_fee, Some(100));
+        assert!(no_show.status == ReservationStatus::Cancelled);
+    }
+
+    #[test]
+    fn only_the_booker_the_creator_or_staff_cancel() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 1, 2).unwrap();
+        set_caller(user(2));
+        assert!(matches!(cancel_reservation(reservation.id), Err(Error::NotAuthorized { .. })));
+        assert!(get_reservation(reservation.id).is_ok());
+        set_caller(admin());
+        add_staff(user(3)).unwrap();
+        set_caller(user(3));
+        assert!(cancel_reservation(reservation.id).is_ok());
+    }
+
+    #[test]
+    fn callers_see_only_the_customers_they_created() {
+        let (ada, grace) = (customer("ada"), customer("grace"));
+        set_caller(user(2));
+        let bob = customer("bob");
+        let ids = |customers: Vec<Customer>| -> Vec<u64> { customers.iter().map(|customer| customer.id).collect() };
+        assert_eq!(ids(my_customers()), vec![bob.id]);
+        set_caller(user(1));
+        assert_eq!(ids(my_customers()), vec![ada.id, grace.id]);
+        set_caller(user(3));
+        assert!(my_customers().is_empty());
+    }
+
+    fn request(car: &Car, customer: &Customer, from: u64, to: u64) -> ReservationRequest {
+        ReservationRequest {
+            car_id: car.id,
+            customer_id: customer.id,
+            start_time: day(from),
+            end_time: day(to),
+        }
+    }
+
+    #[test]
+    fn block_bookings_make_every_reservation() {
+        let (first, second, customer) = (car(), car(), customer("ada"));
+        let made = make_reservations(vec![
+            request(&first, &customer, 1, 3),
+            request(&second, &customer, 1, 3),
+            request(&first, &customer, 3, 4),
+        ])
+        .unwrap();
+        let cars: Vec<u64> = made.iter().map(|reservation| reservation.car_id).collect();
+        assert_eq!(cars, vec![first.id, second.id, first.id]);
+        assert_eq!(_get_car_reservations(&first.id).len(), 2);
+        assert_eq!(_get_car_reservations(&second.id).len(), 1);
+    }
+
+    #[test]
+    fn one_conflict_rejects_the_whole_batch() {
+        let (first, second, customer) = (car(), car(), customer("ada"));
+        reserve(&second, &self::customer("bob"), 2, 3).unwrap();
+        let result = make_reservations(vec![
+            request(&first, &customer, 1, 3),
+            request(&second, &customer, 1, 3),
+        ]);
+        assert!(matches!(
+            result,
+            Err(Error::BatchRejected { index: 1, error }) if matches!(*error, Error::ReservationConflict { .. })
+        ));
+        assert!(_get_car_reservations(&first.id).is_empty());
+        // Requests of one batch may not overlap each other either
+        let result = make_reservations(vec![
+            request(&first, &customer, 1, 3),
+            request(&first, &customer, 2, 4),
+        ]);
+        assert!(matches!(result, Err(Error::BatchRejected { index: 1, .. })));
+        assert!(_get_car_reservations(&first.id).is_empty());
+    }
+
+    #[test]
+    fn customers_are_found_again_by_contact() {
+        let payload = |name: &str, contact: Contact| CustomerPayload {
+            name: name.to_string(),
+            contact,
+        };
+        let ada = get_or_create_customer(payload("ada", Contact::Email("ada@example.com".to_string()))).unwrap();
+        let again = get_or_create_customer(payload("Ada L.", Contact::Email("ADA@Example.com".to_string()))).unwrap();
+        assert_eq!(again.id, ada.id);
+        assert_eq!(again.name, "ada");
+        let grace = get_or_create_customer(payload("grace", Contact::Email("grace@example.com".to_string()))).unwrap();
+        let phone = get_or_create_customer(payload("ada", Contact::Phone("+14155552671".to_string()))).unwrap();
+        assert!(grace.id != ada.id && phone.id != ada.id && phone.id != grace.id);
+        assert_eq!(_get_customers().len(), 3);
+        assert!(get_or_create_customer(payload("bob", Contact::Phone("12".to_string()))).is_err());
+    }
+
+    #[test]
+    fn previews_report_conflicts_without_reserving() {
+        let car = add_car(CarPayload {
+            daily_price: 100,
+            ..payload()
+        })
+        .unwrap();
+        let preview = preview_reservation(car.id, day(1), day(3)).unwrap();
+        assert!(preview.available && preview.conflicts.is_empty());
+        assert_eq!(preview.estimated_cost, Some(_quote(&car, day(1).as_nanos(), day(3).as_nanos(), None).amount));
+        assert!(_get_car_reservations(&car.id).is_empty());
+        let (first, second) = (
+            reserve(&car, &customer("ada"), 2, 3).unwrap(),
+            reserve(&car, &customer("bob"), 0, 1).unwrap(),
+        );
+        let preview = preview_reservation(car.id, day(0), day(3)).unwrap();
+        assert!(!preview.available);
+        assert_eq!(preview.conflicts, vec![second.id, first.id]);
+        // Back to back is no conflict; unpriced cars get no estimate
+        assert!(preview_reservation(car.id, day(1), day(2)).unwrap().available);
+        assert_eq!(preview_reservation(self::car().id, day(1), day(2)).unwrap().estimated_cost, None);
+        assert!(matches!(preview_reservation(404, day(1), day(2)), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn revenue_potential_prices_every_car_in_service() {
+        for (daily_price, status) in [
+            (100, CarStatus::Available),
+            (250, CarStatus::Booked),
+            (40, CarStatus::Maintenance),
+            (1_000, CarStatus::Retired),
+        ] {
+            add_car(CarPayload {
+                daily_price,
+                status,
+                ..payload()
+            })
+            .unwrap();
+        }
+        // Monday to Thursday: three weekdays at 390 a day across the fleet
+        let monday = Timestamp(1_704_067_200 * 1_000_000_000);
+        let thursday = Timestamp(monday.as_nanos() + 3 * NANOS_PER_DAY);
+        assert_eq!(revenue_potential(monday, thursday).unwrap(), 3 * 390);
+        // A started day counts in full
+        assert_eq!(revenue_potential(monday, Timestamp(monday.as_nanos() + 1)).unwrap(), 390);
+        assert!(revenue_potential(thursday, monday).is_err());
+    }
+
+    fn tagged(tags: &[&str]) -> Car {
+        add_car(CarPayload {
+            tags: Some(tags.iter().map(|tag| tag.to_string()).collect()),
+            ..payload()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn tag_filters_match_all_or_any_of_the_tags() {
+        let both = tagged(&[" Luxury", "airport", "luxury"]);
+        let airport = tagged(&["airport"]);
+        tagged(&["long-term"]);
+        assert_eq!(both.tags, vec!["luxury", "airport"]);
+        let ids = |filter: CarFilter| -> Vec<u64> { search_cars(filter).iter().map(|car| car.id).collect() };
+        let wanted = |tags: &[&str]| Some(tags.iter().map(|tag| tag.to_string()).collect());
+        assert_eq!(
+            ids(CarFilter {
+                tags_all: wanted(&["AIRPORT", "luxury"]),
+                ..Default::default()
+            }),
+            vec![both.id]
+        );
+        assert_eq!(
+            ids(CarFilter {
+                tags_any: wanted(&["airport", "economy"]),
+                ..Default::default()
+            }),
+            vec![both.id, airport.id]
+        );
+        assert!(ids(CarFilter {
+            tags_any: wanted(&["economy"]),
+            ..Default::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn tags_are_validated() {
+        let rejects = |tags: Vec<String>| {
+            matches!(
+                add_car(CarPayload {
+                    tags: Some(tags),
+                    ..payload()
+                }),
+                Err(Error::ValidationErrors { errors }) if errors[0].field == "tags"
+            )
+        };
+        assert!(rejects(vec![" ".to_string()]));
+        assert!(rejects(vec!["t".repeat(33)]));
+        assert!(rejects((0..11).map(|n| n.to_string()).collect()));
+        assert!(!rejects(vec!["t".repeat(32)]));
+    }
+
+    #[test]
+    fn no_shows_free_the_car_once_the_window_started() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 1, 3).unwrap();
+        assert!(matches!(mark_no_show(reservation.id), Err(Error::InvalidState { .. })));
+        set_time(day(1).as_nanos() + 1);
+        _refresh_booking_status(&car.id);
+        assert!(get_car(car.id).unwrap().status == CarStatus::Booked);
+        set_caller(user(2));
+        assert!(matches!(mark_no_show(reservation.id), Err(Error::NotAuthorized { .. })));
+        set_caller(user(1));
+        let no_show = mark_no_show(reservation.id).unwrap();
+        assert!(no_show.status == ReservationStatus::NoShow);
+        assert!(reservation_status(reservation.id).unwrap() == ReservationStatus::NoShow);
+        assert!(get_car(car.id).unwrap().status == CarStatus::Available);
+        // The rest of the window can be booked again
+        assert!(reserve(&car, &self::customer("bob"), 2, 3).is_ok());
+    }
+
+    #[test]
+    fn checked_out_reservations_are_no_no_shows() {
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 0, 1).unwrap();
+        check_out(reservation.id, 0).unwrap();
+        assert!(matches!(mark_no_show(reservation.id), Err(Error::InvalidState { .. })));
+    }
+
+    #[test]
+    fn no_shows_dont_count_towards_the_tier() {
+        set_caller(admin());
+        set_loyalty_thresholds(1, 2).unwrap();
+        set_caller(user(1));
+        let (car, customer) = (car(), customer("ada"));
+        let reservation = reserve(&car, &customer, 0, 1).unwrap();
+        assert!(customer_tier(customer.id).unwrap() == LoyaltyTier::Silver);
+        mark_no_show(reservation.id).unwrap();
+        assert!(customer_tier(customer.id).unwrap() == LoyaltyTier::Bronze);
+    }
+
+    fn located(latitude: f64, longitude: f64) -> Car {
+        add_car(CarPayload {
+            latitude: Some(latitude),
+            longitude: Some(longitude),
+            ..payload()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn nearby_cars_are_listed_nearest_first() {
+        // Around central London
+        let heathrow = located(51.4700, -0.4543);
+        let kings_cross = located(51.5308, -0.1238);
+        let paris = located(48.8566, 2.3522);
+        car();
+        let ids = |radius_km: f64| -> Vec<u64> {
+            cars_near(51.5074, -0.1278, radius_km).unwrap().iter().map(|car| car.id).collect()
+        };
+        assert_eq!(ids(1.0), Vec::<u64>::new());
+        assert_eq!(ids(5.0), vec![kings_cross.id]);
+        assert_eq!(ids(30.0), vec![kings_cross.id, heathrow.id]);
+        assert_eq!(ids(400.0), vec![kings_cross.id, heathrow.id, paris.id]);
+        assert!((_haversine_km(51.5074, -0.1278, 48.8566, 2.3522) - 343.5).abs() < 1.0);
+    }
+
+    #[test]
+    fn coordinates_are_validated() {
+        assert!(cars_near(91.0, 0.0, 1.0).is_err());
+        assert!(cars_near(0.0, -181.0, 1.0).is_err());
+        assert!(cars_near(0.0, 0.0, -1.0).is_err());
+        let rejects = |latitude: Option<f64>, longitude: Option<f64>| {
+            add_car(CarPayload {
+                latitude,
+                longitude,
+                ..payload()
+            })
+            .is_err()
+        };
+        assert!(rejects(Some(90.5), Some(0.0)));
+        assert!(rejects(Some(0.0), Some(f64::NAN)));
+        assert!(rejects(Some(0.0), None));
+        assert!(!rejects(Some(-90.0), Some(180.0)));
+    }
+
+    #[test]
+    fn updates_with_a_stale_version_are_refused() {
+        let car = car();
+        assert_eq!(car.version, 1);
+        let repaint = |color: &str| CarPayload {
+            color: color.to_string(),
+            ..payload()
+        };
+        let updated = update_car(car.id, repaint("black"), Some(1)).unwrap();
+        assert_eq!(updated.version, 2);
+        assert!(matches!(
+            update_car(car.id, repaint("white"), Some(1)),
+            Err(Error::VersionConflict { current_version: 2 })
+        ));
+        assert_eq!(get_car(car.id).unwrap().color, "black");
+        // Without an expected version the update always goes through
+        assert_eq!(update_car(car.id, repaint("white"), None).unwrap().version, 3);
+    }
+
+    #[test]
+    fn patches_with_a_stale_version_are_refused() {
+        let car = car();
+        let patch = |make: &str| CarPatch {
+            make: Some(make.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(patch_car(car.id, patch("Honda"), Some(car.version)).unwrap().version, car.version + 1);
+        assert!(matches!(
+            patch_car(car.id, patch("Mazda"), Some(car.version)),
+            Err(Error::VersionConflict { current_version }) if current_version == car.version + 1
+        ));
+        assert_eq!(get_car(car.id).unwrap().make, "Honda");
+        // Bookings change the car too
+        let booked = reserve(&car, &customer("ada"), 0, 1).unwrap();
+        let version = get_car(booked.car_id).unwrap().version;
+        assert!(version > car.version + 1);
+        assert!(patch_car(car.id, patch("Mazda"), Some(version)).is_ok());
+    }
+
+    #[test]
+    fn only_staff_or_the_owner_check_cars_in() {
+        let customer = customer("ada");
+        let car = car();
+        let reservation = reserve(&car, &customer, 0, 1).unwrap();
+        check_out(reservation.id, 0).unwrap();
+        // Neither staff nor the car's owner
+        set_caller(user(2));
+        assert!(matches!(check_in(reservation.id, 10), Err(Error::NotAuthorized { .. })));
+        set_caller(admin());
+        add_staff(user(2)).unwrap();
+        set_caller(user(2));
+        assert_eq!(check_in(reservation.id, 10).unwrap().checkin_odometer, Some(10));
+    }
+
+    #[test]
+    fn is_staff_reports_staff_and_admins() {
+        set_caller(user(2));
+        assert!(!is_staff());
+        set_caller(admin());
+        assert!(is_staff());
+        add_staff(user(2)).unwrap();
+        set_caller(user(2));
+        assert!(is_staff());
+        set_caller(admin());
+        remove_staff(user(2)).unwrap();
+        set_caller(user(2));
+        assert!(!is_staff());
+    }
+
+    #[test]
+    fn a_consistent_store_passes_the_integrity_check() {
+        let customer = customer("ada");
+        let car = car();
+        reserve(&car, &customer, 0, 1).unwrap();
+        set_caller(admin());
+        let report = integrity_check().unwrap();
+        assert_eq!(report.corrupt_cars.count, 0);
+        assert_eq!(report.reservations_without_car.count, 0);
+        assert_eq!(report.reservations_without_customer.count, 0);
+        assert_eq!(report.booked_cars_without_reservation.count, 0);
+        assert!(!report.id_counter_behind);
+        assert!(!report.reservation_counter_behind);
+        set_caller(user(1));
+        assert!(matches!(integrity_check(), Err(Error::NotAuthorized { .. })));
+    }
+
+    #[test]
+    fn integrity_check_flags_orphaned_reservations() {
+        let (ada, bob) = (customer("ada"), customer("bob"));
+        let (car, other) = (self::car(), self::car());
+        let first = reserve(&car, &ada, 1, 2).unwrap();
+        let second = reserve(&car, &ada, 3, 4).unwrap();
+        let third = reserve(&other, &bob, 1, 2).unwrap();
+        CAR_STORAGE.with(|service| service.borrow_mut().remove(&car.id));
+        let customer_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)));
+        StableBTreeMap::<u64, Customer, Memory>::init(customer_storage).remove(&bob.id);
+        set_caller(admin());
+        let report = integrity_check().unwrap();
+        assert_eq!(report.reservations_without_car.count, 2);
+        assert_eq!(report.reservations_without_car.sample_ids, vec![first.id, second.id]);
+        assert_eq!(report.reservations_without_customer.count, 1);
+        assert_eq!(report.reservations_without_customer.sample_ids, vec![third.id]);
+        // Nothing was repaired along the way
+        assert_eq!(get_reservation(first.id).unwrap().car_id, car.id);
+        assert!(get_customer(bob.id).is_err());
+    }
+
+    #[test]
+    fn integrity_check_flags_cars_booked_without_a_reservation() {
+        let customer = customer("ada");
+        let (held, ahead, idle) = (self::car(), self::car(), self::car());
+        reserve(&held, &customer, 0, 1).unwrap();
+        reserve(&ahead, &customer, 1, 2).unwrap();
+        for id in [ahead.id, idle.id] {
+            let mut car = _get_car(&id).unwrap();
+            car.status = CarStatus::Booked;
+            do_insert_car(&mut car).unwrap();
+        }
+        set_caller(admin());
+        let report = integrity_check().unwrap();
+        // A reservation still ahead doesn't hold its car yet
+        assert_eq!(report.booked_cars_without_reservation.count, 2);
+        assert_eq!(report.booked_cars_without_reservation.sample_ids, vec![ahead.id, idle.id]);
+        assert!(_get_car(&idle.id).unwrap().status == CarStatus::Booked);
+    }
+
+    #[test]
+    fn integrity_check_flags_corrupt_cars_and_lagging_counters() {
+        let customer = customer("ada");
+        let car = car();
+        reserve(&car, &customer, 0, 1).unwrap();
+        CAR_STORAGE.with(|service| service.borrow_mut().insert(99, CarRecord(vec![0xde, 0xad])));
+        ID_RESERVATION_COUNTER.with(|counter| counter.borrow_mut().set(0).unwrap());
+        set_caller(admin());
+        let report = integrity_check().unwrap();
+        assert_eq!(report.corrupt_cars.count, 1);
+        assert_eq!(report.corrupt_cars.sample_ids, vec![99]);
+        // The corrupt car's id is still taken
+        assert!(report.id_counter_behind);
+        assert!(report.reservation_counter_behind);
+        assert_eq!(report.reservations_without_car.count, 0);
+    }
+
+    #[test]
+    fn integrity_samples_are_capped() {
+        let issue = IntegrityIssue::from_ids(0..25);
+        assert_eq!(issue.count, 25);
+        assert_eq!(issue.sample_ids, (0..10).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn list_cars_pages_in_id_order_with_the_total() {
+        let ids: Vec<u64> = (0..5).map(|_| car().id).collect();
+        let page = list_cars(1, 2);
+        assert_eq!(page.total_matches, 5);
+        assert_eq!(page.items.iter().map(|car| car.id).collect::<Vec<u64>>(), ids[1..3]);
+        assert!(list_cars(5, 2).items.is_empty());
+        assert_eq!(list_cars(0, u64::MAX).items.len(), 5);
+    }
+
+    #[test]
+    fn search_combines_make_model_year_and_color_filters() {
+        let old = add_car(CarPayload { year: 2012, ..payload() }).unwrap();
+        let new = car();
+        let blue = add_car(CarPayload {
+            color: "blue".to_string(),
+            ..payload()
+        })
+        .unwrap();
+        add_car(CarPayload {
+            make: "Honda".to_string(),
+            model: "Civic".to_string(),
+            ..payload()
+        })
+        .unwrap();
+        let ids = |filter: CarFilter| search_cars(filter).iter().map(|car| car.id).collect::<Vec<u64>>();
+        // Make and model match substrings in any case
+        let corollas = || CarFilter {
+            make: Some("TOY".to_string()),
+            model: Some("roll".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(corollas()), [old.id, new.id, blue.id]);
+        let recent_red = CarFilter {
+            min_year: Some(2015),
+            max_year: Some(2020),
+            color: Some(" Red ".to_string()),
+            ..corollas()
+        };
+        assert_eq!(ids(recent_red), [new.id]);
+    }
+
+    #[test]
+    fn get_available_cars_leaves_out_held_and_resting_cars() {
+        let customer = customer("ada");
+        let (held, ahead, idle) = (self::car(), self::car(), self::car());
+        add_car(CarPayload { status: CarStatus::Maintenance, ..payload() }).unwrap();
+        reserve(&held, &customer, 0, 1).unwrap();
+        reserve(&ahead, &customer, 1, 2).unwrap();
+        let ids = || get_available_cars().iter().map(|car| car.id).collect::<Vec<u64>>();
+        // A reservation still ahead doesn't hold its car yet, and resting cars never show
+        assert_eq!(ids(), [ahead.id, idle.id]);
+        set_time(day(1).as_nanos());
+        assert_eq!(ids(), [held.id, idle.id]);
+    }
+
+    #[test]
+    fn cars_sort_by_daily_price_in_either_direction() {
+        let mid = add_car(CarPayload { daily_price: 500, ..payload() }).unwrap();
+        let cheap = add_car(CarPayload { daily_price: 100, ..payload() }).unwrap();
+        let dear = add_car(CarPayload { daily_price: 900, ..payload() }).unwrap();
+        assert_eq!(ids(get_cars_sorted(CarSortKey::DailyPrice, false)), [cheap.id, mid.id, dear.id]);
+        assert_eq!(ids(get_cars_sorted(CarSortKey::DailyPrice, true)), [dear.id, mid.id, cheap.id]);
+        let page = query_cars(CarFilter::default(), CarSortKey::DailyPrice, true, 0, 2);
+        assert_eq!(ids(page.items), [dear.id, mid.id]);
+    }
+
+    #[test]
+    fn weekly_prices_charge_full_weeks_when_cheaper() {
+        let car = add_car(CarPayload { daily_price: 100, weekly_price: Some(600), ..payload() }).unwrap();
+        let customer = customer("ada");
+        // A week at the weekly price and one more day by the day
+        assert_eq!(reserve(&car, &customer, 1, 9).unwrap().cost.unwrap().amount, 700);
+        // Six days by the day cost less than a week
+        assert_eq!(reserve(&car, &customer, 10, 16).unwrap().cost.unwrap().amount, 600);
+    }
+
+    #[test]
+    fn repricing_leaves_booked_costs_alone() {
+        let car = priced_car(100);
+        let reservation = reserve(&car, &customer("ada"), 1, 3).unwrap();
+        let pricing = CarPricing {
+            daily_price: 150,
+            weekend_multiplier: Some(2.0),
+            weekly_price: Some(900),
+            monthly_price: None,
+        };
+        let repriced = set_car_pricing(car.id, pricing, None).unwrap();
+        assert_eq!(repriced.daily_price, 150);
+        assert_eq!(repriced.weekend_multiplier, 2.0);
+        assert_eq!(repriced.weekly_price, Some(900));
+        assert_eq!(get_reservation(reservation.id).unwrap().cost.unwrap().amount, 200);
+        // Leaving the weekly price out stops offering it
+        let pricing = CarPricing {
+            daily_price: 150,
+            weekend_multiplier: None,
+            weekly_price: None,
+            monthly_price: None,
+        };
+        let repriced = set_car_pricing(car.id, pricing, None).unwrap();
+        assert_eq!(repriced.weekly_price, None);
+        assert_eq!(repriced.weekend_multiplier, 1.0);
+    }
+
+    #[test]
+    fn only_the_owner_or_an_admin_reprices_a_car_at_the_expected_version() {
+        let car = priced_car(100);
+        let pricing = |daily_price| CarPricing {
+            daily_price,
+            weekend_multiplier: None,
+            weekly_price: None,
+            monthly_price: None,
+        };
+        set_caller(user(2));
+        assert!(matches!(set_car_pricing(car.id, pricing(1), None), Err(Error::NotAuthorized { .. })));
+        set_caller(admin());
+        let repriced = set_car_pricing(car.id, pricing(120), Some(car.version)).unwrap();
+        set_caller(user(1));
+        assert!(matches!(
+            set_car_pricing(car.id, pricing(130), Some(car.version)),
+            Err(Error::VersionConflict { current_version }) if current_version == repriced.version
+        ));
+        let invalid = CarPricing {
+            weekend_multiplier: Some(-1.0),
+            ..pricing(130)
+        };
+        assert!(matches!(set_car_pricing(car.id, invalid, None), Err(Error::ValidationErrors { .. })));
+        assert_eq!(get_car(car.id).unwrap().daily_price, 120);
+    }
+}